@@ -0,0 +1,338 @@
+//! ## #[derive(Responder)]
+//! 为结构体或枚举自动实现 actix-web 的 `Responder` trait, 省去手写 `respond_to` 的样板代码.
+//!
+//! 通过 `#[responder(...)]` 属性来描述响应:
+//! * `status = 201` - 响应状态码, 默认 200
+//! * `content_type = "text/plain"` - 响应的 Content-Type, 默认由 body 类型决定
+//! * `header("X-Version", "0.2")` - 额外的响应头, 可以写多个
+//! * `json` / `text` - body 的生成方式, 默认 `json`
+//!
+//! 结构体上的属性直接描述整个响应. 枚举上的属性作为所有变体的默认值, 每个变体可以再用自己的属性覆盖,
+//! 这样一个枚举就可以代替嵌套的 `Either` 来表达多种不同的响应.
+//!
+//! body 的生成规则:
+//! * 结构体: `json` 序列化整个结构体, `text` 使用它的 `Display` 实现
+//! * 单元变体: 空 body
+//! * 只有一个字段的元组变体: `json` 序列化这个字段, `text` 使用这个字段的 `Display` 实现
+//! * 其它变体: 命名字段序列化成 json 对象, 元组字段序列化成 json 数组
+//!
+//! 命名字段的变体通过 serde 序列化, 字段上的 `#[serde(...)]` 属性(比如 `rename`, `skip_serializing_if`)
+//! 与变体上的 `#[serde(rename_all = "...")]` 都会生效.
+//!
+//! 序列化失败会返回 500 错误, 而不是 panic.
+//!
+//! 过程宏的包不能导出运行时的依赖, 生成的代码通过 `::serde` 与 `::serde_json` 序列化 json body,
+//! 所以使用 `json`(默认的 body 生成方式)的包需要自己依赖 `serde` 与 `serde_json`.
+//!
+//! 状态码, 响应头的名称与值以及 `content_type` 在展开时检查, 不合法时编译失败.
+//! 泛型类型的字段用到了类型参数时, 会自动添加 `Serialize`(json) 或 `Display`(text) 约束.
+//!
+//! ```ignore
+//! #[derive(Serialize, Responder)]
+//! #[responder(status = 201, header("X-Version", "0.2"))]
+//! struct MyObj {
+//!     name: &'static str,
+//! }
+//!
+//! #[derive(Responder)]
+//! enum RegisterResponse {
+//!     #[responder(text)]
+//!     Small(String),
+//!     #[responder(status = 202)]
+//!     Great { number: u32 },
+//!     #[responder(status = 404)]
+//!     NotFound,
+//! }
+//! ```
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use proc_macro2::TokenTree;
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Fields, Ident, Lit, LitStr, Meta, NestedMeta, Type,
+          WherePredicate};
+
+#[proc_macro_derive(Responder, attributes(responder, serde))]
+pub fn derive_responder(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(|err| err.to_compile_error()).into()
+}
+
+/// body 的生成方式
+#[derive(Clone, Copy, PartialEq)]
+enum BodyKind {
+    Json,
+    Text,
+}
+
+/// `#[responder(...)]` 属性解析出来的响应描述
+#[derive(Clone)]
+struct ResponseAttrs {
+    status: Option<u16>,
+    content_type: Option<String>,
+    headers: Vec<(String, String)>,
+    kind: Option<BodyKind>,
+}
+
+impl ResponseAttrs {
+    fn empty() -> Self {
+        ResponseAttrs { status: None, content_type: None, headers: Vec::new(), kind: None }
+    }
+
+    /// 解析属性, 并在 `defaults` 的基础上覆盖
+    fn parse(attrs: &[Attribute], defaults: &ResponseAttrs) -> syn::Result<Self> {
+        let mut parsed = defaults.clone();
+        for attr in attrs.iter().filter(|a| a.path.is_ident("responder")) {
+            let list = match attr.parse_meta()? {
+                Meta::List(list) => list,
+                other => return Err(syn::Error::new_spanned(other, "expected #[responder(...)]")),
+            };
+            for nested in list.nested {
+                parsed.apply(nested)?;
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn apply(&mut self, nested: NestedMeta) -> syn::Result<()> {
+        match nested {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("status") => match &nv.lit {
+                Lit::Int(code) => {
+                    let code: u16 = code.base10_parse()?;
+                    if !(100..=599).contains(&code) {
+                        return Err(syn::Error::new_spanned(&nv.lit, "status must be between 100 and 599"));
+                    }
+                    self.status = Some(code);
+                }
+                _ => return Err(syn::Error::new_spanned(&nv.lit, "status must be an integer")),
+            },
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("content_type") => match &nv.lit {
+                Lit::Str(s) if valid_header_value(&s.value()) => self.content_type = Some(s.value()),
+                Lit::Str(s) => return Err(syn::Error::new_spanned(s, "content_type is not a valid header value")),
+                _ => return Err(syn::Error::new_spanned(&nv.lit, "content_type must be a string")),
+            },
+            NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("header") => {
+                let values: Vec<&LitStr> = list.nested.iter().map(|n| match n {
+                    NestedMeta::Lit(Lit::Str(s)) => Ok(s),
+                    other => Err(syn::Error::new_spanned(other, "header name and value must be strings")),
+                }).collect::<syn::Result<_>>()?;
+                match values.as_slice() {
+                    [name, _] if !valid_header_name(&name.value()) => {
+                        return Err(syn::Error::new_spanned(name, "invalid header name"));
+                    }
+                    [_, value] if !valid_header_value(&value.value()) => {
+                        return Err(syn::Error::new_spanned(value, "invalid header value"));
+                    }
+                    [name, value] => self.headers.push((name.value(), value.value())),
+                    _ => return Err(syn::Error::new_spanned(list, "expected header(\"Name\", \"value\")")),
+                }
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("json") => self.kind = Some(BodyKind::Json),
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("text") => self.kind = Some(BodyKind::Text),
+            other => return Err(syn::Error::new_spanned(other, "unknown responder attribute")),
+        }
+        Ok(())
+    }
+
+    /// 生成 HttpResponseBuilder 并设置状态码与响应头, `default_content_type` 在没有显式指定时使用
+    fn builder(&self, default_content_type: Option<&str>) -> TokenStream2 {
+        let status = self.status.unwrap_or(200);
+        let content_type = self.content_type.as_deref().or(default_content_type).map(|ct| {
+            quote! { builder.content_type(#ct); }
+        });
+        let headers = self.headers.iter().map(|(name, value)| {
+            quote! { builder.header(#name, #value); }
+        });
+        quote! {
+            #[allow(unused_mut)]
+            let mut builder = ::actix_web::dev::HttpResponseBuilder::new(
+                ::actix_web::http::StatusCode::from_u16(#status).unwrap()
+            );
+            #content_type
+            #(#headers)*
+        }
+    }
+
+    fn kind(&self) -> BodyKind {
+        self.kind.unwrap_or(BodyKind::Json)
+    }
+}
+
+/// 响应头名称必须是 RFC 7230 中的 token
+fn valid_header_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// 与 `HeaderValue::from_str` 相同: 只允许可见的 ASCII 字符, 空格与 tab
+fn valid_header_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || (0x20..0x7f).contains(&b))
+}
+
+/// 类型中是否用到了某个类型参数
+fn uses_params(tokens: TokenStream2, params: &[Ident]) -> bool {
+    tokens.into_iter().any(|tree| match tree {
+        TokenTree::Ident(ident) => params.contains(&ident),
+        TokenTree::Group(group) => uses_params(group.stream(), params),
+        _ => false,
+    })
+}
+
+/// 生成 body 需要的约束, 只为用到了类型参数的类型添加
+fn body_bound(ty: TokenStream2, kind: BodyKind, params: &[Ident]) -> Option<WherePredicate> {
+    if !uses_params(ty.clone(), params) {
+        return None;
+    }
+    Some(match kind {
+        BodyKind::Json => parse_quote!(#ty: ::serde::Serialize),
+        BodyKind::Text => parse_quote!(#ty: ::std::fmt::Display),
+    })
+}
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// 变体上对字段生效的 serde 属性, 目前只有 `rename_all`
+fn variant_serde_attrs(attrs: &[Attribute]) -> syn::Result<Vec<TokenStream2>> {
+    let mut kept = Vec::new();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        if let Meta::List(list) = attr.parse_meta()? {
+            for nested in list.nested.iter() {
+                if let NestedMeta::Meta(Meta::NameValue(nv)) = nested {
+                    if nv.path.is_ident("rename_all") {
+                        kept.push(quote! { #[serde(#nv)] });
+                    }
+                }
+            }
+        }
+    }
+    Ok(kept)
+}
+
+/// 根据 body 生成方式, 把表达式 `value` 转换成响应
+fn respond_with(attrs: &ResponseAttrs, value: TokenStream2) -> TokenStream2 {
+    match attrs.kind() {
+        BodyKind::Json => {
+            let builder = attrs.builder(Some(JSON_CONTENT_TYPE));
+            quote! {{
+                let body = ::serde_json::to_string(&#value)
+                    .map_err(::actix_web::error::ErrorInternalServerError)?;
+                #builder
+                Ok(builder.body(body))
+            }}
+        }
+        BodyKind::Text => {
+            let builder = attrs.builder(Some(TEXT_CONTENT_TYPE));
+            quote! {{
+                let body = ::std::string::ToString::to_string(&#value);
+                #builder
+                Ok(builder.body(body))
+            }}
+        }
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let container = ResponseAttrs::parse(&input.attrs, &ResponseAttrs::empty())?;
+    let params: Vec<Ident> = input.generics.type_params().map(|param| param.ident.clone()).collect();
+    let (_, ty_generics, _) = input.generics.split_for_impl();
+    let mut bounds: Vec<WherePredicate> = Vec::new();
+    let mut field_bounds = |types: Vec<&Type>, kind: BodyKind| {
+        bounds.extend(types.into_iter().filter_map(|ty| body_bound(quote!(#ty), kind, &params)));
+    };
+
+    let body = match &input.data {
+        Data::Struct(_) => {
+            // 整个结构体作为 body
+            if let Some(bound) = body_bound(quote!(#name #ty_generics), container.kind(), &params) {
+                bounds.push(bound);
+            }
+            respond_with(&container, quote!(self))
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().map(|variant| {
+                let attrs = ResponseAttrs::parse(&variant.attrs, &container)?;
+                let ident = &variant.ident;
+                let arm = match &variant.fields {
+                    Fields::Unit => {
+                        let builder = attrs.builder(None);
+                        quote! { #name::#ident => { #builder Ok(builder.finish()) } }
+                    }
+                    Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                        field_bounds(fields.unnamed.iter().map(|f| &f.ty).collect(), attrs.kind());
+                        let respond = respond_with(&attrs, quote!(field));
+                        quote! { #name::#ident(field) => #respond }
+                    }
+                    Fields::Unnamed(fields) => {
+                        if attrs.kind() == BodyKind::Text {
+                            return Err(syn::Error::new_spanned(variant, "text body requires exactly one field"));
+                        }
+                        field_bounds(fields.unnamed.iter().map(|f| &f.ty).collect(), BodyKind::Json);
+                        let bindings: Vec<_> = (0..fields.unnamed.len()).map(|i| format_ident!("field{}", i)).collect();
+                        let respond = respond_with(&attrs, quote!((#(#bindings,)*)));
+                        quote! { #name::#ident(#(#bindings),*) => #respond }
+                    }
+                    Fields::Named(fields) => {
+                        if attrs.kind() == BodyKind::Text {
+                            return Err(syn::Error::new_spanned(variant, "text body requires exactly one field"));
+                        }
+                        let types: Vec<&Type> = fields.named.iter().map(|f| &f.ty).collect();
+                        field_bounds(types.clone(), BodyKind::Json);
+                        let idents: Vec<_> = fields.named.iter().map(|f| f.ident.clone().unwrap()).collect();
+                        let field_attrs: Vec<Vec<&Attribute>> = fields.named.iter()
+                            .map(|f| f.attrs.iter().filter(|a| a.path.is_ident("serde")).collect())
+                            .collect();
+                        let container_attrs = variant_serde_attrs(&variant.attrs)?;
+
+                        // 字段放到一个派生了 Serialize 的结构体中, 这样 serde 的属性都会生效.
+                        // 结构体带有枚举所有的泛型参数, 约束由 where 子句给出, 不使用 serde 推断的约束
+                        let mut body_generics = input.generics.clone();
+                        body_generics.make_where_clause().predicates
+                            .extend(types.iter().filter_map(|ty| body_bound(quote!(#ty), BodyKind::Json, &params)));
+                        let (body_impl_generics, _, body_where) = body_generics.split_for_impl();
+                        let respond = respond_with(&attrs, quote!(body));
+                        quote! {
+                            #name::#ident { #(#idents),* } => {
+                                #[derive(::serde::Serialize)]
+                                #[serde(bound = "")]
+                                #(#container_attrs)*
+                                struct __ResponderBody #body_impl_generics #body_where {
+                                    #( #(#field_attrs)* #idents: #types, )*
+                                    #[serde(skip)]
+                                    __marker: ::std::marker::PhantomData<fn() -> #name #ty_generics>,
+                                }
+                                let body = __ResponderBody { #(#idents,)* __marker: ::std::marker::PhantomData };
+                                #respond
+                            }
+                        }
+                    }
+                };
+                Ok(arm)
+            }).collect::<syn::Result<Vec<_>>>()?;
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new_spanned(&input, "Responder cannot be derived for unions")),
+    };
+
+    let mut generics = input.generics.clone();
+    generics.make_where_clause().predicates.extend(bounds);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::actix_web::Responder for #name #ty_generics #where_clause {
+            type Error = ::actix_web::Error;
+            type Future = ::std::future::Ready<::std::result::Result<::actix_web::HttpResponse, ::actix_web::Error>>;
+
+            fn respond_to(self, _req: &::actix_web::HttpRequest) -> Self::Future {
+                let respond = move || -> ::std::result::Result<::actix_web::HttpResponse, ::actix_web::Error> {
+                    #body
+                };
+                ::std::future::ready(respond())
+            }
+        }
+    })
+}
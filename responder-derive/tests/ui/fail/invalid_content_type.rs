@@ -0,0 +1,9 @@
+use responder_derive::Responder;
+
+#[derive(Responder)]
+enum Response {
+    #[responder(text, content_type = "text/plain\r\n")]
+    Text(String),
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use responder_derive::Responder;
+
+#[derive(serde::Serialize, Responder)]
+#[responder(header("X Version", "0.2"))]
+struct BadName {
+    ok: bool,
+}
+
+#[derive(serde::Serialize, Responder)]
+#[responder(header("X-Version", "line\nbreak"))]
+struct BadValue {
+    ok: bool,
+}
+
+fn main() {}
@@ -0,0 +1,9 @@
+use responder_derive::Responder;
+
+#[derive(serde::Serialize, Responder)]
+#[responder(status = 700)]
+struct Response {
+    ok: bool,
+}
+
+fn main() {}
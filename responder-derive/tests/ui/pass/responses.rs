@@ -0,0 +1,62 @@
+use actix_web::dev::{Body, ResponseBody};
+use actix_web::{test, HttpResponse, Responder};
+use responder_derive::Responder;
+use serde::Serialize;
+
+#[derive(Serialize, Responder)]
+#[responder(status = 201, content_type = "application/vnd.demo+json", header("X-Version", "0.2"))]
+struct Created<T> {
+    items: Vec<T>,
+}
+
+#[derive(Responder)]
+enum Reply<T> {
+    #[responder(text)]
+    Text(T),
+    #[responder(status = 202)]
+    #[serde(rename_all = "camelCase")]
+    Accepted {
+        request_id: u32,
+        #[serde(rename = "msg")]
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detail: Option<T>,
+    },
+    #[responder(status = 204)]
+    Empty,
+}
+
+fn respond<R: Responder>(responder: R) -> HttpResponse where R::Future: 'static {
+    let req = test::TestRequest::default().to_http_request();
+    match actix_web::rt::System::new("ui").block_on(responder.respond_to(&req)) {
+        Ok(res) => res,
+        Err(_) => panic!("respond_to failed"),
+    }
+}
+
+fn body(res: &HttpResponse) -> String {
+    match res.body() {
+        ResponseBody::Body(Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+        ResponseBody::Body(Body::Empty) => String::new(),
+        _ => panic!("unexpected body"),
+    }
+}
+
+fn main() {
+    let res = respond(Created { items: vec![1, 2] });
+    assert_eq!(res.status().as_u16(), 201);
+    assert_eq!(res.headers().get("content-type").unwrap(), "application/vnd.demo+json");
+    assert_eq!(res.headers().get("x-version").unwrap(), "0.2");
+    assert_eq!(body(&res), r#"{"items":[1,2]}"#);
+
+    let res = respond(Reply::<&str>::Text("hello"));
+    assert_eq!(res.headers().get("content-type").unwrap(), "text/plain; charset=utf-8");
+    assert_eq!(body(&res), "hello");
+
+    let res = respond(Reply::<&str>::Accepted { request_id: 7, message: "queued".to_string(), detail: None });
+    assert_eq!(res.status().as_u16(), 202);
+    assert_eq!(body(&res), r#"{"requestId":7,"msg":"queued"}"#);
+
+    let res = respond(Reply::<&str>::Empty);
+    assert_eq!(res.status().as_u16(), 204);
+}
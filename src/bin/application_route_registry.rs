@@ -0,0 +1,62 @@
+use actix_web::{web, App, HttpServer, HttpResponse, http::Method};
+use actix_web3_doc::routing::{MethodResource, RouteRegistry, Routes, ScopeSpec};
+
+/// ## 路由注册表
+/// `application.rs` 中的路由分散在 `.service()`, 多个 `.configure()` 与带 guard 的 scope 中, 很难知道最终注册了哪些路由.
+/// actix-web 注册之后无法再读取路由的信息, 这里用同样的方式组合应用, 但是通过 `actix_web3_doc::routing::Routes` 注册,
+/// 注册的同时把每个路由的方法, 完整路径, 名称与 scope 上的 guard 记录到 `RouteRegistry` 中.
+///
+/// * 注册表通过 `web::Data` 在所有 worker 之间共享, 并显式传给 `Routes::new`
+/// * 每个 worker 构建 App 时都会注册一遍路由, 相同的路由只记录一次
+/// * 第一个 worker 构建完 App 之后以表格形式打印路由表, 运行时可以访问 `/_routes` 得到 json 格式的路由表
+///
+/// ```shell
+/// curl http://127.0.0.1:8080/_routes
+/// curl -H "Content-Type: application/html" http://127.0.0.1:8080/app/index.html
+/// curl -i -X POST http://127.0.0.1:8080/t      # 405, Allow: GET, HEAD, OPTIONS
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let registry = web::Data::new(RouteRegistry::new());
+
+    HttpServer::new(move || {
+        let app = App::new()
+            .app_data(registry.clone())
+            .configure(|cfg| routes(cfg, &registry));
+        registry.print_table_once();
+        app
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+fn routes(cfg: &mut web::ServiceConfig, registry: &RouteRegistry) {
+    Routes::new(cfg, registry)
+        .named(Method::GET, "/_routes", "routes", web::to(route_table))
+        .route(Method::GET, "/", web::to(|| HttpResponse::Ok().body("index")))
+        .configure(config)
+        .configure(second_config)
+        // 所有以 /app 开头的path都将被匹配, Http Header 的Content-Type 必须为指定的类型
+        .scope(ScopeSpec::new("/app").header("Content-Type", "application/html"), |routes| {
+            routes
+                .route(Method::GET, "/index.html", web::to(|| HttpResponse::Ok().body("hello actix-web 3.0")))
+                .route(Method::GET, "/getAppInfo", web::to(|| HttpResponse::Ok().body("This is app Info")));
+        });
+}
+
+/// 第一种配置function
+fn config(routes: &mut Routes) {
+    routes.resource(MethodResource::new("/t")
+        .get(web::to(|| HttpResponse::Ok().body("This is oneConfig Response"))));
+}
+
+/// 第二种配置function
+fn second_config(routes: &mut Routes) {
+    routes.scope(ScopeSpec::new("/secondScope").header("Content-Type", "application/text"), |routes| {
+        routes.route(Method::GET, "/test", web::to(|| HttpResponse::Ok().body("This is Second Config Response")));
+    });
+}
+
+/// json 格式的路由表
+async fn route_table(registry: web::Data<RouteRegistry>) -> HttpResponse {
+    HttpResponse::Ok().json(registry.routes())
+}
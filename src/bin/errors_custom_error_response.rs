@@ -1,8 +1,36 @@
 use actix_web::{HttpServer, App, get, error, Result, dev::HttpResponseBuilder, http::header,
-                http::StatusCode, HttpResponse, middleware::Logger};
+                http::StatusCode, HttpResponse, middleware::Logger, web, Error, HttpMessage};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::rt::time::{delay_until, Delay, Instant};
+use actix_service::{Service, Transform};
+use bytes::Bytes;
 use derive_more::{Display, Error};
+use futures::future::{ok, select, Either, Ready};
+use futures::{Future, Stream};
 use log::debug;
 
+use std::cell::{Cell, RefCell};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// ## 自定义错误响应
+/// `MyError` 与 `UserError` 通过实现 `ResponseError` 把错误转换成响应.
+///
+/// `Timeout` 中间件会产生 `MyError::Timeout`(504):
+/// * 可以注册在 app, scope 或 resource 上, 嵌套时最里层的配置生效(比如 app 上是 2 秒, 上传接口单独放宽到 30 秒)
+/// * 超时后 drop 处理函数的 future, 处理函数会在下一个 `.await` 处被取消, 然后返回配置的错误,
+///   默认是 `MyError::Timeout`, 也可以通过 `on_timeout()` 换成 503 之类的错误
+/// * `body_timeout()` 单独限制接收请求 body 的时间, 防止客户端很慢地上传 body 一直占用连接, 超时返回 408
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/slow/3           # 504
+/// curl -i http://127.0.0.1:8080/reports/slow/3   # 503
+/// curl -i --limit-rate 1k -H "Content-Type: application/octet-stream" --data-binary @Cargo.lock \
+///      http://127.0.0.1:8080/upload               # 408
+/// ```
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // 设置环境变量参数
@@ -13,13 +41,25 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(|| {
         App::new()
             // warp方法 注册一个中间件
+            // 所有路由默认 2 秒超时, 下面的 scope 与 resource 单独配置
+            .wrap(Timeout::new(Duration::from_secs(2)))
             .wrap(Logger::default()) // 添加默认的日志设置
             .service(index)
             .service(user_error)
+            .route("/slow/{secs}", web::get().to(slow))
+            .service(web::scope("/reports")
+                // 报表服务繁忙时让客户端稍后重试
+                .wrap(Timeout::new(Duration::from_secs(1))
+                    .on_timeout(|| error::ErrorServiceUnavailable("report service is busy, retry later")))
+                .route("/slow/{secs}", web::get().to(slow)))
+            .service(web::resource("/upload")
+                .wrap(Timeout::new(Duration::from_secs(30)).body_timeout(Duration::from_secs(3)))
+                .route(web::post().to(upload)))
     }).bind("127.0.0.1:8080")?
         .run().await
 }
 
+#[allow(dead_code)] // InternalError 仅用于演示 status_code 映射
 #[derive(Debug, Display, Error)]
 enum MyError {
     #[display(fmt = "internal error")]
@@ -83,3 +123,247 @@ async fn user_error() -> Result<&'static str, UserError> {
     debug!("{}", error);
     Err(error)
 }
+
+/// 模拟一个耗时的处理函数
+async fn slow(secs: web::Path<u64>) -> String {
+    actix_web::rt::time::delay_for(Duration::from_secs(*secs)).await;
+    format!("finished after {} seconds", secs)
+}
+
+async fn upload(body: web::Bytes) -> String {
+    format!("received {} bytes", body.len())
+}
+
+/// 一个请求当前的截止时间, 保存在请求的 extensions 中, 嵌套的 `Timeout` 共享同一个截止时间
+struct Deadline {
+    at: Cell<Instant>,
+    on_timeout: RefCell<Rc<dyn Fn() -> Error>>,
+}
+
+/// 超时中间件
+#[derive(Clone)]
+pub struct Timeout {
+    timeout: Duration,
+    body_timeout: Option<Duration>,
+    on_timeout: Rc<dyn Fn() -> Error>,
+}
+
+impl Timeout {
+    pub fn new(timeout: Duration) -> Self {
+        Timeout { timeout, body_timeout: None, on_timeout: Rc::new(|| MyError::Timeout.into()) }
+    }
+
+    /// 超时后返回的错误
+    pub fn on_timeout<F: Fn() -> Error + 'static>(mut self, f: F) -> Self {
+        self.on_timeout = Rc::new(f);
+        self
+    }
+
+    /// 接收请求 body 的最长时间
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.body_timeout = Some(timeout);
+        self
+    }
+}
+
+impl<S, B> Transform<S> for Timeout
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TimeoutMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TimeoutMiddleware { service, config: self.clone() })
+    }
+}
+
+pub struct TimeoutMiddleware<S> {
+    service: S,
+    config: Timeout,
+}
+
+impl<S, B> Service for TimeoutMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let start = Instant::now();
+        let at = start + self.config.timeout;
+
+        // 外层已经设置了截止时间时用里层的配置覆盖它
+        let existing = req.extensions().get::<Rc<Deadline>>().cloned();
+        let deadline = match existing {
+            Some(deadline) => {
+                deadline.at.set(at);
+                *deadline.on_timeout.borrow_mut() = self.config.on_timeout.clone();
+                deadline
+            }
+            None => {
+                let deadline = Rc::new(Deadline { at: Cell::new(at), on_timeout: RefCell::new(self.config.on_timeout.clone()) });
+                req.extensions_mut().insert(deadline.clone());
+                deadline
+            }
+        };
+
+        let body_expired = Rc::new(Cell::new(false));
+        if let Some(body_timeout) = self.config.body_timeout {
+            let payload = req.take_payload();
+            req.set_payload(Payload::Stream(Box::pin(BodyDeadline {
+                payload,
+                delay: delay_until(start + body_timeout),
+                expired: body_expired.clone(),
+                done: false,
+            })));
+        }
+
+        let request_line = format!("{} {}", req.method(), req.path());
+        let mut fut = Box::pin(self.service.call(req));
+
+        Box::pin(async move {
+            loop {
+                let at = deadline.at.get();
+                match select(fut, delay_until(at)).await {
+                    Either::Left((res, _)) => {
+                        if body_expired.get() {
+                            log::warn!("{} request body not received within the body timeout", request_line);
+                            return Err(error::ErrorRequestTimeout("request body timeout"));
+                        }
+                        return res;
+                    }
+                    // 里层的 Timeout 延长了截止时间, 继续等待
+                    Either::Right((_, pending)) if deadline.at.get() > at => fut = pending,
+                    // drop 掉处理函数的 future, 处理函数被取消
+                    Either::Right(_) => {
+                        log::warn!("{} timed out after {:?}", request_line, start.elapsed());
+                        let on_timeout = deadline.on_timeout.borrow().clone();
+                        return Err(on_timeout());
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// 包装请求 body, 超过截止时间还没有接收完时返回一个错误
+struct BodyDeadline {
+    payload: Payload,
+    delay: Delay,
+    expired: Rc<Cell<bool>>,
+    done: bool,
+}
+
+impl Stream for BodyDeadline {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(item) = Pin::new(&mut self.payload).poll_next(cx) {
+            self.done = item.is_none();
+            return Poll::Ready(item);
+        }
+        match Pin::new(&mut self.delay).poll(cx) {
+            Poll::Ready(()) => {
+                self.done = true;
+                self.expired.set(true);
+                let error = std::io::Error::new(std::io::ErrorKind::TimedOut, "request body timeout");
+                Poll::Ready(Some(Err(PayloadError::Io(error))))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_service::fn_service;
+    use actix_web::test;
+
+    /// 等待 `millis` 毫秒之后读取完整的请求 body
+    fn handler(millis: u64) -> impl Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error> {
+        fn_service(move |req: ServiceRequest| async move {
+            actix_web::rt::time::delay_for(Duration::from_millis(millis)).await;
+            let (req, mut payload) = req.into_parts();
+            while let Some(chunk) = futures::StreamExt::next(&mut payload).await {
+                chunk?;
+            }
+            Ok(ServiceResponse::new(req, HttpResponse::Ok().finish()))
+        })
+    }
+
+    async fn status<S>(service: &mut S, req: ServiceRequest) -> StatusCode
+        where S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    {
+        match service.call(req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn times_out_with_504_or_the_configured_error() {
+        actix_web::rt::System::new("timeout").block_on(async {
+            let timeout = Timeout::new(Duration::from_millis(50));
+            let mut slow = timeout.new_transform(handler(500)).await.unwrap();
+            assert_eq!(status(&mut slow, test::TestRequest::default().to_srv_request()).await, StatusCode::GATEWAY_TIMEOUT);
+            let mut fast = timeout.new_transform(handler(0)).await.unwrap();
+            assert_eq!(status(&mut fast, test::TestRequest::default().to_srv_request()).await, StatusCode::OK);
+
+            let busy = Timeout::new(Duration::from_millis(50)).on_timeout(|| error::ErrorServiceUnavailable("busy"));
+            let mut slow = busy.new_transform(handler(500)).await.unwrap();
+            assert_eq!(status(&mut slow, test::TestRequest::default().to_srv_request()).await, StatusCode::SERVICE_UNAVAILABLE);
+        });
+    }
+
+    #[test]
+    fn innermost_timeout_wins() {
+        actix_web::rt::System::new("timeout").block_on(async {
+            let inner = Timeout::new(Duration::from_millis(500)).new_transform(handler(100)).await.unwrap();
+            let mut outer = Timeout::new(Duration::from_millis(50)).new_transform(inner).await.unwrap();
+            assert_eq!(status(&mut outer, test::TestRequest::default().to_srv_request()).await, StatusCode::OK);
+
+            let inner = Timeout::new(Duration::from_millis(20))
+                .on_timeout(|| error::ErrorServiceUnavailable("busy"))
+                .new_transform(handler(100)).await.unwrap();
+            let mut outer = Timeout::new(Duration::from_millis(500)).new_transform(inner).await.unwrap();
+            assert_eq!(status(&mut outer, test::TestRequest::default().to_srv_request()).await, StatusCode::SERVICE_UNAVAILABLE);
+        });
+    }
+
+    #[test]
+    fn slow_request_body_is_a_408() {
+        actix_web::rt::System::new("timeout").block_on(async {
+            let timeout = Timeout::new(Duration::from_secs(5)).body_timeout(Duration::from_millis(50));
+            let mut service = timeout.new_transform(handler(0)).await.unwrap();
+
+            // body 一直没有发送完
+            let mut req = test::TestRequest::post().to_srv_request();
+            req.set_payload(Payload::Stream(Box::pin(futures::stream::pending())));
+            assert_eq!(status(&mut service, req).await, StatusCode::REQUEST_TIMEOUT);
+
+            let mut req = test::TestRequest::post().to_srv_request();
+            req.set_payload(Payload::Stream(Box::pin(futures::stream::once(async { Ok(Bytes::from_static(b"data")) }))));
+            assert_eq!(status(&mut service, req).await, StatusCode::OK);
+        });
+    }
+}
@@ -0,0 +1,318 @@
+use actix_web::{HttpServer, App, web, get, HttpRequest, HttpResponse, HttpMessage, Error, FromRequest, error,
+                dev::{Payload, Service}};
+use actix_web::cookie::{Cookie, CookieJar, Key};
+use futures::future::{ready, Ready, FutureExt};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+/// ## A/B 实验路由 (Sticky weighted A/B experiments)
+/// `handlers_different_return_types.rs` 中的 index 使用 `rand::thread_rng()` 决定返回哪种响应,
+/// 同一个用户每次请求看到的内容都可能不同.
+///
+/// 这里实现一个简单的实验系统:
+/// * 每个实验包含多个带权重的变体(variant), 用户第一次访问时按权重随机分配一个变体
+/// * 分配结果保存在一个签名的 cookie 中(`exp_实验名`), 之后的请求都会得到同一个变体, cookie 被篡改时会重新分配
+/// * 处理函数通过 `Variant` 提取器拿到分配的变体, 提取器使用哪个实验由路由上注册的 `VariantConfig` 决定,
+///   用法与 `JsonConfig` 相同
+/// * 每个变体的曝光次数可以通过 `/experiments` 查询
+/// * 随机数生成器可以指定种子(`EXPERIMENT_SEED`), 同样的请求顺序得到同样的分配结果, 下面的测试也依赖这一点
+///
+/// 新分配的变体在提取器中被放入请求的 extensions, 然后由 `wrap_fn` 注册的中间件写入响应的 `Set-Cookie`.
+///
+/// 可以使用如下命令查看效果:
+/// ```shell
+/// curl -i -c cookies.txt -b cookies.txt http://127.0.0.1:8080/   # 多次执行结果都相同
+/// curl http://127.0.0.1:8080/experiments
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    // 真实环境中应该使用 Key::generate() 或者从配置中读取密钥, 64个字节
+    let key = Key::from(&[0; 64]);
+    // 设置 EXPERIMENT_SEED 环境变量后, 分配结果是确定的
+    let experiments = match std::env::var("EXPERIMENT_SEED").ok().and_then(|seed| seed.parse().ok()) {
+        Some(seed) => Experiments::with_seed(key, seed),
+        None => Experiments::new(key),
+    };
+    let experiments = web::Data::new(
+        experiments.experiment("homepage", &[("short", 50), ("great", 30), ("plain", 20)])
+    );
+
+    HttpServer::new(move || {
+        App::new().app_data(experiments.clone())
+            // 把新分配的变体写入签名 cookie
+            .wrap_fn(|req, srv| {
+                srv.call(req).map(|res| res.map(|mut res| {
+                    let cookies = res.request().extensions_mut().remove::<NewAssignments>();
+                    if let (Some(cookies), Some(experiments)) =
+                        (cookies, res.request().app_data::<web::Data<Experiments>>().cloned()) {
+                        for (name, variant) in cookies.0 {
+                            let cookie = experiments.sign(&name, &variant);
+                            // 只有在 cookie 合法时才会失败, 这里的 cookie 是我们自己生成的
+                            let _ = res.response_mut().add_cookie(&cookie);
+                        }
+                    }
+                    res
+                }))
+            })
+            .service(web::resource("/")
+                .app_data(VariantConfig::new("homepage"))
+                .route(web::get().to(index)))
+            .service(exposures)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+async fn index(variant: Variant) -> HttpResponse {
+    match variant.name() {
+        "short" => HttpResponse::Ok().body("number less then 5"),
+        "great" => HttpResponse::Ok().body("Great! This is the new homepage"),
+        _ => HttpResponse::Ok().body("plain homepage"),
+    }
+}
+
+/// 查询每个实验中各个变体的曝光次数
+#[get("/experiments")]
+async fn exposures(experiments: web::Data<Experiments>) -> HttpResponse {
+    HttpResponse::Ok().json(experiments.exposures())
+}
+
+/// 一个实验以及它的所有变体与权重
+struct Experiment {
+    variants: Vec<(String, u32)>,
+    /// 所有变体的总权重, 注册时计算
+    total: u32,
+}
+
+impl Experiment {
+    fn contains(&self, variant: &str) -> bool {
+        self.variants.iter().any(|(name, _)| name == variant)
+    }
+
+    /// 按权重选择一个变体, 注册实验时已经保证总权重大于0并且没有溢出
+    fn choose(&self, rng: &mut StdRng) -> String {
+        let mut point = rng.gen_range(0, self.total);
+        for (name, weight) in &self.variants {
+            if point < *weight {
+                return name.clone();
+            }
+            point -= weight;
+        }
+        unreachable!("point is always less than the total weight")
+    }
+}
+
+/// 所有实验的注册表, 作为 web::Data 在所有 worker 之间共享
+struct Experiments {
+    key: Key,
+    experiments: HashMap<String, Experiment>,
+    rng: Mutex<StdRng>,
+    exposures: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+}
+
+impl Experiments {
+    fn new(key: Key) -> Self {
+        Self::with_rng(key, StdRng::from_entropy())
+    }
+
+    /// 使用固定的种子, 同样的请求顺序总是得到同样的分配结果
+    fn with_seed(key: Key, seed: u64) -> Self {
+        Self::with_rng(key, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(key: Key, rng: StdRng) -> Self {
+        Experiments {
+            key,
+            experiments: HashMap::new(),
+            rng: Mutex::new(rng),
+            exposures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// 注册一个实验, 权重为0的变体不会被分配到, 但至少要有一个变体的权重大于0, 总权重不能超过 `u32::MAX`
+    fn experiment(mut self, name: &str, variants: &[(&str, u32)]) -> Self {
+        assert!(!variants.is_empty(), "experiment {} must have at least one variant", name);
+        assert!(variants.iter().any(|(_, weight)| *weight > 0), "experiment {} must have a variant with a positive weight", name);
+        let total = variants.iter().try_fold(0u32, |total, (_, weight)| total.checked_add(*weight))
+            .unwrap_or_else(|| panic!("total weight of experiment {} overflows u32", name));
+        let variants = variants.iter().map(|(name, weight)| (name.to_string(), *weight)).collect();
+        self.experiments.insert(name.to_string(), Experiment { variants, total });
+        self
+    }
+
+    fn cookie_name(experiment: &str) -> String {
+        format!("exp_{}", experiment)
+    }
+
+    /// 从签名 cookie 中读取已分配的变体, 签名不正确或者变体已经不存在时返回 None
+    fn assigned(&self, req: &HttpRequest, experiment: &Experiment, name: &str) -> Option<String> {
+        let cookie = req.cookie(&Self::cookie_name(name))?;
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        let variant = jar.signed(&self.key).get(&Self::cookie_name(name))?.value().to_string();
+        if experiment.contains(&variant) { Some(variant) } else { None }
+    }
+
+    /// 生成签名后的 cookie
+    fn sign(&self, experiment: &str, variant: &str) -> Cookie<'static> {
+        let mut jar = CookieJar::new();
+        let mut cookie = Cookie::new(Self::cookie_name(experiment), variant.to_string());
+        cookie.set_path("/");
+        cookie.set_http_only(true);
+        cookie.set_max_age(time::Duration::days(30));
+        jar.signed(&self.key).add(cookie);
+        jar.get(&Self::cookie_name(experiment)).unwrap().clone()
+    }
+
+    /// 返回已分配的变体以及它是不是新分配的, 同时记录一次曝光
+    fn assign(&self, req: &HttpRequest, name: &str) -> Option<(String, bool)> {
+        let experiment = self.experiments.get(name)?;
+        let (variant, is_new) = match self.assigned(req, experiment, name) {
+            Some(variant) => (variant, false),
+            None => (experiment.choose(&mut self.rng.lock().unwrap()), true),
+        };
+        *self.exposures.lock().unwrap()
+            .entry(name.to_string()).or_default()
+            .entry(variant.clone()).or_insert(0) += 1;
+        Some((variant, is_new))
+    }
+
+    fn exposures(&self) -> BTreeMap<String, BTreeMap<String, u64>> {
+        self.exposures.lock().unwrap().clone()
+    }
+}
+
+/// 本次请求中新分配的变体, 需要写入响应 cookie
+#[derive(Default)]
+struct NewAssignments(Vec<(String, String)>);
+
+/// `Variant` 提取器的配置, 指定使用哪个实验
+#[derive(Clone, Default)]
+struct VariantConfig {
+    experiment: String,
+}
+
+impl VariantConfig {
+    fn new(experiment: &str) -> Self {
+        VariantConfig { experiment: experiment.to_string() }
+    }
+}
+
+/// 当前用户在某个实验中被分配到的变体
+struct Variant {
+    name: String,
+}
+
+impl Variant {
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl FromRequest for Variant {
+    type Error = Error;
+    type Future = Ready<Result<Self, Error>>;
+    type Config = VariantConfig;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let config = match req.app_data::<VariantConfig>() {
+            Some(config) => config,
+            None => return ready(Err(error::ErrorInternalServerError("VariantConfig is not registered"))),
+        };
+        let experiments = match req.app_data::<web::Data<Experiments>>() {
+            Some(experiments) => experiments,
+            None => return ready(Err(error::ErrorInternalServerError("Experiments is not registered"))),
+        };
+
+        ready(match experiments.assign(req, &config.experiment) {
+            Some((name, is_new)) => {
+                if is_new {
+                    let mut extensions = req.extensions_mut();
+                    if extensions.get::<NewAssignments>().is_none() {
+                        extensions.insert(NewAssignments::default());
+                    }
+                    extensions.get_mut::<NewAssignments>().unwrap().0.push((config.experiment.clone(), name.clone()));
+                }
+                Ok(Variant { name })
+            }
+            None => Err(error::ErrorInternalServerError(format!("unknown experiment: {}", config.experiment))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn experiments(seed: u64) -> Experiments {
+        Experiments::with_seed(Key::from(&[0; 64]), seed)
+            .experiment("homepage", &[("short", 50), ("great", 30), ("plain", 20), ("off", 0)])
+    }
+
+    #[test]
+    fn choose_follows_weights() {
+        let experiments = experiments(42);
+        let experiment = &experiments.experiments["homepage"];
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut counts = HashMap::new();
+        for _ in 0..10_000 {
+            *counts.entry(experiment.choose(&mut rng)).or_insert(0) += 1;
+        }
+        assert!((4_700..5_300).contains(&counts["short"]), "{:?}", counts);
+        assert!((2_700..3_300).contains(&counts["great"]), "{:?}", counts);
+        assert!((1_700..2_300).contains(&counts["plain"]), "{:?}", counts);
+        assert!(!counts.contains_key("off"));
+    }
+
+    #[test]
+    fn same_seed_same_assignments() {
+        let (a, b) = (experiments(7), experiments(7));
+        for _ in 0..20 {
+            let req = TestRequest::default().to_http_request();
+            assert_eq!(a.assign(&req, "homepage"), b.assign(&req, "homepage"));
+        }
+    }
+
+    #[test]
+    fn signed_cookie_is_sticky() {
+        let experiments = experiments(1);
+        let (variant, is_new) = experiments.assign(&TestRequest::default().to_http_request(), "homepage").unwrap();
+        assert!(is_new);
+
+        let cookie = experiments.sign("homepage", &variant);
+        for _ in 0..20 {
+            let req = TestRequest::default().cookie(cookie.clone()).to_http_request();
+            assert_eq!(experiments.assign(&req, "homepage"), Some((variant.clone(), false)));
+        }
+    }
+
+    #[test]
+    fn tampered_cookie_is_reassigned() {
+        let experiments = experiments(1);
+        let req = TestRequest::default().cookie(Cookie::new("exp_homepage", "great")).to_http_request();
+        let (_, is_new) = experiments.assign(&req, "homepage").unwrap();
+        assert!(is_new);
+    }
+
+    #[test]
+    #[should_panic(expected = "positive weight")]
+    fn all_zero_weights_are_rejected() {
+        let _ = Experiments::new(Key::from(&[0; 64])).experiment("empty", &[("a", 0), ("b", 0)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows u32")]
+    fn overflowing_weights_are_rejected() {
+        let _ = Experiments::new(Key::from(&[0; 64])).experiment("huge", &[("a", u32::MAX), ("b", 1)]);
+    }
+
+    #[test]
+    fn max_total_weight_is_accepted() {
+        let experiments = Experiments::with_seed(Key::from(&[0; 64]), 3)
+            .experiment("edge", &[("a", u32::MAX - 1), ("b", 1)]);
+        let mut rng = StdRng::seed_from_u64(3);
+        assert_eq!(experiments.experiments["edge"].choose(&mut rng), "a");
+    }
+}
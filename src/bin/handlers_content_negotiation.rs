@@ -0,0 +1,358 @@
+use actix_web::{HttpServer, App, HttpResponse, Responder, HttpRequest, Error, get, error, http::header, http::HeaderValue};
+use serde::Serialize;
+use serde_json::Value;
+use futures::future::{ready, Ready};
+
+/// ## 内容协商 (Content Negotiation)
+/// `handlers_response_with_custom_type.rs` 中手动为 MyObj 实现的 Responder 总是返回 json, 并且直接 unwrap 了序列化结果.
+///
+/// 这里实现一个泛型的 `Negotiated<T: Serialize>` 响应器, 它会读取请求头中的 `Accept`(支持 q 值权重),
+/// 然后选择服务端支持的格式之一来渲染响应:
+/// * application/json
+/// * application/xml (text/xml)
+/// * application/yaml (application/x-yaml, text/yaml)
+/// * application/msgpack (application/x-msgpack)
+/// * text/html (渲染成一个 html 表格)
+///
+/// 没有任何格式能满足 `Accept` 时返回 406 Not Acceptable, 所有响应都会带上 `Vary: Accept` 头, 告诉缓存服务器
+/// 响应内容会随 `Accept` 变化. 序列化失败时返回 500 而不是 panic.
+///
+/// 可以使用如下命令查看效果:
+/// ```shell
+/// curl -H "Accept: application/xml" http://127.0.0.1:8080/user
+/// curl -H "Accept: text/yaml;q=0.9, text/html;q=0.5" http://127.0.0.1:8080/users
+/// curl -i -H "Accept: image/png" http://127.0.0.1:8080/user # 406
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| {
+        App::new().service(user)
+            .service(users)
+    }).bind(SERVER_ADDRESS)?
+        .run().await
+}
+
+const SERVER_ADDRESS: &str = "127.0.0.1:8080";
+
+#[derive(Serialize)]
+struct User {
+    name: String,
+    age: u32,
+    tags: Vec<String>,
+}
+
+#[get("/user")]
+async fn user() -> Negotiated<User> {
+    Negotiated(User { name: "dsl".to_string(), age: 18, tags: vec!["rust".to_string(), "actix".to_string()] })
+}
+
+#[get("/users")]
+async fn users() -> Negotiated<Vec<User>> {
+    Negotiated(vec![
+        User { name: "dsl".to_string(), age: 18, tags: vec!["rust".to_string()] },
+        User { name: "<script>".to_string(), age: 20, tags: vec![] },
+    ])
+}
+
+/// 服务端支持的响应格式, 声明顺序就是服务端的偏好顺序(q 值相同时靠前的优先)
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Xml,
+    Yaml,
+    MsgPack,
+    Html,
+}
+
+impl Format {
+    const ALL: [Format; 5] = [Format::Json, Format::Xml, Format::Yaml, Format::MsgPack, Format::Html];
+
+    /// 每种格式可以被哪些媒体类型匹配到, 第一个是响应时使用的 Content-Type
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Format::Json => &["application/json"],
+            Format::Xml => &["application/xml", "text/xml"],
+            Format::Yaml => &["application/yaml", "application/x-yaml", "text/yaml"],
+            Format::MsgPack => &["application/msgpack", "application/x-msgpack"],
+            Format::Html => &["text/html"],
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            _ => self.media_types()[0],
+        }
+    }
+
+    /// 按格式序列化数据
+    fn render<T: Serialize>(self, data: &T) -> Result<Vec<u8>, Error> {
+        let body = match self {
+            Format::Json => serde_json::to_vec(data).map_err(error::ErrorInternalServerError)?,
+            Format::Xml => {
+                let value = serde_json::to_value(data).map_err(error::ErrorInternalServerError)?;
+                let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+                render_xml("response", &value, &mut xml);
+                xml.into_bytes()
+            }
+            Format::Yaml => serde_yaml::to_vec(data).map_err(error::ErrorInternalServerError)?,
+            Format::MsgPack => rmp_serde::to_vec_named(data).map_err(error::ErrorInternalServerError)?,
+            Format::Html => {
+                let value = serde_json::to_value(data).map_err(error::ErrorInternalServerError)?;
+                let mut html = String::from("<!DOCTYPE html><html><body>");
+                render_html(&value, &mut html);
+                html.push_str("</body></html>");
+                html.into_bytes()
+            }
+        };
+        Ok(body)
+    }
+}
+
+/// `Accept` 头中的一个媒体范围, 比如 `text/*;q=0.8`
+struct MediaRange {
+    main: String,
+    sub: String,
+    q: f32,
+}
+
+impl MediaRange {
+    /// 匹配程度: 完全匹配为 2, `type/*` 为 1, `*/*` 为 0, 不匹配返回 None
+    fn specificity(&self, media_type: &str) -> Option<u8> {
+        let mut parts = media_type.splitn(2, '/');
+        let (main, sub) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+        match (self.main.as_str(), self.sub.as_str()) {
+            ("*", "*") => Some(0),
+            (m, "*") if m == main => Some(1),
+            (m, s) if m == main && s == sub => Some(2),
+            _ => None,
+        }
+    }
+}
+
+/// 解析 `Accept` 头, 无法解析的片段直接忽略
+fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept.split(',').filter_map(|item| {
+        let mut params = item.split(';');
+        let media = params.next()?.trim().to_ascii_lowercase();
+        let mut parts = media.splitn(2, '/');
+        let (main, sub) = (parts.next()?.to_string(), parts.next()?.to_string());
+        if main.is_empty() || sub.is_empty() {
+            return None;
+        }
+        let q = params.filter_map(|p| {
+            let mut kv = p.splitn(2, '=');
+            match (kv.next()?.trim(), kv.next()?.trim()) {
+                ("q", v) => v.parse::<f32>().ok().filter(|q| q.is_finite()),
+                _ => None,
+            }
+        }).next().unwrap_or(1.0).clamp(0.0, 1.0);
+        Some(MediaRange { main, sub, q })
+    }).collect()
+}
+
+/// 为每种格式找到最具体的那个媒体范围并取它的 q 值, 选出 q 值最大且大于 0 的格式.
+/// 没有 `Accept` 头时等同于 `*/*`, 返回服务端首选的 json.
+fn negotiate(accept: Option<&str>) -> Option<Format> {
+    let ranges = match accept {
+        Some(accept) if !accept.trim().is_empty() => parse_accept(accept),
+        _ => return Some(Format::ALL[0]),
+    };
+
+    let mut best: Option<(Format, f32)> = None;
+    for format in Format::ALL.iter().copied() {
+        let q = format.media_types().iter().filter_map(|media_type| {
+            ranges.iter()
+                .filter_map(|range| range.specificity(media_type).map(|s| (s, range.q)))
+                .max_by(|a, b| a.0.cmp(&b.0))
+        }).max_by(|a, b| a.0.cmp(&b.0).then(a.1.partial_cmp(&b.1).unwrap()))
+            .map(|(_, q)| q)
+            .unwrap_or(0.0);
+        if q > 0.0 && !matches!(best, Some((_, best_q)) if best_q >= q) {
+            best = Some((format, q));
+        }
+    }
+    best.map(|(format, _)| format)
+}
+
+/// 把 json 值渲染成 xml: 对象的字段渲染成子元素, 数组字段渲染成多个同名元素, 顶层或嵌套的数组元素使用 `<item>`
+fn render_xml(name: &str, value: &Value, out: &mut String) {
+    let name = xml_name(name);
+    match value {
+        Value::Object(map) => {
+            out.push_str(&format!("<{}>", name));
+            for (key, value) in map {
+                match value {
+                    Value::Array(items) => items.iter().for_each(|item| render_xml(key, item, out)),
+                    _ => render_xml(key, value, out),
+                }
+            }
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Array(items) => {
+            out.push_str(&format!("<{}>", name));
+            items.iter().for_each(|item| render_xml("item", item, out));
+            out.push_str(&format!("</{}>", name));
+        }
+        Value::Null => out.push_str(&format!("<{}/>", name)),
+        Value::String(s) => out.push_str(&format!("<{0}>{1}</{0}>", name, escape_html(s))),
+        other => out.push_str(&format!("<{0}>{1}</{0}>", name, other)),
+    }
+}
+
+/// map 的 key 可以是任意字符串, 把不能出现在 xml 元素名中的字符替换成 `_`
+fn xml_name(name: &str) -> String {
+    let name: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '_' || c == '-' || c == '.' { c } else { '_' })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_alphabetic() || c == '_' => name,
+        _ => format!("_{}", name),
+    }
+}
+
+/// 把 json 值渲染成 html: 对象渲染成 key/value 两列的表格, 元素全是对象的数组渲染成多列表格, 其它数组渲染成列表
+fn render_html(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(map) => {
+            out.push_str("<table>");
+            for (key, value) in map {
+                out.push_str("<tr><th>");
+                out.push_str(&escape_html(key));
+                out.push_str("</th><td>");
+                render_html(value, out);
+                out.push_str("</td></tr>");
+            }
+            out.push_str("</table>");
+        }
+        Value::Array(items) if !items.is_empty() && items.iter().all(Value::is_object) => {
+            let mut columns: Vec<&String> = Vec::new();
+            for item in items.iter().filter_map(Value::as_object) {
+                for key in item.keys() {
+                    if !columns.contains(&key) {
+                        columns.push(key);
+                    }
+                }
+            }
+            out.push_str("<table><tr>");
+            for column in &columns {
+                out.push_str("<th>");
+                out.push_str(&escape_html(column));
+                out.push_str("</th>");
+            }
+            out.push_str("</tr>");
+            for item in items.iter().filter_map(Value::as_object) {
+                out.push_str("<tr>");
+                for column in &columns {
+                    out.push_str("<td>");
+                    render_html(item.get(column.as_str()).unwrap_or(&Value::Null), out);
+                    out.push_str("</td>");
+                }
+                out.push_str("</tr>");
+            }
+            out.push_str("</table>");
+        }
+        Value::Array(items) => {
+            out.push_str("<ul>");
+            for item in items {
+                out.push_str("<li>");
+                render_html(item, out);
+                out.push_str("</li>");
+            }
+            out.push_str("</ul>");
+        }
+        Value::String(s) => out.push_str(&escape_html(s)),
+        Value::Null => {}
+        other => out.push_str(&escape_html(&other.to_string())),
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// 根据 `Accept` 选择响应格式的泛型 Responder
+pub struct Negotiated<T: Serialize>(pub T);
+
+impl<T: Serialize> Responder for Negotiated<T> {
+    type Error = Error;
+    type Future = Ready<Result<HttpResponse, Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        let accept = req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok());
+
+        let mut res = match negotiate(accept) {
+            Some(format) => match format.render(&self.0) {
+                Ok(body) => HttpResponse::Ok().content_type(format.content_type()).body(body),
+                // 在这里生成错误响应, 这样 500 也带有 Vary
+                Err(e) => HttpResponse::from_error(e),
+            },
+            None => {
+                // 列出服务端支持的类型, 方便客户端重试
+                let supported: Vec<&str> = Format::ALL.iter().map(|f| f.media_types()[0]).collect();
+                HttpResponse::NotAcceptable()
+                    .body(format!("Not Acceptable, supported types: {}", supported.join(", ")))
+            }
+        };
+        res.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+        ready(Ok(res))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[test]
+    fn ranks_formats_by_quality() {
+        assert_eq!(negotiate(None), Some(Format::Json));
+        assert_eq!(negotiate(Some("*/*")), Some(Format::Json));
+        assert_eq!(negotiate(Some("text/yaml;q=0.9, text/html;q=0.5")), Some(Format::Yaml));
+        assert_eq!(negotiate(Some("application/json;q=0.1, text/xml")), Some(Format::Xml));
+        // q 值相同时按服务端的偏好顺序
+        assert_eq!(negotiate(Some("text/html, application/x-msgpack")), Some(Format::MsgPack));
+        // 更具体的媒体范围优先, 即使它的 q 值更低
+        assert_eq!(negotiate(Some("application/*;q=0.9, application/json;q=0.2, text/html;q=0.5")), Some(Format::Xml));
+        assert_eq!(negotiate(Some("*/*;q=0.1, application/json;q=0")), Some(Format::Xml));
+        assert_eq!(negotiate(Some("image/png")), None);
+        assert_eq!(negotiate(Some("application/json;q=0")), None);
+    }
+
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("cannot serialize"))
+        }
+    }
+
+    #[test]
+    fn every_response_varies_on_accept() {
+        actix_web::rt::System::new("negotiation").block_on(async {
+            let mut app = test::init_service(App::new().service(user)).await;
+
+            let req = test::TestRequest::with_uri("/user").header(header::ACCEPT, "application/yaml").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/yaml");
+            assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+
+            let req = test::TestRequest::with_uri("/user").header(header::ACCEPT, "image/png").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+            assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+            let body = test::read_body(res).await;
+            assert!(std::str::from_utf8(&body).unwrap().contains("application/json"));
+
+            let req = test::TestRequest::default().to_http_request();
+            let res = Negotiated(Unserializable).respond_to(&req).await.unwrap();
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+        });
+    }
+}
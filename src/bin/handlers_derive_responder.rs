@@ -0,0 +1,96 @@
+use actix_web::{HttpServer, App, get};
+use responder_derive::Responder;
+use serde::Serialize;
+use rand::Rng;
+use std::collections::HashMap;
+
+/// ## 派生 Responder (#[derive(Responder)])
+/// `handlers_response_with_custom_type.rs` 中为了返回 MyObj 需要手写差不多10行 Responder 实现,
+/// `handlers_different_return_types.rs` 中为了返回两种不同的响应又要使用 `Either<HttpResponse, Result<String, Error>>`.
+///
+/// 工作空间中的 `responder-derive` 包提供了一个 `#[derive(Responder)]` 派生宏, 它可以通过 `#[responder(...)]`
+/// 属性设置状态码(status), 内容类型(content_type) 以及额外的响应头(header), 对于枚举则可以为每一个变体声明一种响应,
+/// 用来代替嵌套的 `Either`.
+///
+/// 先在Cargo.toml文件中添加如下依赖项:
+/// ```rust
+/// responder-derive = { path = "responder-derive" }
+/// # 生成的代码使用它们序列化 json body
+/// serde = { version = "1.0", features = ["derive"] }
+/// serde_json = "1.0"
+/// ```
+///
+/// 序列化失败不会 panic, 而是返回 500, 可以访问 `/broken` 查看效果.
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| {
+        App::new().service(index)
+            .service(register)
+            .service(broken)
+            .service(names)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 相当于手动实现的 MyObj, 序列化成 json 并额外设置一个响应头
+#[derive(Serialize, Responder)]
+#[responder(header("X-Version", "0.2"))]
+struct MyObj {
+    name: &'static str,
+}
+
+#[get("/")]
+async fn index() -> MyObj {
+    MyObj { name: "user" }
+}
+
+/// 每一个变体对应一种响应, 代替 Either<HttpResponse, Result<String, Error>>
+#[derive(Responder)]
+enum RegisterResponse {
+    /// 纯文本响应
+    #[responder(text)]
+    Small(&'static str),
+    /// 命名字段序列化成 json 对象, 状态码 202
+    #[responder(status = 202)]
+    Great { number: u32, message: String },
+    /// 没有 body 的响应
+    #[responder(status = 204, header("X-Reason", "zero"))]
+    Zero,
+}
+
+#[get("/register")]
+async fn register() -> RegisterResponse {
+    // 产生一个 0-9的随机整数
+    let rand_num = rand::thread_rng().gen_range(0, 9);
+    match rand_num {
+        0 => RegisterResponse::Zero,
+        n if n < 5 => RegisterResponse::Small("number less then 5"),
+        n => RegisterResponse::Great { number: n, message: format!("Great! This number is {}", n) },
+    }
+}
+
+/// json 的 key 必须是字符串, 所以这个结构体序列化时一定会失败
+#[derive(Serialize, Responder)]
+struct Broken {
+    points: HashMap<(u8, u8), u8>,
+}
+
+#[get("/broken")]
+async fn broken() -> Broken {
+    let mut points = HashMap::new();
+    points.insert((1, 2), 3);
+    Broken { points }
+}
+
+/// 泛型结构体, 派生宏会自动添加 `T: Serialize` 约束
+#[derive(Serialize, Responder)]
+struct Page<T> {
+    items: Vec<T>,
+    total: usize,
+}
+
+#[get("/names")]
+async fn names() -> Page<&'static str> {
+    let items = vec!["tom", "jerry"];
+    Page { total: items.len(), items }
+}
@@ -0,0 +1,286 @@
+use actix_web::{HttpServer, App, web, get, post, HttpRequest, HttpResponse, Error, http::header};
+use bytes::Bytes;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// ## 服务器推送事件 (Server-Sent Events)
+/// `handlers_streaming_response_body.rs` 中的 stream() 只返回了一个静态的 Bytes 块.
+/// 在流式响应的基础上, 这里实现一个 SSE 广播器:
+/// * 按名称区分的频道(channel), 处理函数可以向频道发布带类型的事件. 频道在启动时通过 `SSE_CHANNELS` 环境变量配置
+///   (逗号分隔, 默认是 `chat,news`), 订阅或者发布到其它名称时返回 404, 客户端不能无限制地创建频道
+/// * `GET /events/{channel}` 返回 `text/event-stream` 响应, 订阅一个频道
+/// * 定时向所有订阅者发送心跳注释(`: heartbeat`), 并在连接开始时发送 `retry:` 告诉浏览器重连间隔
+/// * 每个频道用一个有界的环形缓冲区保存最近的事件, 客户端重连时通过 `Last-Event-ID` 补发错过的事件
+/// * actix-web 在写入失败时才能发现客户端已经断开, 所以心跳也用来检测断开的连接: 断开后的一到两次心跳之内
+///   响应 body 会被 drop, 订阅者随之从频道中移除; 消费太慢的订阅者也会被断开, 它可以带着 `Last-Event-ID` 重连
+///
+/// 可以使用如下命令查看效果:
+/// ```shell
+/// curl -N http://127.0.0.1:8080/events/chat
+/// curl -X POST -H "Content-Type: application/json" -d '{"user":"dsl","text":"hi"}' http://127.0.0.1:8080/chat/chat
+/// curl -N -H "Last-Event-ID: 1" http://127.0.0.1:8080/events/chat   # 补发 id 大于1的事件
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let names = std::env::var("SSE_CHANNELS").unwrap_or_else(|_| "chat,news".to_string());
+    let names: Vec<&str> = names.split(',').map(str::trim).filter(|name| !name.is_empty()).collect();
+    let broadcaster = web::Data::new(Mutex::new(Broadcaster::new(REPLAY_CAPACITY, &names)));
+
+    // 定时发送心跳, 同时可以发现已经失效的订阅者
+    let heartbeat = broadcaster.clone();
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            interval.tick().await;
+            heartbeat.lock().unwrap().heartbeat();
+        }
+    });
+
+    HttpServer::new(move || {
+        App::new().app_data(broadcaster.clone())
+            .service(subscribe)
+            .service(chat)
+            .service(channels)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 每个频道最多保存多少个历史事件
+const REPLAY_CAPACITY: usize = 100;
+/// 每个订阅者最多缓存多少个还没发送出去的消息
+const SUBSCRIBER_BUFFER: usize = 64;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// 浏览器 EventSource 断线后的重连间隔(毫秒)
+const RETRY_MILLIS: u64 = 3000;
+
+/// 订阅一个频道, 支持 `Last-Event-ID` 补发
+#[get("/events/{channel}")]
+async fn subscribe(req: HttpRequest, channel: web::Path<String>,
+                   broadcaster: web::Data<Mutex<Broadcaster>>) -> HttpResponse {
+    let last_event_id = req.headers().get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok());
+
+    match Broadcaster::subscribe(&broadcaster, &channel, last_event_id) {
+        Some(subscription) => HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .set_header(header::CACHE_CONTROL, "no-cache")
+            .streaming(subscription),
+        None => HttpResponse::NotFound().body(format!("unknown channel {}", channel)),
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+struct ChatMessage {
+    user: String,
+    text: String,
+}
+
+/// 处理函数向频道发布一个带类型的事件
+#[post("/chat/{channel}")]
+async fn chat(channel: web::Path<String>, message: web::Json<ChatMessage>,
+              broadcaster: web::Data<Mutex<Broadcaster>>) -> Result<HttpResponse, Error> {
+    let id = broadcaster.lock().unwrap().publish(&channel, "message", &message.into_inner())?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": id })))
+}
+
+/// 查看每个频道的订阅者数量
+#[get("/channels")]
+async fn channels(broadcaster: web::Data<Mutex<Broadcaster>>) -> HttpResponse {
+    let broadcaster = broadcaster.lock().unwrap();
+    let counts: HashMap<&str, usize> = broadcaster.channels.iter()
+        .map(|(name, channel)| (name.as_str(), channel.subscribers.len()))
+        .collect();
+    HttpResponse::Ok().json(counts)
+}
+
+/// 一个已经编码好的事件
+struct Event {
+    id: u64,
+    frame: Bytes,
+}
+
+/// 按 SSE 格式编码一个事件, 多行数据需要拆成多个 `data:` 行
+fn encode_event(id: u64, event: &str, data: &str) -> Bytes {
+    let mut frame = format!("id: {}\nevent: {}\n", id, event);
+    for line in data.lines() {
+        frame.push_str("data: ");
+        frame.push_str(line);
+        frame.push('\n');
+    }
+    frame.push('\n');
+    Bytes::from(frame)
+}
+
+struct Channel {
+    next_id: u64,
+    /// 最近的事件, 用来补发
+    history: VecDeque<Event>,
+    subscribers: HashMap<u64, Sender<Bytes>>,
+}
+
+struct Broadcaster {
+    channels: HashMap<String, Channel>,
+    capacity: usize,
+    next_subscriber: u64,
+}
+
+impl Broadcaster {
+    /// 只有 `names` 中的频道可以订阅与发布
+    fn new(capacity: usize, names: &[&str]) -> Self {
+        let named = names.iter()
+            .map(|name| (name.to_string(), Channel { next_id: 0, history: VecDeque::new(), subscribers: HashMap::new() }))
+            .collect();
+        Broadcaster { channels: named, capacity, next_subscriber: 0 }
+    }
+
+    /// 创建一个订阅, 先发送 retry 提示与需要补发的事件. 频道不存在时返回 `None`
+    fn subscribe(this: &web::Data<Mutex<Broadcaster>>, name: &str, last_event_id: Option<u64>) -> Option<Subscription> {
+        let mut broadcaster = this.lock().unwrap();
+        let capacity = broadcaster.capacity;
+        let id = broadcaster.next_subscriber;
+        let (mut tx, rx) = channel(SUBSCRIBER_BUFFER + capacity);
+        let channel = broadcaster.channels.get_mut(name)?;
+        let _ = tx.try_send(Bytes::from(format!("retry: {}\n\n", RETRY_MILLIS)));
+        if let Some(last) = last_event_id {
+            for event in channel.history.iter().filter(|e| e.id > last) {
+                let _ = tx.try_send(event.frame.clone());
+            }
+        }
+        channel.subscribers.insert(id, tx);
+        broadcaster.next_subscriber += 1;
+
+        Some(Subscription { id, channel: name.to_string(), rx, broadcaster: this.clone() })
+    }
+
+    fn unsubscribe(&mut self, name: &str, id: u64) {
+        if let Some(channel) = self.channels.get_mut(name) {
+            channel.subscribers.remove(&id);
+        }
+    }
+
+    /// 序列化并发布一个事件, 返回事件 id. 频道不存在时返回 404
+    fn publish<T: Serialize>(&mut self, name: &str, event: &str, data: &T) -> Result<u64, Error> {
+        let data = serde_json::to_string(data)?;
+        let capacity = self.capacity;
+        let channel = self.channels.get_mut(name)
+            .ok_or_else(|| actix_web::error::ErrorNotFound(format!("unknown channel {}", name)))?;
+        channel.next_id += 1;
+        let event = Event { id: channel.next_id, frame: encode_event(channel.next_id, event, &data) };
+
+        // 发送失败说明客户端已经断开或者消费太慢, 直接移除
+        let frame = event.frame.clone();
+        channel.subscribers.retain(|_, tx| tx.try_send(frame.clone()).is_ok());
+
+        channel.history.push_back(event);
+        while channel.history.len() > capacity {
+            channel.history.pop_front();
+        }
+        Ok(channel.next_id)
+    }
+
+    /// 发送心跳注释, EventSource 会忽略以 `:` 开头的行
+    fn heartbeat(&mut self) {
+        let frame = Bytes::from_static(b": heartbeat\n\n");
+        for channel in self.channels.values_mut() {
+            channel.subscribers.retain(|_, tx| tx.try_send(frame.clone()).is_ok());
+        }
+    }
+}
+
+/// 一个订阅者的事件流, 作为 streaming 响应的 body.
+/// 向断开的客户端写入失败后 actix-web 会 drop 响应 body, 此时从频道中移除这个订阅者.
+struct Subscription {
+    id: u64,
+    channel: String,
+    rx: Receiver<Bytes>,
+    broadcaster: web::Data<Mutex<Broadcaster>>,
+}
+
+impl Stream for Subscription {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_next_unpin(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        if let Ok(mut broadcaster) = self.broadcaster.lock() {
+            broadcaster.unsubscribe(&self.channel, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, http::StatusCode};
+    use futures::executor::block_on;
+
+    fn broadcaster(capacity: usize) -> web::Data<Mutex<Broadcaster>> {
+        web::Data::new(Mutex::new(Broadcaster::new(capacity, &["chat"])))
+    }
+
+    fn text(frame: Option<Result<Bytes, Error>>) -> String {
+        String::from_utf8(frame.unwrap().unwrap().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn encodes_multi_line_data() {
+        assert_eq!(encode_event(3, "message", "a\nb"), Bytes::from("id: 3\nevent: message\ndata: a\ndata: b\n\n"));
+    }
+
+    #[test]
+    fn replays_events_after_last_event_id() {
+        let broadcaster = broadcaster(2);
+        for text in &["one", "two", "three"] {
+            broadcaster.lock().unwrap().publish("chat", "message", text).unwrap();
+        }
+
+        let mut subscription = Broadcaster::subscribe(&broadcaster, "chat", Some(1)).unwrap();
+        assert_eq!(text(block_on(subscription.next())), "retry: 3000\n\n");
+        // 只保存最近的两个事件, id 为 1 的事件已经被丢弃
+        assert_eq!(text(block_on(subscription.next())), "id: 2\nevent: message\ndata: \"two\"\n\n");
+        assert_eq!(text(block_on(subscription.next())), "id: 3\nevent: message\ndata: \"three\"\n\n");
+
+        broadcaster.lock().unwrap().publish("chat", "message", &"four").unwrap();
+        assert_eq!(text(block_on(subscription.next())), "id: 4\nevent: message\ndata: \"four\"\n\n");
+        broadcaster.lock().unwrap().heartbeat();
+        assert_eq!(text(block_on(subscription.next())), ": heartbeat\n\n");
+    }
+
+    #[test]
+    fn dropped_subscription_leaves_the_channel() {
+        let broadcaster = broadcaster(10);
+        let subscription = Broadcaster::subscribe(&broadcaster, "chat", None).unwrap();
+        assert_eq!(broadcaster.lock().unwrap().channels["chat"].subscribers.len(), 1);
+        drop(subscription);
+        assert!(broadcaster.lock().unwrap().channels["chat"].subscribers.is_empty());
+    }
+
+    #[test]
+    fn unknown_channels_are_not_created() {
+        let broadcaster = broadcaster(10);
+        assert!(Broadcaster::subscribe(&broadcaster, "other", None).is_none());
+        assert!(broadcaster.lock().unwrap().publish("other", "message", &"hi").is_err());
+        assert_eq!(broadcaster.lock().unwrap().channels.len(), 1);
+
+        actix_web::rt::System::new("sse").block_on(async move {
+            let mut app = test::init_service(App::new().app_data(broadcaster).service(subscribe).service(chat)).await;
+            let req = test::TestRequest::get().uri("/events/other").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::NOT_FOUND);
+            let req = test::TestRequest::post().uri("/chat/other")
+                .set_json(&ChatMessage { user: "dsl".to_string(), text: "hi".to_string() })
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::NOT_FOUND);
+        });
+    }
+}
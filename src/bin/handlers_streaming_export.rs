@@ -0,0 +1,235 @@
+use actix_web::{HttpServer, App, web, get, HttpResponse, Error, error, http::header};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::{Stream, StreamExt, SinkExt};
+use serde::{Deserialize, Serialize};
+use env_logger::Env;
+
+/// ## 流式导出 (Streaming export)
+/// `handlers_streaming_response_body.rs` 只演示了 `futures::stream::once`, 导出一个很大的数据集时如果先把所有数据
+/// 放进内存再返回, 内存占用会随数据量一起增长.
+///
+/// 这里提供两个辅助函数, 把一行一行的 `Serialize` 数据转换成分块(chunked)的 CSV, NDJSON 或 JSON 数组响应:
+/// * `export_iter()` - 数据来自一个阻塞的迭代器(比如数据库游标), 迭代器通过 `web::block` 在 actix 的阻塞线程池中运行,
+///   通过一个有界 channel 把数据块发送给响应. channel 满了时生产者线程会被阻塞(背压), 客户端断开后 channel 关闭,
+///   生产者随之停止. 线程池的大小是固定的(`ACTIX_THREADPOOL` 环境变量), 同时进行的导出超过线程数时会排队等待.
+/// * `export_stream()` - 数据来自一个异步 Stream, 响应按需拉取数据, 客户端断开时 Stream 被 drop.
+///
+/// 两种方式都会把数据攒到 `CHUNK_SIZE` 大小后再发送一块, 既不会一行一个块, 也不会无限制地占用内存.
+///
+/// 可以使用如下命令查看效果:
+/// ```shell
+/// curl http://127.0.0.1:8080/export/users.csv?count=10
+/// curl http://127.0.0.1:8080/export/stream.ndjson?count=10
+/// curl http://127.0.0.1:8080/export/users.json?count=100000000 | head -c 100  # 提前断开, 日志中可以看到生产者被取消
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    HttpServer::new(|| {
+        App::new().service(export_users)
+            .service(export_user_stream)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 每个数据块的目标大小
+const CHUNK_SIZE: usize = 16 * 1024;
+/// 生产者最多可以领先消费者多少个数据块
+const MAX_PENDING_CHUNKS: usize = 4;
+
+#[derive(Serialize)]
+struct User {
+    id: u64,
+    name: String,
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    count: Option<u64>,
+}
+
+/// 模拟一个阻塞的数据库游标
+#[get("/export/users.{format}")]
+async fn export_users(format: web::Path<String>, query: web::Query<ExportQuery>) -> Result<HttpResponse, Error> {
+    let format = ExportFormat::from_extension(&format)?;
+    let count = query.count.unwrap_or(1000);
+    let rows = (0..count).map(|id| User { id, name: format!("user{}", id), email: format!("user{}@example.com", id) });
+    Ok(export_iter(format, "users", rows))
+}
+
+/// 数据来自一个异步 Stream
+#[get("/export/stream.{format}")]
+async fn export_user_stream(format: web::Path<String>, query: web::Query<ExportQuery>) -> Result<HttpResponse, Error> {
+    let format = ExportFormat::from_extension(&format)?;
+    let count = query.count.unwrap_or(1000);
+    let rows = futures::stream::iter(0..count)
+        .map(|id| User { id, name: format!("user{}", id), email: format!("user{}@example.com", id) });
+    Ok(export_stream(format, "users", rows))
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Csv,
+    NdJson,
+    JsonArray,
+}
+
+impl ExportFormat {
+    fn from_extension(ext: &str) -> Result<Self, Error> {
+        match ext {
+            "csv" => Ok(ExportFormat::Csv),
+            "ndjson" => Ok(ExportFormat::NdJson),
+            "json" => Ok(ExportFormat::JsonArray),
+            other => Err(error::ErrorNotFound(format!("unsupported export format: {}", other))),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::NdJson => "application/x-ndjson",
+            ExportFormat::JsonArray => "application/json",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::NdJson => "ndjson",
+            ExportFormat::JsonArray => "json",
+        }
+    }
+}
+
+/// 把一行一行的数据编码到缓冲区中
+struct RowEncoder {
+    format: ExportFormat,
+    rows: u64,
+    buf: Vec<u8>,
+}
+
+impl RowEncoder {
+    fn new(format: ExportFormat) -> Self {
+        RowEncoder { format, rows: 0, buf: Vec::with_capacity(CHUNK_SIZE) }
+    }
+
+    fn encode<T: Serialize>(&mut self, row: &T) -> Result<(), Error> {
+        match self.format {
+            ExportFormat::Csv => {
+                // 只有第一行前面输出表头
+                let mut writer = csv::WriterBuilder::new().has_headers(self.rows == 0).from_writer(&mut self.buf);
+                writer.serialize(row).map_err(error::ErrorInternalServerError)?;
+                writer.flush()?;
+            }
+            ExportFormat::NdJson => {
+                serde_json::to_writer(&mut self.buf, row)?;
+                self.buf.push(b'\n');
+            }
+            ExportFormat::JsonArray => {
+                self.buf.push(if self.rows == 0 { b'[' } else { b',' });
+                serde_json::to_writer(&mut self.buf, row)?;
+            }
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// 缓冲区达到块大小时取出一块
+    fn take_chunk(&mut self) -> Option<Bytes> {
+        if self.buf.len() >= CHUNK_SIZE {
+            Some(Bytes::from(std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE))))
+        } else {
+            None
+        }
+    }
+
+    /// 所有数据都编码完成后取出剩余的部分
+    fn finish(mut self) -> Bytes {
+        if let ExportFormat::JsonArray = self.format {
+            if self.rows == 0 {
+                self.buf.push(b'[');
+            }
+            self.buf.push(b']');
+        }
+        Bytes::from(self.buf)
+    }
+}
+
+fn streaming_response<S>(format: ExportFormat, name: &str, body: S) -> HttpResponse
+    where
+        S: Stream<Item = Result<Bytes, Error>> + Unpin + 'static,
+{
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .set_header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", name, format.extension()))
+        .streaming(body)
+}
+
+/// 在阻塞线程池中运行阻塞迭代器, 通过有界 channel 实现背压
+fn export_iter<I, T>(format: ExportFormat, name: &str, rows: I) -> HttpResponse
+    where
+        I: Iterator<Item = T> + Send + 'static,
+        T: Serialize,
+{
+    // actix 的 Error 不能跨线程发送, 先转换成字符串
+    let (mut tx, rx) = mpsc::channel::<Result<Bytes, String>>(MAX_PENDING_CHUNKS);
+    let export_name = name.to_string();
+
+    let producer = web::block(move || {
+        let mut encoder = RowEncoder::new(format);
+        for row in rows {
+            if let Err(err) = encoder.encode(&row) {
+                let _ = futures::executor::block_on(tx.send(Err(err.to_string())));
+                return Ok(());
+            }
+            if let Some(chunk) = encoder.take_chunk() {
+                // channel 满时阻塞在这里; 客户端断开后 receiver 被 drop, send 返回错误
+                if futures::executor::block_on(tx.send(Ok(chunk))).is_err() {
+                    log::info!("export {} cancelled by client after {} rows", export_name, encoder.rows);
+                    return Ok(());
+                }
+            }
+        }
+        let rows = encoder.rows;
+        if futures::executor::block_on(tx.send(Ok(encoder.finish()))).is_ok() {
+            log::info!("export {} finished, {} rows", export_name, rows);
+        }
+        Ok::<_, ()>(())
+    });
+    // 不需要等待生产者的结果, 数据通过 channel 发送
+    actix_web::rt::spawn(async move {
+        let _ = producer.await;
+    });
+
+    streaming_response(format, name, rx.map(|chunk| chunk.map_err(error::ErrorInternalServerError)))
+}
+
+/// 按需从异步 Stream 中拉取数据, 攒够一块再输出
+fn export_stream<S, T>(format: ExportFormat, name: &str, rows: S) -> HttpResponse
+    where
+        S: Stream<Item = T> + Unpin + 'static,
+        T: Serialize,
+{
+    // 状态: (数据流, 编码器), 编码器为 None 表示已经输出完毕
+    let body = futures::stream::unfold((rows, Some(RowEncoder::new(format))), |(mut rows, encoder)| async move {
+        let mut encoder = encoder?;
+        loop {
+            match rows.next().await {
+                Some(row) => {
+                    if let Err(err) = encoder.encode(&row) {
+                        return Some((Err(err), (rows, None)));
+                    }
+                    if let Some(chunk) = encoder.take_chunk() {
+                        return Some((Ok(chunk), (rows, Some(encoder))));
+                    }
+                }
+                None => return Some((Ok(encoder.finish()), (rows, None))),
+            }
+        }
+    });
+
+    streaming_response(format, name, Box::pin(body))
+}
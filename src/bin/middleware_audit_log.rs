@@ -0,0 +1,586 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, dev::BodySize, dev::MessageBody,
+                dev::ResponseBody, error::PayloadError, Error, HttpMessage, HttpServer, App, HttpResponse, web, error, middleware,
+                http::header, http::HeaderMap, http::Method};
+use actix_service::{Service, Transform};
+use bytes::{Bytes, BytesMut};
+use futures::future::{ok, Ready};
+use futures::{Future, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use env_logger::Env;
+
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+/// ## 审计日志 (Audit Log)
+/// 出于合规要求, 每一个修改类的调用(默认 POST/PUT/PATCH/DELETE)都需要留下记录. `AuditLog` 中间件记录请求方法, 路径,
+/// 调用者身份, 状态码, 耗时, 请求头以及截断后的请求与响应 body:
+/// * 请求 body 在处理函数读取的同时被复制一份, 响应 body 在发送给客户端的同时被复制一份, 不会改变流式传输的行为
+/// * 只保留 body 的前 `max_body` 个字节, 处理函数没有读完的请求 body 也只记录读到的部分
+/// * `redact_headers()` 配置的请求头(默认 Authorization, Cookie)以及 json body 中 `redact_fields()` 配置的字段
+///   (任意层级)会被替换成 `[REDACTED]`, 表单这类 `key=value&..` 形式的 body 按字段名同样处理
+/// * json body 被截断或者无法解析时无法可靠地脱敏, 只记录它的大小
+///
+/// 响应 body 发送完毕或者客户端断开之后, 记录以 json lines 的形式交给一个单独的线程追加写入 `AUDIT_LOG` 指定的文件
+/// (默认 `audit.log`), 文件超过 `AUDIT_LOG_MAX_BYTES`(默认 10MB)后轮转为 `audit.log.1`, `audit.log.2` ..., 最多保留5个.
+///
+/// ```shell
+/// curl -u alice:secret -d 'hello' http://127.0.0.1:8080/echo
+/// curl -H "Content-Type: application/json" -d '{"name":"dsl","number":1,"password":"p@ss"}' http://127.0.0.1:8080/echo
+/// curl -d '{"name":"dsl","number":1}' http://127.0.0.1:8080/manual
+/// curl -H "Content-Type: application/json" -d '{"name":"dsl","number":1}' http://127.0.0.1:8080/json
+/// tail -n 4 audit.log
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let path = std::env::var("AUDIT_LOG").unwrap_or_else(|_| "audit.log".to_string());
+    let max_bytes = std::env::var("AUDIT_LOG_MAX_BYTES").ok().and_then(|v| v.parse().ok()).unwrap_or(10 * 1024 * 1024);
+    let sink = Arc::new(AuditSink::open(path, max_bytes, 5)?);
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap(AuditLog::new(sink.clone())
+                .max_body(1024)
+                .redact_fields(&["password", "token", "secret"])
+                .redact_headers(&["authorization", "cookie", "x-api-key"])
+                .identity(basic_auth_user))
+            .wrap(middleware::Logger::default())
+            .route("/echo", web::post().to(echo))
+            .route("/manual", web::post().to(index_manual))
+            .route("/json", web::post().to(index_json))
+            .route("/hello", web::get().to(|| async { "not audited" }))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+async fn echo(req_body: String) -> HttpResponse {
+    HttpResponse::Ok().body(req_body)
+}
+
+#[derive(Deserialize, Serialize)]
+struct MyObj {
+    name: String,
+    number: i32,
+}
+
+const MAX_SIZE: usize = 262_144;
+
+/// 手动读取 payload, 审计日志中记录的是处理函数实际读到的内容
+async fn index_manual(mut payload: web::Payload) -> Result<HttpResponse, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) > MAX_SIZE {
+            return Err(error::ErrorBadRequest("overflow"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let obj = serde_json::from_slice::<MyObj>(&body)?;
+    Ok(HttpResponse::Ok().json(obj))
+}
+
+async fn index_json(obj: web::Json<MyObj>) -> HttpResponse {
+    HttpResponse::Ok().json(obj.into_inner())
+}
+
+/// 调用者身份取 Basic 认证中的用户名, 实际项目中一般由认证中间件放到 request extensions 中
+fn basic_auth_user(req: &ServiceRequest) -> Option<String> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded.trim()).ok()?).ok()?;
+    decoded.split(':').next().map(str::to_string)
+}
+
+const REDACTED: &str = "[REDACTED]";
+
+/// 审计中间件的配置
+#[derive(Clone)]
+struct AuditConfig {
+    sink: Arc<AuditSink>,
+    methods: Vec<Method>,
+    max_body: usize,
+    redact_fields: Vec<String>,
+    redact_headers: Vec<String>,
+    identity: fn(&ServiceRequest) -> Option<String>,
+}
+
+/// 审计日志中间件, 每个 worker 各自创建, 共享同一个 `AuditSink`
+pub struct AuditLog {
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn new(sink: Arc<AuditSink>) -> Self {
+        AuditLog {
+            config: AuditConfig {
+                sink,
+                methods: vec![Method::POST, Method::PUT, Method::PATCH, Method::DELETE],
+                max_body: 4096,
+                redact_fields: vec!["password".to_string()],
+                redact_headers: vec!["authorization".to_string(), "cookie".to_string(), "proxy-authorization".to_string()],
+                identity: |_| None,
+            },
+        }
+    }
+
+    /// 需要审计的请求方法
+    pub fn methods(mut self, methods: &[Method]) -> Self {
+        self.config.methods = methods.to_vec();
+        self
+    }
+
+    /// 请求与响应 body 各自最多记录的字节数, 默认 4096
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.config.max_body = bytes;
+        self
+    }
+
+    /// json 与表单 body 中需要脱敏的字段名, 不区分大小写
+    pub fn redact_fields(mut self, fields: &[&str]) -> Self {
+        self.config.redact_fields = fields.iter().map(|f| f.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// 需要脱敏的请求头
+    pub fn redact_headers(mut self, headers: &[&str]) -> Self {
+        self.config.redact_headers = headers.iter().map(|h| h.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// 从请求中取出调用者身份
+    pub fn identity(mut self, f: fn(&ServiceRequest) -> Option<String>) -> Self {
+        self.config.identity = f;
+        self
+    }
+}
+
+impl<S, B> Transform<S> for AuditLog
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<AuditBody<B>>;
+    type Error = Error;
+    type Transform = AuditLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AuditLogMiddleware { service, config: Rc::new(self.config.clone()) })
+    }
+}
+
+pub struct AuditLogMiddleware<S> {
+    service: S,
+    config: Rc<AuditConfig>,
+}
+
+impl<S, B> Service for AuditLogMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<AuditBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        if !self.config.methods.contains(req.method()) {
+            let fut = self.service.call(req);
+            return Box::pin(async move {
+                let res = fut.await?;
+                Ok(res.map_body(|_, body| ResponseBody::Body(AuditBody { body: Box::pin(body), capture: None, record: None })))
+            });
+        }
+
+        let config = self.config.clone();
+        let record = AuditRecord {
+            ts: time::OffsetDateTime::now_utc().format(time::Format::Rfc3339),
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            query: req.query_string().to_string(),
+            identity: (config.identity)(&req),
+            client_ip: req.connection_info().realip_remote_addr().map(str::to_string),
+            request_headers: redact_headers(req.headers(), &config.redact_headers),
+            status: 0,
+            duration_ms: 0,
+            request_body: Value::Null,
+            response_body: Value::Null,
+            completed: false,
+        };
+        let request_type = content_type(req.headers());
+
+        // 处理函数读取请求 body 的同时复制一份
+        let request_capture = Rc::new(RefCell::new(Capture::new(config.max_body)));
+        let payload = req.take_payload();
+        req.set_payload(Payload::Stream(Box::pin(TeeStream { payload, capture: request_capture.clone() })));
+
+        let start = Instant::now();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            let pending = PendingRecord {
+                record: AuditRecord { status: res.status().as_u16(), ..record },
+                config,
+                start,
+                request_capture,
+                request_type,
+                response_type: content_type(res.headers()),
+            };
+            let capture = Capture::new(pending.config.max_body);
+            Ok(res.map_body(move |_, body| ResponseBody::Body(AuditBody { body: Box::pin(body), capture: Some(capture), record: Some(pending) })))
+        })
+    }
+}
+
+/// 写入审计日志的一条记录
+#[derive(Serialize)]
+struct AuditRecord {
+    ts: String,
+    method: String,
+    path: String,
+    query: String,
+    identity: Option<String>,
+    client_ip: Option<String>,
+    request_headers: Map<String, Value>,
+    status: u16,
+    duration_ms: u64,
+    request_body: Value,
+    response_body: Value,
+    /// 响应 body 是否完整发送, 客户端中途断开时为 false
+    completed: bool,
+}
+
+/// 等待响应 body 发送完毕的记录
+struct PendingRecord {
+    record: AuditRecord,
+    config: Rc<AuditConfig>,
+    start: Instant,
+    request_capture: Rc<RefCell<Capture>>,
+    request_type: Option<String>,
+    response_type: Option<String>,
+}
+
+/// 复制下来的 body 的前一部分
+struct Capture {
+    limit: usize,
+    buf: BytesMut,
+    /// 流经的总字节数
+    total: usize,
+    /// 是否读到了 body 的结尾
+    complete: bool,
+}
+
+impl Capture {
+    fn new(limit: usize) -> Self {
+        Capture { limit, buf: BytesMut::new(), total: 0, complete: false }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.total += chunk.len();
+        let room = self.limit.saturating_sub(self.buf.len());
+        self.buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    }
+
+    fn truncated(&self) -> bool {
+        self.total > self.buf.len()
+    }
+
+    /// 转换成写入日志的形式, 能够解析的 json 与表单会被脱敏
+    fn render(&self, content_type: Option<&str>, fields: &[String]) -> Value {
+        if self.total == 0 {
+            return Value::Null;
+        }
+        let summary = |note: &str| serde_json::json!({ "bytes": self.total, "complete": self.complete, "omitted": note });
+
+        let content_type = content_type.unwrap_or("");
+        let looks_like_json = self.buf.iter().find(|b| !b.is_ascii_whitespace()).is_some_and(|b| *b == b'{' || *b == b'[');
+        if content_type.contains("json") || looks_like_json {
+            if self.truncated() || !self.complete {
+                return summary("incomplete json cannot be redacted");
+            }
+            return match serde_json::from_slice::<Value>(&self.buf) {
+                Ok(mut value) => {
+                    redact_json(&mut value, fields);
+                    value
+                }
+                Err(_) => summary("invalid json cannot be redacted"),
+            };
+        }
+
+        let text = redact_pairs(&String::from_utf8_lossy(&self.buf), fields);
+        serde_json::json!({ "bytes": self.total, "complete": self.complete, "truncated": self.truncated(), "text": text })
+    }
+}
+
+/// 表单以及 `key=value&..` 形式的文本按字段名脱敏, 回显表单内容的纯文本响应也能被处理.
+/// 字段名按 `application/x-www-form-urlencoded` 解码之后再比较, `pass%77ord` 与 `password` 是同一个字段;
+/// 不需要脱敏的部分保持原样
+fn redact_pairs(text: &str, fields: &[String]) -> String {
+    text.split('&')
+        .map(|pair| {
+            let key = serde_urlencoded::from_str::<Vec<(String, String)>>(pair).ok()
+                .and_then(|pairs| pairs.into_iter().next())
+                .map(|(key, _)| key.trim().to_ascii_lowercase());
+            match (pair.split_once('='), key) {
+                (Some((raw_key, _)), Some(key)) if fields.contains(&key) => format!("{}={}", raw_key, REDACTED),
+                _ => pair.to_string(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn content_type(headers: &HeaderMap) -> Option<String> {
+    headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(|v| v.to_ascii_lowercase())
+}
+
+fn redact_headers(headers: &HeaderMap, redact: &[String]) -> Map<String, Value> {
+    let mut map = Map::new();
+    for (name, value) in headers {
+        let value = if redact.iter().any(|r| r == name.as_str()) {
+            REDACTED.to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        // 同名的头合并成一个值
+        match map.get_mut(name.as_str()) {
+            Some(Value::String(existing)) => {
+                existing.push_str(", ");
+                existing.push_str(&value);
+            }
+            _ => {
+                map.insert(name.to_string(), Value::String(value));
+            }
+        }
+    }
+    map
+}
+
+/// 递归地替换需要脱敏的字段
+fn redact_json(value: &mut Value, fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if fields.contains(&key.to_ascii_lowercase()) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact_json(value, fields);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(|item| redact_json(item, fields)),
+        _ => {}
+    }
+}
+
+/// 把流经的请求 body 复制到 `Capture` 中
+struct TeeStream {
+    payload: Payload,
+    capture: Rc<RefCell<Capture>>,
+}
+
+impl Stream for TeeStream {
+    type Item = Result<Bytes, PayloadError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let item = Pin::new(&mut self.payload).poll_next(cx);
+        match &item {
+            Poll::Ready(Some(Ok(chunk))) => self.capture.borrow_mut().push(chunk),
+            Poll::Ready(None) => self.capture.borrow_mut().complete = true,
+            _ => {}
+        }
+        item
+    }
+}
+
+/// 复制流经的响应 body, 发送完毕或者客户端断开后 body 被 drop, 此时写入审计记录
+pub struct AuditBody<B> {
+    body: Pin<Box<ResponseBody<B>>>,
+    capture: Option<Capture>,
+    record: Option<PendingRecord>,
+}
+
+impl<B: MessageBody> MessageBody for AuditBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
+        let this = self.as_mut().get_mut();
+        let item = MessageBody::poll_next(this.body.as_mut(), cx);
+        if let Some(capture) = &mut this.capture {
+            match &item {
+                Poll::Ready(Some(Ok(chunk))) => capture.push(chunk),
+                Poll::Ready(None) => capture.complete = true,
+                _ => {}
+            }
+        }
+        item
+    }
+}
+
+impl<B> Drop for AuditBody<B> {
+    fn drop(&mut self) {
+        let (pending, capture) = match (self.record.take(), self.capture.take()) {
+            (Some(pending), Some(capture)) => (pending, capture),
+            _ => return,
+        };
+        let config = &pending.config;
+        let record = AuditRecord {
+            duration_ms: pending.start.elapsed().as_millis() as u64,
+            request_body: pending.request_capture.borrow().render(pending.request_type.as_deref(), &config.redact_fields),
+            response_body: capture.render(pending.response_type.as_deref(), &config.redact_fields),
+            completed: capture.complete,
+            ..pending.record
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => config.sink.write(line),
+            Err(e) => log::error!("failed to serialize audit record: {}", e),
+        }
+    }
+}
+
+/// 追加写入的 json lines 文件, 由一个单独的线程负责写入与轮转, 不会阻塞 worker
+pub struct AuditSink {
+    tx: Mutex<mpsc::Sender<String>>,
+}
+
+impl AuditSink {
+    /// 文件超过 `max_bytes` 后轮转, 最多保留 `keep` 个旧文件
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let path = path.into();
+        // 在启动时打开文件, 路径有问题可以立即发现
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let (tx, rx) = mpsc::channel::<String>();
+
+        std::thread::spawn(move || {
+            let mut writer = RotatingFile { path, file, size, max_bytes, keep };
+            for line in rx {
+                if let Err(e) = writer.append(&line) {
+                    log::error!("failed to write audit record to {}: {}", writer.path.display(), e);
+                }
+            }
+        });
+        Ok(AuditSink { tx: Mutex::new(tx) })
+    }
+
+    fn write(&self, line: String) {
+        if self.tx.lock().unwrap().send(line).is_err() {
+            log::error!("audit writer thread has stopped");
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn append(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        // 一次写入一整行, 避免出现半行记录
+        let mut buf = Vec::with_capacity(len as usize);
+        buf.extend_from_slice(line.as_bytes());
+        buf.push(b'\n');
+        self.file.write_all(&buf)?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// audit.log.{keep-1} -> audit.log.{keep}, ..., audit.log -> audit.log.1, 超出 keep 的文件被删除
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            let _ = std::fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let from = rotated(n);
+                if from.exists() {
+                    std::fs::rename(&from, rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        log::info!("rotated audit log {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    fn fields() -> Vec<String> {
+        vec!["password".to_string(), "token".to_string()]
+    }
+
+    #[test]
+    fn redacts_form_fields_after_decoding_names() {
+        assert_eq!(redact_pairs("name=dsl&password=p%40ss", &fields()), "name=dsl&password=[REDACTED]");
+        assert_eq!(redact_pairs("pass%77ord=x&Token=y&a=password", &fields()), "pass%77ord=[REDACTED]&Token=[REDACTED]&a=password");
+        assert_eq!(redact_pairs("password+=x", &fields()), "password+=[REDACTED]");
+        // 不是表单的文本保持原样
+        assert_eq!(redact_pairs("hello world", &fields()), "hello world");
+        assert_eq!(redact_pairs("password", &fields()), "password");
+    }
+
+    #[test]
+    fn redacts_json_fields_at_any_depth() {
+        let mut value = serde_json::json!({
+            "name": "dsl",
+            "Password": "p@ss",
+            "items": [{ "token": "t", "id": 1 }],
+            "nested": { "token": { "value": "t" } },
+        });
+        redact_json(&mut value, &fields());
+        assert_eq!(value, serde_json::json!({
+            "name": "dsl",
+            "Password": REDACTED,
+            "items": [{ "token": REDACTED, "id": 1 }],
+            "nested": { "token": REDACTED },
+        }));
+    }
+
+    #[test]
+    fn redacts_configured_headers_and_merges_repeated_ones() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic YWxpY2U6c2VjcmV0"));
+        headers.append(header::ACCEPT, HeaderValue::from_static("text/html"));
+        headers.append(header::ACCEPT, HeaderValue::from_static("text/plain"));
+        let map = redact_headers(&headers, &["authorization".to_string()]);
+        assert_eq!(map["authorization"], REDACTED);
+        let accept = map["accept"].as_str().unwrap();
+        assert!(accept == "text/html, text/plain" || accept == "text/plain, text/html");
+    }
+}
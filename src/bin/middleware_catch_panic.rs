@@ -0,0 +1,235 @@
+use actix_web::{dev::Factory, dev::ServiceResponse, Either, FromRequest, HttpServer, App, HttpRequest, HttpResponse,
+                Responder, web, http, middleware::Logger, middleware::errhandlers::ErrorHandlerResponse,
+                middleware::errhandlers::ErrorHandlers};
+use actix_web3_doc::request_id::{request_id, RequestId, RequestIdValue};
+use env_logger::Env;
+use futures::future::{ready, LocalBoxFuture};
+use futures::{Future, FutureExt};
+use serde::Deserialize;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+
+/// ## 捕获处理函数中的 panic
+/// `query`, `get_matches` 等处理函数中的 `unwrap()`, 锁中毒以及 `assert_eq!` 都可能 panic, 默认情况下 panic 会让
+/// worker 线程退出, 客户端只看到连接被断开. `catch_panic()` 包装处理函数, 捕获其中的 panic(不包括 `panic = "abort"`),
+/// 返回一个普通的 500 响应, 所以 App 上注册的 `ErrorHandlers` 仍然可以生成错误页面:
+/// * 日志中输出 panic 的信息以及 `RequestId` 分配的请求 ID(`X-Request-Id`), 方便与客户端看到的错误页面对应起来
+/// * 按路由模式统计 panic 的次数, 通过 `/metrics`(Prometheus 文本格式)查看, 统计保存在 `web::Data<PanicMetrics>` 中
+///
+/// 这里没有使用中间件: 中间件不能提前保留一份 `HttpRequest` 的 clone(存在 clone 时 `ServiceRequest::from_parts` 会失败,
+/// 路由匹配与提取器也需要独占请求), 处理函数 panic 之后原来的请求又已经随着处理函数一起被丢弃, 只能重新构造一个没有
+/// app data 与路由信息的请求. 在处理函数这一层, 参数已经提取完毕, 可以放心地持有 `HttpRequest`.
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/users/tom/abc
+/// curl -i -H "X-Request-Id: abc-123" http://127.0.0.1:8080/matches/300/a
+/// curl -i "http://127.0.0.1:8080/counter?panic=true"
+/// curl -i http://127.0.0.1:8080/counter   # 锁已经中毒, 之后每次都会 panic
+/// curl -i "http://127.0.0.1:8080/external/a%20b"
+/// curl http://127.0.0.1:8080/metrics
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    // 在所有 worker 之间共享
+    let metrics = web::Data::new(PanicMetrics::default());
+    let counter = web::Data::new(AppStateWithCounter { counter: Mutex::new(0) });
+
+    HttpServer::new(move || {
+        App::new()
+            .wrap(ErrorHandlers::new().handler(http::StatusCode::INTERNAL_SERVER_ERROR, render_500))
+            // 在 ErrorHandlers 的外层, 错误页面需要读取请求 ID
+            .wrap(RequestId)
+            // actix-web 3 中 `%a` 是 socket 的对端地址, `%{r}a` 才会读取客户端可以伪造的 `Forwarded`/`X-Forwarded-For`
+            .wrap(Logger::new("%a \"%r\" %s %{x-request-id}o"))
+            .app_data(counter.clone())
+            .app_data(metrics.clone())
+            .external_resource("youtube", "https://youtube.com/watch/{video_id}")
+            .configure(routes)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+fn routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/users/{username}/{age}", web::get().to(catch_panic(query)))
+        .route("/matches/{v1}/{v2}", web::get().to(catch_panic(get_matches)))
+        .route("/counter", web::get().to(catch_panic(mutable_counter)))
+        .route("/external/{video_id}", web::get().to(catch_panic(external_resource)))
+        .route("/metrics", web::get().to(metrics_handler));
+}
+
+async fn query(req: HttpRequest) -> String {
+    let age: u32 = req.match_info().get("age").unwrap().parse().unwrap();
+    let username: String = req.match_info().query("username").parse().unwrap();
+    format!("Hello {} your age:{}", username, age)
+}
+
+async fn get_matches(req: HttpRequest) -> String {
+    let v1: u8 = req.match_info().get("v1").unwrap().parse().unwrap();
+    let v2: String = req.match_info().query("v2").parse().unwrap();
+    format!("v1: {} v2: {}", v1, v2)
+}
+
+struct AppStateWithCounter {
+    counter: Mutex<i32>,
+}
+
+#[derive(Deserialize)]
+struct CounterQuery {
+    #[serde(default)]
+    panic: bool,
+}
+
+async fn mutable_counter(data: web::Data<AppStateWithCounter>, query: web::Query<CounterQuery>) -> String {
+    let mut counter = data.counter.lock().unwrap();
+    *counter += 1;
+    // 持有锁的时候 panic 会让锁中毒, 之后的 lock().unwrap() 都会 panic
+    if query.panic {
+        panic!("counter update failed at {}", counter);
+    }
+    format!("Request number : {}", counter)
+}
+
+async fn external_resource(req: HttpRequest) -> HttpResponse {
+    let video_id = req.match_info().query("video_id");
+    let url = req.url_for("youtube", [video_id]).unwrap();
+    // video_id 中有需要转义的字符时不相等
+    assert_eq!(url.as_str(), format!("https://youtube.com/watch/{}", video_id));
+    HttpResponse::Ok().content_type("text/plain").body(url.into_string())
+}
+
+async fn metrics_handler(metrics: web::Data<PanicMetrics>) -> HttpResponse {
+    let mut out = String::from("# TYPE http_panics_total counter\n");
+    for (route, count) in metrics.snapshot() {
+        let _ = writeln!(out, "http_panics_total{{route=\"{}\"}} {}", route.replace('\\', "\\\\").replace('"', "\\\""), count);
+    }
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(out)
+}
+
+/// `ErrorHandlers` 生成的 500 页面, panic 与处理函数返回的错误走同一个流程
+fn render_500<B>(mut res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+    // 请求 ID 只包含字母, 数字, `-` 与 `_`, 不需要转义
+    let request_id = request_id(res.request());
+    let body = format!(
+        "<!doctype html>\n<html><body><h1>500 Internal Server Error</h1><p><small>request id: {}</small></p></body></html>\n",
+        request_id.as_ref().map_or("", RequestIdValue::as_str),
+    );
+    res.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static("text/html; charset=utf-8"));
+    res.headers_mut().remove(http::header::CONTENT_LENGTH);
+    Ok(ErrorHandlerResponse::Response(res.map_body(|_, _| {
+        actix_web::dev::ResponseBody::Other(actix_web::dev::Body::from(body))
+    })))
+}
+
+/// 按路由模式统计的 panic 次数
+#[derive(Clone, Default)]
+pub struct PanicMetrics {
+    panics: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl PanicMetrics {
+    fn record(&self, route: &str) {
+        // 统计本身不应该因为锁中毒而失败
+        let mut panics = self.panics.lock().unwrap_or_else(|e| e.into_inner());
+        *panics.entry(route.to_string()).or_insert(0) += 1;
+    }
+
+    /// 按路由排序
+    pub fn snapshot(&self) -> Vec<(String, u64)> {
+        let panics = self.panics.lock().unwrap_or_else(|e| e.into_inner());
+        let mut items: Vec<_> = panics.iter().map(|(route, count)| (route.clone(), *count)).collect();
+        items.sort();
+        items
+    }
+}
+
+/// 包装一个处理函数, 把其中的 panic 转换为 500 响应. 用法与普通的处理函数相同: `web::get().to(catch_panic(query))`.
+///
+/// 包装之后的处理函数额外提取一个 `HttpRequest`, panic 之后仍然可以用它记录日志与统计, 返回的 500 响应也使用这个请求,
+/// 之后的 `ErrorHandlers` 与 `Logger` 看到的都是真实的请求. 提取参数时的 panic 不会被捕获.
+pub fn catch_panic<F, T, R, O>(handler: F) -> impl Fn(HttpRequest, T) -> LocalBoxFuture<'static, Either<O, HttpResponse>> + Clone + 'static
+    where
+        F: Factory<T, R, O>,
+        T: FromRequest + 'static,
+        R: Future<Output = O> + 'static,
+        O: Responder + 'static,
+{
+    move |req: HttpRequest, params: T| {
+        // 处理函数同步 panic 时也需要捕获
+        let fut = match std::panic::catch_unwind(AssertUnwindSafe(|| handler.call(params))) {
+            Ok(fut) => fut,
+            Err(panic) => return Box::pin(ready(Either::B(panic_response(&req, panic)))) as LocalBoxFuture<'static, _>,
+        };
+        Box::pin(async move {
+            match AssertUnwindSafe(fut).catch_unwind().await {
+                Ok(res) => Either::A(res),
+                Err(panic) => Either::B(panic_response(&req, panic)),
+            }
+        })
+    }
+}
+
+/// 记录日志与统计, 返回不包含 panic 信息的 500 响应
+fn panic_response(req: &HttpRequest, panic: Box<dyn Any + Send>) -> HttpResponse {
+    let request_id = request_id(req);
+    let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+    log::error!("request {} {} {} (route {}) panicked: {}",
+                request_id.as_ref().map_or("-", RequestIdValue::as_str), req.method(), req.uri(), route, panic_message(&*panic));
+    if let Some(metrics) = req.app_data::<web::Data<PanicMetrics>>() {
+        metrics.record(&route);
+    }
+    HttpResponse::InternalServerError().finish()
+}
+
+/// `panic!` 的参数是字符串字面量时为 `&str`, 带有格式化参数时为 `String`
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, http::StatusCode};
+
+    #[test]
+    fn panicking_handler_yields_500_through_error_handlers() {
+        actix_web::rt::System::new("catch_panic").block_on(async {
+            let metrics = web::Data::new(PanicMetrics::default());
+            let counter = web::Data::new(AppStateWithCounter { counter: Mutex::new(0) });
+            let mut app = test::init_service(App::new()
+                .wrap(ErrorHandlers::new().handler(http::StatusCode::INTERNAL_SERVER_ERROR, render_500))
+                .wrap(RequestId)
+                .app_data(counter)
+                .app_data(metrics.clone())
+                .configure(routes)).await;
+
+            let req = test::TestRequest::get().uri("/users/tom/abc").header("x-request-id", "abc-123").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+            let body = test::read_body(res).await;
+            assert!(std::str::from_utf8(&body).unwrap().contains("request id: abc-123"));
+
+            let req = test::TestRequest::get().uri("/counter?panic=true").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            let req = test::TestRequest::get().uri("/users/tom/18").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+
+            assert_eq!(metrics.snapshot(), vec![
+                ("/counter".to_string(), 1),
+                ("/users/{username}/{age}".to_string(), 1),
+            ]);
+        });
+    }
+}
@@ -0,0 +1,469 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, HttpServer, App, HttpResponse, web,
+                http::header, http::HeaderName, http::HeaderValue, http::Method};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Ready};
+use futures::{Future, StreamExt};
+use serde::{Deserialize, Serialize};
+use env_logger::Env;
+
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+/// ## 跨域资源共享 (CORS)
+/// `extractors_json.rs` 与 `requests.rs` 中的 json 接口无法被其它源(origin)的浏览器应用调用.
+/// 这里实现一个可以按 scope 或 resource 配置的 CORS 中间件:
+/// * 允许的源, 支持通配符: `https://*.example.com` 匹配所有子域名, `http://localhost:*` 匹配任意端口
+/// * 允许的方法, 请求头, 以及允许浏览器读取的响应头(`Access-Control-Expose-Headers`)
+/// * 是否允许携带凭证(cookie), 以及预检结果的缓存时间(`Access-Control-Max-Age`)
+///
+/// 中间件直接响应预检(preflight)的 `OPTIONS` 请求, 不会到达处理函数. 不允许的源, 方法或请求头返回 403,
+/// 并在日志中说明原因. 因为响应内容取决于 `Origin`, 所有响应都带有 `Vary: Origin`.
+///
+/// 浏览器在同源的 `POST` 请求中同样会带上 `Origin`, `Origin` 与请求的 scheme 和 `Host` 相同时按同源请求处理, 不做检查.
+///
+/// 注意: scope 上的 guard 对预检请求同样生效, 预检请求不会带有 `Content-Type: application/json`,
+/// 所以这里的 `/json` scope 去掉了 `extractors_json.rs` 中的 Content-Type guard.
+///
+/// ```shell
+/// # 预检请求
+/// curl -i -X OPTIONS -H "Origin: https://app.example.com" -H "Access-Control-Request-Method: POST" \
+///      -H "Access-Control-Request-Headers: content-type" http://127.0.0.1:8080/json
+/// # 实际请求
+/// curl -i -H "Origin: http://localhost:3000" -H "Content-Type: application/json" -d '{"name":"dsl","number":1}' \
+///      http://127.0.0.1:8080/json
+/// # 不允许的源
+/// curl -i -H "Origin: https://evil.com" http://127.0.0.1:8080/json/getInfo
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    HttpServer::new(|| {
+        // 前端应用需要携带 cookie
+        let app_cors = Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_origin("https://*.example.com")
+            .allow_origin("http://localhost:*")
+            .allow_methods(&[Method::GET, Method::POST])
+            .allow_headers(&[header::CONTENT_TYPE, header::AUTHORIZATION])
+            .expose_headers(&["x-request-id"])
+            .allow_credentials()
+            .max_age(3600);
+        // 公开的接口允许任意源, 但不能携带凭证
+        let public_cors = Cors::new()
+            .allow_any_origin()
+            .allow_methods(&[Method::POST])
+            .allow_headers(&[header::CONTENT_TYPE]);
+
+        App::new()
+            .service(web::scope("/json")
+                .wrap(app_cors)
+                .route("", web::post().to(echo))
+                .route("/getInfo", web::get().to(get_info)))
+            .service(web::resource("/manual")
+                .wrap(public_cors)
+                .route(web::post().to(index_manual)))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+#[derive(Deserialize)]
+struct Info {
+    username: String,
+}
+
+async fn get_info(info: web::Query<Info>) -> HttpResponse {
+    HttpResponse::Ok().set_header("x-request-id", "42").body(format!("Welcome {}!", info.username))
+}
+
+#[derive(Deserialize, Serialize)]
+struct MyObj {
+    name: String,
+    number: i32,
+}
+
+async fn echo(obj: web::Json<MyObj>) -> HttpResponse {
+    HttpResponse::Ok().json(obj.into_inner())
+}
+
+async fn index_manual(mut payload: web::Payload) -> Result<HttpResponse, Error> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+    }
+    let obj = serde_json::from_slice::<MyObj>(&body)?;
+    Ok(HttpResponse::Ok().json(obj))
+}
+
+/// 允许的源
+#[derive(Clone)]
+enum OriginPattern {
+    Any,
+    /// 完全相同才匹配
+    Exact(String),
+    /// 按 `*` 拆开的各个部分, `*` 匹配至少一个主机名或端口中的字符
+    Wildcard(Vec<String>),
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Self {
+        match pattern {
+            "*" => OriginPattern::Any,
+            p if p.contains('*') => OriginPattern::Wildcard(p.to_ascii_lowercase().split('*').map(String::from).collect()),
+            p => OriginPattern::Exact(p.to_ascii_lowercase()),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => exact.eq_ignore_ascii_case(origin),
+            OriginPattern::Wildcard(parts) => wildcard_match(parts, &origin.to_ascii_lowercase()),
+        }
+    }
+}
+
+/// `*` 只能匹配主机名与端口中的字符, 不能跨过 `/` 或 `:`, 这样 `https://*.example.com`
+/// 不会匹配 `https://evil.com/.example.com` 之类的源
+fn wildcard_match(parts: &[String], origin: &str) -> bool {
+    let (first, rest) = match parts.split_first() {
+        Some(split) => split,
+        None => return origin.is_empty(),
+    };
+    let mut remaining = match origin.strip_prefix(first.as_str()) {
+        Some(remaining) => remaining,
+        None => return false,
+    };
+    for (i, part) in rest.iter().enumerate() {
+        let is_last = i == rest.len() - 1;
+        // 找到满足条件的最短匹配, 最后一部分必须匹配到结尾
+        let position = (1..=remaining.len()).find(|&n| {
+            let wild = &remaining[..n];
+            let tail = &remaining[n..];
+            wild.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+                && if is_last { tail == part } else { tail.starts_with(part.as_str()) }
+        });
+        match position {
+            Some(n) => remaining = &remaining[n + part.len()..],
+            None => return false,
+        }
+    }
+    remaining.is_empty()
+}
+
+/// CORS 中间件的配置
+#[derive(Clone, Default)]
+pub struct Cors {
+    origins: Vec<OriginPattern>,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    expose_headers: Vec<HeaderName>,
+    credentials: bool,
+    max_age: Option<u32>,
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    /// 允许的源, 可以包含通配符 `*`
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        self.origins.push(OriginPattern::parse(origin));
+        self
+    }
+
+    pub fn allow_any_origin(self) -> Self {
+        self.allow_origin("*")
+    }
+
+    pub fn allow_methods(mut self, methods: &[Method]) -> Self {
+        self.methods.extend_from_slice(methods);
+        self
+    }
+
+    pub fn allow_headers(mut self, headers: &[HeaderName]) -> Self {
+        self.headers.extend_from_slice(headers);
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers.extend(headers.iter().map(|h| HeaderName::from_bytes(h.as_bytes()).expect("invalid header name")));
+        self
+    }
+
+    /// 允许携带凭证. 此时 `Access-Control-Allow-Origin` 不能是 `*`, 会返回请求中的源
+    pub fn allow_credentials(mut self) -> Self {
+        self.credentials = true;
+        self
+    }
+
+    pub fn max_age(mut self, seconds: u32) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    fn origin_allowed(&self, origin: &str) -> bool {
+        self.origins.iter().any(|pattern| pattern.matches(origin))
+    }
+
+    /// `Access-Control-Allow-Origin` 的值
+    fn allow_origin_value(&self, origin: &HeaderValue) -> HeaderValue {
+        let any = self.origins.iter().any(|pattern| matches!(pattern, OriginPattern::Any));
+        if any && !self.credentials {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        }
+    }
+
+    fn join<T: AsRef<str>>(items: &[T]) -> HeaderValue {
+        let joined = items.iter().map(|i| i.as_ref()).collect::<Vec<_>>().join(", ");
+        HeaderValue::from_str(&joined).expect("header names and methods are valid header values")
+    }
+
+    /// 检查预检请求, 返回拒绝的原因
+    fn check_preflight(&self, req: &ServiceRequest) -> Result<(), String> {
+        let method = req.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| Method::from_bytes(v.as_bytes()).ok())
+            .ok_or_else(|| "invalid Access-Control-Request-Method".to_string())?;
+        if !self.methods.contains(&method) {
+            return Err(format!("method {} is not allowed", method));
+        }
+
+        let requested = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("");
+        for name in requested.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if !self.headers.iter().any(|allowed| allowed.as_str().eq_ignore_ascii_case(name)) {
+                return Err(format!("header {} is not allowed", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// 预检请求的响应
+    fn preflight_response(&self, origin: &HeaderValue) -> HttpResponse {
+        let mut res = HttpResponse::NoContent();
+        res.set_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, self.allow_origin_value(origin))
+            .set_header(header::ACCESS_CONTROL_ALLOW_METHODS, Cors::join(&self.methods))
+            .set_header(header::VARY, "Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+        if !self.headers.is_empty() {
+            res.set_header(header::ACCESS_CONTROL_ALLOW_HEADERS, Cors::join(&self.headers));
+        }
+        if self.credentials {
+            res.set_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+        if let Some(max_age) = self.max_age {
+            res.set_header(header::ACCESS_CONTROL_MAX_AGE, max_age.to_string());
+        }
+        res.finish()
+    }
+}
+
+/// `Origin` 是否与请求自己的 scheme 和 `Host` 相同
+fn same_origin(req: &ServiceRequest, origin: &HeaderValue) -> bool {
+    let host = match req.headers().get(header::HOST).and_then(|v| v.to_str().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    let scheme = req.connection_info().scheme().to_string();
+    match origin.to_str().ok().and_then(|origin| origin.split_once("://")) {
+        Some((origin_scheme, origin_host)) => origin_scheme.eq_ignore_ascii_case(&scheme) && origin_host.eq_ignore_ascii_case(host),
+        None => false,
+    }
+}
+
+impl<S, B> Transform<S> for Cors
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CorsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CorsMiddleware { service, cors: Rc::new(self.clone()) })
+    }
+}
+
+pub struct CorsMiddleware<S> {
+    service: S,
+    cors: Rc<Cors>,
+}
+
+impl<S, B> Service for CorsMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let origin = match req.headers().get(header::ORIGIN) {
+            Some(origin) if !same_origin(&req, origin) => origin.clone(),
+            // 不是跨域请求, 响应仍然取决于 Origin, 需要 Vary
+            _ => {
+                let fut = self.service.call(req);
+                return Box::pin(async move {
+                    let mut res = fut.await?;
+                    res.headers_mut().append(header::VARY, HeaderValue::from_static("Origin"));
+                    Ok(res)
+                });
+            }
+        };
+
+        let cors = self.cors.clone();
+        let origin_str = origin.to_str().unwrap_or("");
+        let preflight = req.method() == Method::OPTIONS && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+
+        let rejection = if !cors.origin_allowed(origin_str) {
+            Some("origin is not allowed".to_string())
+        } else if preflight {
+            cors.check_preflight(&req).err()
+        } else {
+            None
+        };
+        if let Some(reason) = rejection {
+            log::warn!("CORS rejected {} {} from origin {}: {}", req.method(), req.path(), origin_str, reason);
+            let res = HttpResponse::Forbidden()
+                .set_header(header::VARY, "Origin")
+                .body(format!("CORS request rejected: {}", reason));
+            return Box::pin(ok(req.into_response(res.into_body())));
+        }
+
+        if preflight {
+            let res = cors.preflight_response(&origin);
+            return Box::pin(ok(req.into_response(res.into_body())));
+        }
+
+        let fut = self.service.call(req);
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let headers = res.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, cors.allow_origin_value(&origin));
+            if cors.credentials {
+                headers.insert(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, HeaderValue::from_static("true"));
+            }
+            if !cors.expose_headers.is_empty() {
+                headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, Cors::join(&cors.expose_headers));
+            }
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    #[test]
+    fn matches_origin_patterns() {
+        let exact = OriginPattern::parse("https://app.example.com");
+        assert!(exact.matches("https://app.example.com"));
+        assert!(exact.matches("HTTPS://APP.example.com"));
+        assert!(!exact.matches("http://app.example.com"));
+
+        let subdomain = OriginPattern::parse("https://*.example.com");
+        assert!(subdomain.matches("https://api.example.com"));
+        assert!(subdomain.matches("https://a.b.example.com"));
+        assert!(!subdomain.matches("https://example.com"));
+        assert!(!subdomain.matches("https://example.com.evil.com"));
+        assert!(!subdomain.matches("https://evil.com/.example.com"));
+
+        let port = OriginPattern::parse("http://localhost:*");
+        assert!(port.matches("http://localhost:3000"));
+        assert!(!port.matches("http://localhost.evil.com:3000"));
+
+        assert!(OriginPattern::parse("*").matches("https://anything.test"));
+    }
+
+    macro_rules! app {
+        () => {
+            test::init_service(App::new().service(web::scope("/json")
+                .wrap(Cors::new()
+                    .allow_origin("https://*.example.com")
+                    .allow_methods(&[Method::GET, Method::POST])
+                    .allow_headers(&[header::CONTENT_TYPE])
+                    .allow_credentials()
+                    .max_age(600))
+                .route("", web::post().to(|| HttpResponse::Ok().body("posted")))))
+                .await
+        };
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> test::TestRequest {
+        test::TestRequest::with_uri("/json")
+            .method(Method::OPTIONS)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+    }
+
+    #[test]
+    fn answers_preflight_requests() {
+        actix_web::rt::System::new("cors").block_on(async {
+            let mut app = app!();
+            let res = test::call_service(&mut app, preflight("https://app.example.com", "POST", "Content-Type").to_request()).await;
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+            let headers = res.headers();
+            assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+            assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_METHODS).unwrap(), "GET, POST");
+            assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_HEADERS).unwrap(), "content-type");
+            assert_eq!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).unwrap(), "true");
+            assert_eq!(headers.get(header::ACCESS_CONTROL_MAX_AGE).unwrap(), "600");
+
+            for req in [
+                preflight("https://app.example.com", "DELETE", ""),
+                preflight("https://app.example.com", "POST", "content-type, x-secret"),
+                preflight("https://evil.com", "POST", ""),
+            ] {
+                let res = test::call_service(&mut app, req.to_request()).await;
+                assert_eq!(res.status(), StatusCode::FORBIDDEN);
+                assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+            }
+        });
+    }
+
+    #[test]
+    fn same_origin_requests_with_origin_are_allowed() {
+        actix_web::rt::System::new("cors").block_on(async {
+            let mut app = app!();
+            let post = |origin: &str| test::TestRequest::post().uri("/json")
+                .header(header::HOST, "127.0.0.1:8080")
+                .header(header::ORIGIN, origin)
+                .to_request();
+
+            let res = test::call_service(&mut app, post("http://127.0.0.1:8080")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+            assert_eq!(res.headers().get(header::VARY).unwrap(), "Origin");
+
+            // 端口或 scheme 不同都是跨域
+            let res = test::call_service(&mut app, post("http://127.0.0.1:9090")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = test::call_service(&mut app, post("https://127.0.0.1:8080")).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+            let res = test::call_service(&mut app, post("https://app.example.com")).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), "https://app.example.com");
+        });
+    }
+}
@@ -1,25 +1,237 @@
-use actix_web::{HttpServer, App, web, HttpResponse, dev, Result, http};
+use actix_web::{HttpServer, App, web, HttpResponse, HttpMessage, dev, error, Error, Result, http};
 use actix_web::middleware::errhandlers::{ErrorHandlerResponse, ErrorHandlers};
+use actix_web3_doc::request_id::{request_id, RequestId};
+use actix_web3_doc::routing::MethodResource;
+use env_logger::Env;
 
-/// 自己定义500错误响应
-fn render_500<B>(mut res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
-    res.response_mut().headers_mut().insert(
-        http::header::CONTENT_TYPE,
-        http::HeaderValue::from_static("Error"),
-    );
-    Ok(ErrorHandlerResponse::Response(res))
-}
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
 
+/// ## 错误页面
+/// `ErrorHandlers` 可以为某个状态码注册一个处理函数, 在响应返回给客户端之前修改它. `ErrorPages` 用模板为所有的
+/// 4xx/5xx 响应生成错误页面:
+/// * 启动时从目录(默认 `templates/errors`, 通过 `ERROR_PAGES_DIR` 修改)中加载模板, 按 `404.html`, `4xx.html`,
+///   `error.html` 的顺序查找, 都没有时使用内置的模板
+/// * 模板中可以使用 `{{status}}`, `{{reason}}`, `{{request_id}}` 与 `{{path}}`, 替换的值会做 html 转义
+/// * `Accept` 中优先级最高的类型是 json 的客户端(比如 `Accept: application/json`)得到 json 格式的错误
+///
+/// `RequestId` 中间件(`actix_web3_doc::request_id`)为每个请求分配一个 ID(客户端提供的 `X-Request-Id` 合法时直接使用), 写入响应头,
+/// 错误页面中显示同一个 ID, 方便根据用户反馈查找日志.
+///
+/// `App::route()` 注册的路由在方法不匹配时会继续匹配其他资源, 最终得到 404. 通过 `MethodResource`(`actix_web3_doc::routing`)注册的资源
+/// 会记录实现了哪些方法:
+/// * 方法不匹配时返回 405, `Allow` 头中列出支持的方法
+/// * 没有注册 `OPTIONS` 时自动回应 `OPTIONS` 请求
+/// * 没有注册 `HEAD` 时由 `GET` 处理, 响应体由 actix-web 去掉
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/missing
+/// curl -i -H "Accept: application/json" http://127.0.0.1:8080/error
+/// curl -i -H "X-Request-Id: abc-123" http://127.0.0.1:8080/unavailable
+/// curl -i -X OPTIONS http://127.0.0.1:8080/test
+/// curl -i -X DELETE http://127.0.0.1:8080/test
+/// curl -I http://127.0.0.1:8080/test
+/// ```
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(||{
-        App::new().wrap(
-            ErrorHandlers::new()
-                .handler(http::StatusCode::INTERNAL_SERVER_ERROR, render_500)
-        ).service(web::resource("/test")
-            .route(web::get().to(|| HttpResponse::Ok().body("success")))
-            .route(web::head().to(|| HttpResponse::MethodNotAllowed()))
-        )
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let dir = std::env::var("ERROR_PAGES_DIR").unwrap_or_else(|_| "templates/errors".to_string());
+    let pages = Arc::new(ErrorPages::load(&dir));
+
+    HttpServer::new(move ||{
+        App::new()
+            .wrap(pages.handlers())
+            // 最后注册的在最外层, 错误页面需要读取请求 ID
+            .wrap(RequestId)
+            // actix-web 3 中 `%a` 是 socket 的对端地址, `%{r}a` 才会读取客户端可以伪造的 `Forwarded`/`X-Forwarded-For`
+            .wrap(actix_web::middleware::Logger::new("%a \"%r\" %s %{x-request-id}o"))
+            .service(MethodResource::new("/test")
+                .get(web::to(|| HttpResponse::Ok().body("success")))
+                .post(web::to(|body: String| HttpResponse::Ok().body(body)))
+            )
+            .service(MethodResource::new("/error")
+                .get(web::to(|| async { Err::<HttpResponse, Error>(error::ErrorInternalServerError("database is down")) })))
+            .service(MethodResource::new("/unavailable").get(web::to(HttpResponse::ServiceUnavailable)))
+            .service(MethodResource::new("/teapot")
+                .get(web::to(|| HttpResponse::build(http::StatusCode::IM_A_TEAPOT).finish())))
     }).bind("127.0.0.1:8080")?
         .run().await
 }
+
+const DEFAULT_TEMPLATE: &str = r#"<!doctype html>
+<html>
+<head><meta charset="utf-8"><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p><small>request id: {{request_id}}</small></p>
+</body>
+</html>
+"#;
+
+/// 按状态码渲染的错误页面模板
+pub struct ErrorPages {
+    /// 文件名去掉 `.html` 之后的名字, 比如 `404`, `5xx`, `error`
+    templates: HashMap<String, String>,
+}
+
+impl ErrorPages {
+    /// 加载目录中所有的 `.html` 模板, 目录不存在时只使用内置的模板
+    pub fn load(dir: impl AsRef<Path>) -> Self {
+        let dir = dir.as_ref();
+        let mut templates = HashMap::new();
+        match std::fs::read_dir(dir) {
+            Ok(entries) => {
+                for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                    let name = match (path.file_stem().and_then(|s| s.to_str()), path.extension()) {
+                        (Some(name), Some(ext)) if ext == "html" => name.to_string(),
+                        _ => continue,
+                    };
+                    match std::fs::read_to_string(&path) {
+                        Ok(template) => {
+                            templates.insert(name, template);
+                        }
+                        Err(e) => log::warn!("failed to read error page {}: {}", path.display(), e),
+                    }
+                }
+                log::info!("loaded error pages from {}: {:?}", dir.display(), templates.keys().collect::<Vec<_>>());
+            }
+            Err(e) => log::warn!("error page directory {} is not readable, using the built-in page: {}", dir.display(), e),
+        }
+        ErrorPages { templates }
+    }
+
+    /// 为所有的 4xx 与 5xx 状态码注册同一个处理函数
+    pub fn handlers<B: 'static>(self: &Arc<Self>) -> ErrorHandlers<B> {
+        let mut handlers = ErrorHandlers::new();
+        for code in 400..600 {
+            let status = match http::StatusCode::from_u16(code) {
+                Ok(status) if status.canonical_reason().is_some() => status,
+                _ => continue,
+            };
+            let pages = self.clone();
+            handlers = handlers.handler(status, move |res| pages.render(res));
+        }
+        handlers
+    }
+
+    fn template(&self, status: http::StatusCode) -> &str {
+        let class = format!("{}xx", status.as_u16() / 100);
+        [status.as_str(), class.as_str(), "error"].iter()
+            .find_map(|name| self.templates.get(*name))
+            .map_or(DEFAULT_TEMPLATE, String::as_str)
+    }
+
+    fn render<B>(&self, mut res: dev::ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
+        let status = res.status();
+        let reason = status.canonical_reason().unwrap_or("");
+        let request_id = request_id(res.request()).map(|id| id.as_str().to_string()).unwrap_or_default();
+
+        let (content_type, body) = if prefers_json(res.request()) {
+            let body = serde_json::json!({ "status": status.as_u16(), "reason": reason, "request_id": request_id });
+            ("application/json", body.to_string())
+        } else {
+            let body = self.template(status)
+                .replace("{{status}}", status.as_str())
+                .replace("{{reason}}", &escape_html(reason))
+                .replace("{{request_id}}", &escape_html(&request_id))
+                .replace("{{path}}", &escape_html(res.request().path()));
+            ("text/html; charset=utf-8", body)
+        };
+
+        res.headers_mut().insert(http::header::CONTENT_TYPE, http::HeaderValue::from_static(content_type));
+        res.headers_mut().remove(http::header::CONTENT_LENGTH);
+        Ok(ErrorHandlerResponse::Response(res.map_body(|_, _| dev::ResponseBody::Other(dev::Body::from(body)))))
+    }
+}
+
+/// 按 q 值排序之后, 第一个 json 或 html 类型是 json 时返回 json
+fn prefers_json(req: &web::HttpRequest) -> bool {
+    let accept = match req.get_header::<http::header::Accept>() {
+        Some(accept) => accept,
+        None => return false,
+    };
+    let mut ranked: Vec<_> = accept.iter().collect();
+    // 稳定排序, q 值相同的保持原来的顺序
+    ranked.sort_by_key(|item| std::cmp::Reverse(item.quality));
+    ranked.iter()
+        .map(|item| &item.item)
+        .find_map(|mime| match (mime.type_().as_str(), mime.subtype().as_str(), mime.suffix().map(|s| s.as_str())) {
+            ("application", "json", _) | (_, _, Some("json")) => Some(true),
+            ("text", "html", _) | ("*", "*", _) | ("text", "*", _) => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn pages(templates: &[(&str, &str)]) -> ErrorPages {
+        ErrorPages { templates: templates.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect() }
+    }
+
+    fn render(pages: &ErrorPages, req: test::TestRequest, status: http::StatusCode) -> (String, String) {
+        let res = req.to_srv_response(HttpResponse::build(status).body("original"));
+        let mut res = match pages.render(res).unwrap() {
+            ErrorHandlerResponse::Response(res) => res,
+            ErrorHandlerResponse::Future(_) => panic!("render is synchronous"),
+        };
+        let content_type = res.headers().get(http::header::CONTENT_TYPE).unwrap().to_str().unwrap().to_string();
+        let body = match res.take_body() {
+            dev::ResponseBody::Other(dev::Body::Bytes(bytes)) => String::from_utf8(bytes.to_vec()).unwrap(),
+            _ => panic!("unexpected body"),
+        };
+        (content_type, body)
+    }
+
+    #[test]
+    fn prefers_json_by_quality() {
+        let accepts = |accept: &str| prefers_json(&test::TestRequest::default().header("Accept", accept).to_http_request());
+        assert!(accepts("application/json"));
+        assert!(accepts("application/problem+json"));
+        assert!(accepts("text/html;q=0.5, application/json"));
+        assert!(!accepts("text/html, application/json"));
+        assert!(!accepts("application/json;q=0.1, */*"));
+        assert!(!accepts("image/png"));
+        assert!(!prefers_json(&test::TestRequest::default().to_http_request()));
+    }
+
+    #[test]
+    fn picks_the_most_specific_template() {
+        let pages = pages(&[("404", "not found {{path}}"), ("4xx", "client {{status}}"), ("error", "error {{reason}}")]);
+        let req = || test::TestRequest::with_uri("/it's");
+        assert_eq!(render(&pages, req(), http::StatusCode::NOT_FOUND).1, "not found /it&#39;s");
+        assert_eq!(render(&pages, req(), http::StatusCode::GONE).1, "client 410");
+        assert_eq!(render(&pages, req(), http::StatusCode::BAD_GATEWAY), ("text/html; charset=utf-8".to_string(), "error Bad Gateway".to_string()));
+        let (_, body) = render(&ErrorPages { templates: HashMap::new() }, req(), http::StatusCode::BAD_GATEWAY);
+        assert!(body.contains("<h1>502 Bad Gateway</h1>"));
+    }
+
+    #[test]
+    fn renders_json_with_the_request_id() {
+        actix_web::rt::System::new("pages").block_on(async {
+            let pages = Arc::new(pages(&[]));
+            let mut app = test::init_service(App::new()
+                .wrap(pages.handlers())
+                .wrap(RequestId)
+                .route("/unavailable", web::get().to(HttpResponse::ServiceUnavailable))).await;
+            let req = test::TestRequest::with_uri("/unavailable")
+                .header("Accept", "application/json")
+                .header("X-Request-Id", "abc-123")
+                .to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(res.headers().get(http::header::CONTENT_TYPE).unwrap(), "application/json");
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body, serde_json::json!({ "status": 503, "reason": "Service Unavailable", "request_id": "abc-123" }));
+        });
+    }
+}
@@ -0,0 +1,591 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, Error, HttpServer, App, HttpRequest,
+                HttpResponse, HttpMessage, FromRequest, ResponseError, web, http::header, http::StatusCode};
+use actix_service::{Service, Transform};
+use futures::future::{ok, err, Ready};
+use futures::Future;
+use hmac::{Hmac, Mac, NewMac};
+use md5::Md5;
+use rand::Rng;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use env_logger::Env;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// ## htpasswd 认证 (Basic 与 Digest)
+/// `static_file.rs` 中的 `/getDir` 会列出目录中的所有文件, 内部工具也需要简单的密码保护.
+///
+/// `HtpasswdAuth` 中间件从 Apache 的密码文件中读取用户, 可以分别注册在不同的 scope 上:
+/// * `HtpasswdAuth::basic()` - HTTP Basic 认证, 读取 `htpasswd` 生成的文件, 支持 bcrypt(`$2y$`) 与 SHA(`{SHA}`)
+///   两种格式. bcrypt 的校验比较耗时, 在线程池中执行, 不会阻塞 worker
+/// * `HtpasswdAuth::digest()` - HTTP Digest 认证(RFC 7616, MD5, qop=auth). Digest 认证需要的是
+///   `MD5(user:realm:password)`, 无法从 bcrypt 或 SHA 哈希中得到, 所以读取的是 `htdigest` 生成的文件.
+///   nonce 中带有时间戳与签名, 过期后返回 `stale=true` 让客户端重新计算; 同一个 nonce 的 nc 必须递增, 防止重放
+/// * 启动时读取密码文件, 之后由 `watch` 定时在线程池中检查文件的修改时间, 文件变化后自动重新加载, 不需要重启服务.
+///   请求只读取内存中的内容, 不会在 worker 上做文件 IO. 文件格式错误时继续使用旧的内容
+/// * 用户不存在时同样会校验一个假的哈希, 响应时间不会暴露用户是否存在
+/// * 认证通过后处理函数可以通过 `AuthenticatedUser` 提取器得到用户名
+///
+/// 可以使用如下命令生成密码文件:
+/// ```shell
+/// htpasswd -cB htpasswd dsl                  # bcrypt
+/// htpasswd -s htpasswd admin                 # SHA
+/// htdigest -c htdigest "actix-web3 demo" dsl
+/// ```
+///
+/// 然后:
+/// ```shell
+/// curl -u dsl:password http://127.0.0.1:8080/getDir/
+/// curl --digest -u dsl:password http://127.0.0.1:8080/digest/whoami
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let htpasswd = std::env::var("HTPASSWD_FILE").unwrap_or_else(|_| "htpasswd".to_string());
+    let htdigest = std::env::var("HTDIGEST_FILE").unwrap_or_else(|_| "htdigest".to_string());
+    let dir = std::env::var("SERVE_DIR").unwrap_or_else(|_| ".".to_string());
+    // 在 HttpServer::new 之外创建, 所有 worker 共享同一份密码文件与 nonce 记录
+    let basic = HtpasswdAuth::basic(REALM, htpasswd);
+    let digest = HtpasswdAuth::digest(REALM, htdigest);
+    basic.watch(RELOAD_INTERVAL);
+    digest.watch(RELOAD_INTERVAL);
+
+    HttpServer::new(move || {
+        App::new()
+            .service(web::scope("/getDir")
+                .wrap(basic.clone())
+                .service(actix_files::Files::new("/", &dir).show_files_listing()))
+            .service(web::scope("/digest")
+                .wrap(digest.clone())
+                .route("/whoami", web::get().to(whoami)))
+            .route("/", web::get().to(|| async { "public page" }))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+const REALM: &str = "actix-web3 demo";
+/// Digest 认证 nonce 的有效期
+const NONCE_LIFETIME: Duration = Duration::from_secs(300);
+/// 检查密码文件是否修改的间隔
+const RELOAD_INTERVAL: Duration = Duration::from_secs(2);
+/// `htpasswd -B` 默认的 bcrypt cost, 用户不存在时校验的假哈希使用同样的 cost
+const DUMMY_BCRYPT_COST: u32 = 5;
+
+async fn whoami(user: AuthenticatedUser) -> String {
+    format!("Hello {}!", user.name())
+}
+
+/// 认证通过的用户名
+pub struct AuthenticatedUser(String);
+
+impl AuthenticatedUser {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match req.extensions().get::<AuthenticatedUser>() {
+            Some(user) => ok(AuthenticatedUser(user.0.clone())),
+            None => err(actix_web::error::ErrorUnauthorized("not authenticated")),
+        }
+    }
+}
+
+/// 密码文件的格式
+#[derive(Clone, Copy)]
+enum FileFormat {
+    /// `user:hash`
+    Htpasswd,
+    /// `user:realm:MD5(user:realm:password)`
+    Htdigest,
+}
+
+/// 一个会在修改后自动重新加载的密码文件
+struct PasswordFile {
+    path: PathBuf,
+    format: FileFormat,
+    realm: String,
+    state: Mutex<(Option<SystemTime>, HashMap<String, String>)>,
+}
+
+impl PasswordFile {
+    fn new(path: PathBuf, format: FileFormat, realm: &str) -> Self {
+        PasswordFile { path, format, realm: realm.to_string(), state: Mutex::new((None, HashMap::new())) }
+    }
+
+    /// 查找用户的密码哈希, 只读取内存中的内容
+    fn lookup(&self, user: &str) -> Option<String> {
+        self.state.lock().unwrap().1.get(user).cloned()
+    }
+
+    /// 文件的修改时间变化时重新加载. 会阻塞, 不要在 worker 上调用
+    fn reload(&self) {
+        let modified = std::fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified == self.state.lock().unwrap().0 {
+            return;
+        }
+        // 读取与解析时不持有锁, 请求可以继续使用旧的内容
+        match std::fs::read_to_string(&self.path).map_err(|e| e.to_string()).and_then(|s| self.parse(&s)) {
+            Ok(entries) => {
+                log::info!("loaded {} users from {}", entries.len(), self.path.display());
+                *self.state.lock().unwrap() = (modified, entries);
+            }
+            // 保留旧的内容, 下次检查时再试
+            Err(e) => log::warn!("failed to load {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn parse(&self, content: &str) -> Result<HashMap<String, String>, String> {
+        let mut entries = HashMap::new();
+        for (no, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.splitn(3, ':').collect();
+            match (self.format, fields.as_slice()) {
+                (FileFormat::Htpasswd, [user, hash]) => {
+                    entries.insert(user.to_string(), hash.to_string());
+                }
+                (FileFormat::Htpasswd, [user, hash, rest]) => {
+                    // 哈希中不会包含 `:`, 但 splitn(3) 会把它拆开
+                    entries.insert(user.to_string(), format!("{}:{}", hash, rest));
+                }
+                (FileFormat::Htdigest, [user, realm, ha1]) => {
+                    if *realm == self.realm {
+                        entries.insert(user.to_string(), ha1.to_lowercase());
+                    }
+                }
+                _ => return Err(format!("line {}: invalid entry", no + 1)),
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// 按 htpasswd 中的哈希格式校验密码
+fn verify_password(hash: &str, password: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if let Some(expected) = hash.strip_prefix("{SHA}") {
+        constant_time_eq(base64::encode(Sha1::digest(password.as_bytes())).as_bytes(), expected.as_bytes())
+    } else {
+        log::warn!("unsupported htpasswd hash format, use bcrypt (htpasswd -B) or SHA (htpasswd -s)");
+        false
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn md5_hex(data: &str) -> String {
+    hex(&Md5::digest(data.as_bytes()))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Digest 认证的 nonce: `时间戳.签名`, 签名可以防止客户端伪造时间戳.
+/// 每个 nonce 最后一次使用的 nc 也记录下来, 防止请求被重放.
+struct Nonces {
+    secret: [u8; 32],
+    used: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+enum NonceState {
+    Valid,
+    Stale,
+    Invalid,
+}
+
+impl Nonces {
+    fn new() -> Self {
+        Nonces { secret: rand::thread_rng().gen(), used: Mutex::new(HashMap::new()) }
+    }
+
+    fn sign(&self, timestamp: u64) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.secret).expect("hmac accepts any key size");
+        mac.update(timestamp.to_string().as_bytes());
+        hex(&mac.finalize().into_bytes())
+    }
+
+    fn issue(&self) -> String {
+        let timestamp = now_secs();
+        format!("{}.{}", timestamp, self.sign(timestamp))
+    }
+
+    /// 检查 nonce 的签名与有效期, 并要求 nc 比上一次使用时大
+    fn check(&self, nonce: &str, nc: u32) -> NonceState {
+        let timestamp = match nonce.split_once('.') {
+            Some((ts, sig)) => match ts.parse::<u64>() {
+                Ok(ts) if constant_time_eq(self.sign(ts).as_bytes(), sig.as_bytes()) => ts,
+                _ => return NonceState::Invalid,
+            },
+            None => return NonceState::Invalid,
+        };
+        let now = now_secs();
+        if now.saturating_sub(timestamp) > NONCE_LIFETIME.as_secs() {
+            return NonceState::Stale;
+        }
+
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, (ts, _)| now.saturating_sub(*ts) <= NONCE_LIFETIME.as_secs());
+        let last = used.entry(nonce.to_string()).or_insert((timestamp, 0));
+        if nc <= last.1 {
+            return NonceState::Invalid;
+        }
+        last.1 = nc;
+        NonceState::Valid
+    }
+}
+
+/// 解析 `key=value, key="quoted, value"` 形式的参数
+fn parse_auth_params(input: &str) -> HashMap<String, String> {
+    let mut params = HashMap::new();
+    let mut rest = input.trim();
+    while !rest.is_empty() {
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => break,
+        };
+        let key = rest[..eq].trim().to_lowercase();
+        rest = rest[eq + 1..].trim_start();
+        let value;
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let mut unescaped = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => unescaped.extend(chars.next().map(|(_, c)| c)),
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => unescaped.push(c),
+                }
+            }
+            value = unescaped;
+            rest = &quoted[end..];
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+        }
+        params.insert(key, value);
+        rest = rest.trim_start().trim_start_matches(',').trim_start();
+    }
+    params
+}
+
+/// 认证失败, 返回 401 与 `WWW-Authenticate` 响应头
+#[derive(Debug)]
+pub struct AuthError {
+    challenge: String,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "authentication required")
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::Unauthorized()
+            .set_header(header::WWW_AUTHENTICATE, self.challenge.as_str())
+            .body(self.to_string())
+    }
+}
+
+#[derive(Clone)]
+enum Scheme {
+    Basic,
+    Digest(Arc<Nonces>),
+}
+
+/// htpasswd 认证中间件
+#[derive(Clone)]
+pub struct HtpasswdAuth {
+    scheme: Scheme,
+    realm: String,
+    file: Arc<PasswordFile>,
+    /// 用户不存在时用来校验的哈希
+    dummy_hash: Arc<str>,
+}
+
+impl HtpasswdAuth {
+    pub fn basic<P: Into<PathBuf>>(realm: &str, htpasswd: P) -> Self {
+        let file = PasswordFile::new(htpasswd.into(), FileFormat::Htpasswd, realm);
+        file.reload();
+        let dummy: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(16).collect();
+        HtpasswdAuth {
+            scheme: Scheme::Basic,
+            realm: realm.to_string(),
+            file: Arc::new(file),
+            dummy_hash: bcrypt::hash(dummy, DUMMY_BCRYPT_COST).expect("valid bcrypt cost").into(),
+        }
+    }
+
+    pub fn digest<P: Into<PathBuf>>(realm: &str, htdigest: P) -> Self {
+        let file = PasswordFile::new(htdigest.into(), FileFormat::Htdigest, realm);
+        file.reload();
+        HtpasswdAuth {
+            scheme: Scheme::Digest(Arc::new(Nonces::new())),
+            realm: realm.to_string(),
+            file: Arc::new(file),
+            dummy_hash: hex(&rand::thread_rng().gen::<[u8; 16]>()).into(),
+        }
+    }
+
+    /// 定时在线程池中检查密码文件, 需要在 actix 的 System 中调用
+    pub fn watch(&self, interval: Duration) {
+        let file = self.file.clone();
+        actix_web::rt::spawn(async move {
+            let mut ticks = actix_web::rt::time::interval(interval);
+            loop {
+                ticks.tick().await;
+                let file = file.clone();
+                let _ = web::block(move || {
+                    file.reload();
+                    Ok::<_, ()>(())
+                }).await;
+            }
+        });
+    }
+
+    /// 查找用户的哈希, 用户不存在时返回假的哈希与 `false`
+    fn lookup(&self, user: &str) -> (String, bool) {
+        match self.file.lookup(user) {
+            Some(hash) => (hash, true),
+            None => (self.dummy_hash.to_string(), false),
+        }
+    }
+
+    fn challenge(&self, stale: bool) -> AuthError {
+        let realm = self.realm.replace('"', "\\\"");
+        let challenge = match &self.scheme {
+            Scheme::Basic => format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            Scheme::Digest(nonces) => format!("Digest realm=\"{}\", qop=\"auth\", algorithm=MD5, nonce=\"{}\"{}",
+                                              realm, nonces.issue(), if stale { ", stale=true" } else { "" }),
+        };
+        AuthError { challenge }
+    }
+
+    /// 校验请求中的凭证, 返回用户名
+    async fn authenticate(&self, req: &ServiceRequest) -> Result<String, AuthError> {
+        let authorization = req.headers().get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '))
+            .map(|(scheme, credentials)| (scheme.to_lowercase(), credentials.trim().to_string()));
+
+        match (&self.scheme, authorization) {
+            (Scheme::Basic, Some((scheme, credentials))) if scheme == "basic" => {
+                let decoded = base64::decode(&credentials).ok().and_then(|v| String::from_utf8(v).ok());
+                let (user, password) = match decoded.as_deref().and_then(|v| v.split_once(':')) {
+                    Some((user, password)) => (user.to_string(), password.to_string()),
+                    None => return Err(self.challenge(false)),
+                };
+                // 用户不存在时也要校验一次, 否则响应时间会暴露用户是否存在
+                let (hash, known) = self.lookup(&user);
+                // bcrypt 比较耗时, 放到线程池中执行
+                let valid = web::block(move || Ok::<_, ()>(verify_password(&hash, &password))).await.unwrap_or(false);
+                if valid && known { Ok(user) } else { Err(self.challenge(false)) }
+            }
+            (Scheme::Digest(nonces), Some((scheme, credentials))) if scheme == "digest" => {
+                let params = parse_auth_params(&credentials);
+                let param = |name: &str| params.get(name).map(String::as_str).unwrap_or("");
+                let uri = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+                if param("realm") != self.realm || param("uri") != uri || param("qop") != "auth"
+                    || !matches!(param("algorithm"), "" | "MD5") {
+                    return Err(self.challenge(false));
+                }
+                let user = param("username");
+                let (ha1, known) = self.lookup(user);
+                let ha2 = md5_hex(&format!("{}:{}", req.method(), uri));
+                let expected = md5_hex(&format!("{}:{}:{}:{}:auth:{}", ha1, param("nonce"), param("nc"), param("cnonce"), ha2));
+                if !constant_time_eq(expected.as_bytes(), param("response").as_bytes()) || !known {
+                    return Err(self.challenge(false));
+                }
+                // 摘要正确之后再检查 nonce, 过期时客户端可以用同样的密码重新计算
+                let nc = u32::from_str_radix(param("nc"), 16).unwrap_or(0);
+                match nonces.check(param("nonce"), nc) {
+                    NonceState::Valid => Ok(user.to_string()),
+                    NonceState::Stale => Err(self.challenge(true)),
+                    NonceState::Invalid => Err(self.challenge(false)),
+                }
+            }
+            _ => Err(self.challenge(false)),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for HtpasswdAuth
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = HtpasswdAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(HtpasswdAuthMiddleware { service: Rc::new(RefCell::new(service)), auth: Rc::new(self.clone()) })
+    }
+}
+
+pub struct HtpasswdAuthMiddleware<S> {
+    // 认证是异步的, 完成之后才调用下一个服务, 所以需要共享
+    service: Rc<RefCell<S>>,
+    auth: Rc<HtpasswdAuth>,
+}
+
+impl<S, B> Service for HtpasswdAuthMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let auth = self.auth.clone();
+
+        Box::pin(async move {
+            let user = auth.authenticate(&req).await?;
+            req.extensions_mut().insert(AuthenticatedUser(user));
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(format: FileFormat) -> PasswordFile {
+        PasswordFile::new(PathBuf::from("unused"), format, "users")
+    }
+
+    #[test]
+    fn parses_htpasswd() {
+        let entries = file(FileFormat::Htpasswd)
+            .parse("# comment\n\nalice:{SHA}abc=\n  bob:$2y$05$xyz  \ncarol:a:b\n")
+            .unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries["alice"], "{SHA}abc=");
+        assert_eq!(entries["bob"], "$2y$05$xyz");
+        assert_eq!(entries["carol"], "a:b");
+        assert_eq!(file(FileFormat::Htpasswd).parse("ok:hash\nbroken\n"), Err("line 2: invalid entry".to_string()));
+    }
+
+    #[test]
+    fn htdigest_keeps_only_the_configured_realm() {
+        let entries = file(FileFormat::Htdigest)
+            .parse("alice:users:ABCDEF\nbob:admins:123456\n")
+            .unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries["alice"], "abcdef");
+        assert!(file(FileFormat::Htdigest).parse("alice:ABCDEF\n").is_err());
+    }
+
+    #[test]
+    fn reload_keeps_old_entries_when_the_file_is_broken() {
+        let path = std::env::temp_dir().join(format!("htpasswd-test-{}", std::process::id()));
+        std::fs::write(&path, "alice:{SHA}abc=\n").unwrap();
+        let file = PasswordFile::new(path.clone(), FileFormat::Htpasswd, "users");
+        assert_eq!(file.lookup("alice"), None);
+        file.reload();
+        assert_eq!(file.lookup("alice").as_deref(), Some("{SHA}abc="));
+
+        std::fs::write(&path, "broken\n").unwrap();
+        // 强制修改时间变化
+        file.state.lock().unwrap().0 = None;
+        file.reload();
+        assert_eq!(file.lookup("alice").as_deref(), Some("{SHA}abc="));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_user_is_checked_against_the_dummy_hash() {
+        let auth = HtpasswdAuth::basic("users", "does-not-exist");
+        let (hash, known) = auth.lookup("nobody");
+        assert!(!known);
+        assert!(hash.starts_with("$2"));
+        assert!(!verify_password(&hash, ""));
+    }
+
+    #[test]
+    fn verifies_password_hashes() {
+        // htpasswd -nbs alice secret
+        assert!(verify_password("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "secret"));
+        assert!(!verify_password("{SHA}5en6G6MezRroT3XKqkdPOmY/BfQ=", "wrong"));
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        assert!(verify_password(&hash, "secret"));
+        assert!(!verify_password(&hash, "wrong"));
+        // 不支持的格式(明文, crypt, apr1)一律拒绝
+        assert!(!verify_password("secret", "secret"));
+        assert!(!verify_password("$apr1$salt$hash", "secret"));
+    }
+
+    #[test]
+    fn parses_auth_params() {
+        let params = parse_auth_params(r#"username="alice", realm="a, \"quoted\" realm",nc=00000001 , qop=auth"#);
+        assert_eq!(params["username"], "alice");
+        assert_eq!(params["realm"], r#"a, "quoted" realm"#);
+        assert_eq!(params["nc"], "00000001");
+        assert_eq!(params["qop"], "auth");
+        assert!(parse_auth_params("").is_empty());
+    }
+
+    #[test]
+    fn nonce_rejects_forgery_and_replay() {
+        let nonces = Nonces::new();
+        let nonce = nonces.issue();
+        assert!(matches!(nonces.check(&nonce, 1), NonceState::Valid));
+        assert!(matches!(nonces.check(&nonce, 1), NonceState::Invalid));
+        assert!(matches!(nonces.check(&nonce, 2), NonceState::Valid));
+        let (timestamp, _) = nonce.split_once('.').unwrap();
+        assert!(matches!(nonces.check(&format!("{}.{}", timestamp, "0".repeat(64)), 1), NonceState::Invalid));
+        let old = now_secs() - NONCE_LIFETIME.as_secs() - 1;
+        assert!(matches!(nonces.check(&format!("{}.{}", old, nonces.sign(old)), 1), NonceState::Stale));
+    }
+}
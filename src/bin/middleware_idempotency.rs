@@ -0,0 +1,557 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, dev::Body, dev::MessageBody, dev::ResponseBody,
+                error, Error, HttpMessage, HttpServer, App, HttpResponse, ResponseError, web, middleware,
+                http::header, http::HeaderMap, http::HeaderName, http::HeaderValue, http::Method, http::StatusCode};
+use actix_service::{Service, Transform};
+use bytes::{Bytes, BytesMut};
+use derive_more::Display;
+use futures::future::{ok, poll_fn, Ready};
+use futures::{Future, StreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use env_logger::Env;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// ## 幂等键 (Idempotency-Key)
+/// 网络出错之后重试 `POST /manual` 或 `POST /json`, 处理函数可能会被执行两次. 客户端可以在请求中带上
+/// `Idempotency-Key` 头, `Idempotency` 中间件会保证同一个键对应的请求只被处理一次:
+/// * 第一次请求的响应(状态码, 响应头与 body)按 `客户端 + 键` 保存下来, 在 `ttl` 时间内重复的请求直接返回保存的响应,
+///   并带上 `Idempotent-Replayed: true` 头
+/// * 第一次请求还在处理时, 相同键的请求返回 409 Conflict
+/// * 相同的键但是请求方法, 路径或 body 不同, 返回 422 Unprocessable Entity
+/// * 处理函数返回 5xx 或者请求被取消时不保存响应, 客户端可以使用同一个键重试
+///
+/// 客户端由 `Authorization` 头区分, 没有认证信息时使用客户端的 ip, 不同客户端使用相同的键互不影响.
+/// 保存的记录数量有上限(`IdempotencyStore::max_records()`), 满了之后淘汰最早保存的响应, 全部都在处理中时返回 503;
+/// 每个客户端的记录数量也有上限(`max_records_per_client()`), 超过时返回 429, 一个客户端不能用大量的键挤掉其他客户端的记录.
+/// 过期的记录按保存的顺序放在一个队列中, 每次只清理队列头部已经过期的部分.
+/// 为了比较 body, 中间件会先读取完整的请求 body(通过 `max_body()` 设置上限), 响应 body 也会被完整读取之后才保存.
+///
+/// ```shell
+/// curl -i -H "Idempotency-Key: order-1" -H "Content-Type: application/json" -d '{"name":"dsl","number":1}' http://127.0.0.1:8080/json
+/// # 重复的请求返回同一个订单号
+/// curl -i -H "Idempotency-Key: order-1" -H "Content-Type: application/json" -d '{"name":"dsl","number":1}' http://127.0.0.1:8080/json
+/// # 相同的键, 不同的 body: 422
+/// curl -i -H "Idempotency-Key: order-1" -H "Content-Type: application/json" -d '{"name":"dsl","number":2}' http://127.0.0.1:8080/json
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let store = Arc::new(IdempotencyStore::new(Duration::from_secs(24 * 60 * 60)).max_records(10_000));
+    let orders = web::Data::new(AtomicU64::new(0));
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(orders.clone())
+            .wrap(Idempotency::new(store.clone()).max_body(64 * 1024))
+            .wrap(middleware::Logger::default())
+            .route("/manual", web::post().to(index_manual))
+            .route("/json", web::post().to(index_json))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+#[derive(Deserialize, Serialize)]
+struct MyObj {
+    name: String,
+    number: i32,
+}
+
+#[derive(Serialize)]
+struct Order {
+    order_id: u64,
+    item: MyObj,
+}
+
+const MAX_SIZE: usize = 262_144;
+
+/// 创建订单是一个有副作用的操作, 每执行一次订单号加一
+async fn index_manual(mut payload: web::Payload, orders: web::Data<AtomicU64>) -> Result<HttpResponse, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (body.len() + chunk.len()) > MAX_SIZE {
+            return Err(error::ErrorBadRequest("overflow"));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let item = serde_json::from_slice::<MyObj>(&body)?;
+    Ok(create_order(item, &orders).await)
+}
+
+async fn index_json(item: web::Json<MyObj>, orders: web::Data<AtomicU64>) -> HttpResponse {
+    create_order(item.into_inner(), &orders).await
+}
+
+async fn create_order(item: MyObj, orders: &AtomicU64) -> HttpResponse {
+    // 模拟一个比较慢的操作, 方便观察并发的重复请求
+    actix_web::rt::time::delay_for(Duration::from_millis(500)).await;
+    let order_id = orders.fetch_add(1, Ordering::SeqCst) + 1;
+    HttpResponse::Created()
+        .set_header(header::LOCATION, format!("/orders/{}", order_id))
+        .json(Order { order_id, item })
+}
+
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+const REPLAYED: &str = "idempotent-replayed";
+
+#[derive(Debug, Display)]
+enum IdempotencyError {
+    #[display(fmt = "Idempotency-Key must be 1 to 255 visible ASCII characters")]
+    InvalidKey,
+    #[display(fmt = "request body is larger than {} bytes", _0)]
+    PayloadTooLarge(usize),
+    #[display(fmt = "a request with the same Idempotency-Key is still being processed")]
+    InProgress,
+    #[display(fmt = "Idempotency-Key was already used for a different request")]
+    Mismatch,
+    #[display(fmt = "too many requests are being processed, retry later")]
+    StoreFull,
+    #[display(fmt = "too many idempotency keys in use by this client")]
+    TooManyKeys,
+}
+
+impl ResponseError for IdempotencyError {
+    fn error_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::build(self.status_code());
+        if let IdempotencyError::InProgress | IdempotencyError::StoreFull = self {
+            res.set_header(header::RETRY_AFTER, "1");
+        }
+        res.json(serde_json::json!({ "error": self.to_string() }))
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            IdempotencyError::InvalidKey => StatusCode::BAD_REQUEST,
+            IdempotencyError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            IdempotencyError::InProgress => StatusCode::CONFLICT,
+            IdempotencyError::Mismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            IdempotencyError::StoreFull => StatusCode::SERVICE_UNAVAILABLE,
+            IdempotencyError::TooManyKeys => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+}
+
+/// 保存下来的响应
+#[derive(Clone)]
+struct StoredResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn to_response(&self) -> HttpResponse {
+        let mut res = HttpResponse::with_body(self.status, Body::from(self.body.clone()));
+        *res.headers_mut() = self.headers.clone();
+        res.headers_mut().insert(HeaderName::from_static(REPLAYED), HeaderValue::from_static("true"));
+        res
+    }
+}
+
+enum RecordState {
+    InFlight,
+    Completed(StoredResponse),
+}
+
+struct Record {
+    client: String,
+    /// 请求方法, 路径与 body 的摘要
+    fingerprint: [u8; 32],
+    state: RecordState,
+    /// 保存响应的时间
+    created: Instant,
+}
+
+/// `begin()` 的结果
+enum Begin {
+    /// 第一次看到这个键, 由当前请求执行处理函数
+    Proceed,
+    Replay(StoredResponse),
+}
+
+#[derive(Default)]
+struct Records {
+    /// 键是 `客户端 + 幂等键`
+    map: HashMap<String, Record>,
+    /// 已经保存响应的记录, 按保存的时间排序, 最早的在前面
+    completed: VecDeque<(Instant, String)>,
+    /// 每个客户端的记录数量, 包括处理中的记录
+    per_client: HashMap<String, usize>,
+}
+
+impl Records {
+    fn remove(&mut self, key: &str) {
+        if let Some(record) = self.map.remove(key) {
+            if let Some(count) = self.per_client.get_mut(&record.client) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_client.remove(&record.client);
+                }
+            }
+        }
+    }
+
+    /// 移除队列头部的记录, 返回是否移除了一条记录
+    fn pop_oldest(&mut self) -> bool {
+        match self.completed.pop_front() {
+            Some((created, key)) => {
+                // 队列中的时间与记录不一致时, 记录已经被替换了
+                if self.map.get(&key).is_some_and(|record| record.created == created) {
+                    self.remove(&key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 所有 worker 共享的幂等记录
+pub struct IdempotencyStore {
+    ttl: Duration,
+    max_records: usize,
+    max_records_per_client: usize,
+    records: Mutex<Records>,
+}
+
+impl IdempotencyStore {
+    /// 响应保存 `ttl` 时间, 之后同一个键会被当作新的请求
+    pub fn new(ttl: Duration) -> Self {
+        IdempotencyStore { ttl, max_records: 100_000, max_records_per_client: 1000, records: Mutex::new(Records::default()) }
+    }
+
+    /// 最多保存的记录数量, 默认 100000
+    pub fn max_records(mut self, max_records: usize) -> Self {
+        self.max_records = max_records.max(1);
+        self
+    }
+
+    /// 每个客户端最多保存的记录数量, 默认 1000
+    pub fn max_records_per_client(mut self, max_records: usize) -> Self {
+        self.max_records_per_client = max_records.max(1);
+        self
+    }
+
+    fn begin(&self, client: &str, key: &str, fingerprint: [u8; 32]) -> Result<Begin, IdempotencyError> {
+        let mut records = self.records.lock().unwrap();
+        // 只清理队列头部过期的记录, 处理中的记录不在队列中, 由 `InFlightGuard` 负责清理
+        while records.completed.front().is_some_and(|(created, _)| created.elapsed() >= self.ttl) {
+            records.pop_oldest();
+        }
+
+        let full_key = format!("{}\n{}", client, key);
+        match records.map.get(&full_key) {
+            Some(record) if record.fingerprint != fingerprint => Err(IdempotencyError::Mismatch),
+            Some(Record { state: RecordState::InFlight, .. }) => Err(IdempotencyError::InProgress),
+            Some(Record { state: RecordState::Completed(res), .. }) => Ok(Begin::Replay(res.clone())),
+            None => {
+                if records.per_client.get(client).copied().unwrap_or(0) >= self.max_records_per_client {
+                    return Err(IdempotencyError::TooManyKeys);
+                }
+                // 淘汰最早保存的响应, 处理中的记录不能淘汰
+                while records.map.len() >= self.max_records {
+                    if !records.pop_oldest() {
+                        return Err(IdempotencyError::StoreFull);
+                    }
+                }
+                records.map.insert(full_key, Record {
+                    client: client.to_string(),
+                    fingerprint,
+                    state: RecordState::InFlight,
+                    created: Instant::now(),
+                });
+                *records.per_client.entry(client.to_string()).or_insert(0) += 1;
+                Ok(Begin::Proceed)
+            }
+        }
+    }
+
+    fn complete(&self, key: &str, res: StoredResponse) {
+        let mut records = self.records.lock().unwrap();
+        let now = Instant::now();
+        if let Some(record) = records.map.get_mut(key) {
+            record.state = RecordState::Completed(res);
+            record.created = now;
+            records.completed.push_back((now, key.to_string()));
+        }
+    }
+
+    fn release(&self, key: &str) {
+        self.records.lock().unwrap().remove(key);
+    }
+}
+
+/// 处理函数没有正常完成(出错或者请求被取消)时释放这个键
+struct InFlightGuard {
+    store: Arc<IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl InFlightGuard {
+    fn complete(mut self, res: StoredResponse) {
+        if let Some(key) = self.key.take() {
+            self.store.complete(&key, res);
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            log::debug!("releasing idempotency key {}", key);
+            self.store.release(&key);
+        }
+    }
+}
+
+/// 使用 Authorization 头区分客户端, 没有时使用客户端 ip
+fn client_of(req: &ServiceRequest) -> String {
+    match req.headers().get(header::AUTHORIZATION) {
+        Some(auth) => format!("auth:{:x}", Sha256::digest(auth.as_bytes())),
+        None => format!("ip:{}", req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()),
+    }
+}
+
+/// 幂等键中间件, 只处理带有 `Idempotency-Key` 头的 POST 与 PATCH 请求
+pub struct Idempotency {
+    store: Arc<IdempotencyStore>,
+    max_body: usize,
+}
+
+impl Idempotency {
+    pub fn new(store: Arc<IdempotencyStore>) -> Self {
+        Idempotency { store, max_body: MAX_SIZE }
+    }
+
+    /// 请求 body 的最大字节数, 默认 256KB
+    pub fn max_body(mut self, bytes: usize) -> Self {
+        self.max_body = bytes;
+        self
+    }
+}
+
+impl<S, B> Transform<S> for Idempotency
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Transform = IdempotencyMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IdempotencyMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            store: self.store.clone(),
+            max_body: self.max_body,
+        })
+    }
+}
+
+pub struct IdempotencyMiddleware<S> {
+    // 读完请求 body 之后才调用下一个服务, 所以需要共享
+    service: Rc<RefCell<S>>,
+    store: Arc<IdempotencyStore>,
+    max_body: usize,
+}
+
+impl<S, B> Service for IdempotencyMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let key = match req.headers().get(IDEMPOTENCY_KEY) {
+            Some(key) if req.method() == Method::POST || req.method() == Method::PATCH => key.clone(),
+            _ => {
+                let fut = service.borrow_mut().call(req);
+                return Box::pin(async move { Ok(fut.await?.map_body(|_, body| ResponseBody::Body(Body::from_message(body)))) });
+            }
+        };
+        let store = self.store.clone();
+        let max_body = self.max_body;
+        let client = client_of(&req);
+
+        Box::pin(async move {
+            let raw_key = match key.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= 255 => key.to_string(),
+                _ => return Err(IdempotencyError::InvalidKey.into()),
+            };
+
+            // 读取完整的请求 body 计算摘要, 再交还给处理函数
+            let mut payload = req.take_payload();
+            let mut body = BytesMut::new();
+            while let Some(chunk) = payload.next().await {
+                let chunk = chunk?;
+                if body.len() + chunk.len() > max_body {
+                    return Err(IdempotencyError::PayloadTooLarge(max_body).into());
+                }
+                body.extend_from_slice(&chunk);
+            }
+            let body = body.freeze();
+            let mut hasher = Sha256::new();
+            hasher.update(req.method().as_str());
+            hasher.update(b" ");
+            hasher.update(req.uri().to_string());
+            hasher.update(b"\n");
+            hasher.update(&body);
+            let fingerprint: [u8; 32] = hasher.finalize().into();
+
+            match store.begin(&client, &raw_key, fingerprint)? {
+                Begin::Replay(stored) => {
+                    log::info!("replaying stored response for idempotency key {}", raw_key);
+                    return Ok(req.into_response(stored.to_response()));
+                }
+                Begin::Proceed => {}
+            }
+            let guard = InFlightGuard { store, key: Some(format!("{}\n{}", client, raw_key)) };
+
+            req.set_payload(Payload::Stream(Box::pin(futures::stream::once(async move { Ok(body) }))));
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+
+            // 读取完整的响应 body 保存下来
+            let mut res_body = Box::pin(res.take_body());
+            let mut buf = BytesMut::new();
+            while let Some(chunk) = poll_fn(|cx| res_body.as_mut().poll_next(cx)).await {
+                buf.extend_from_slice(&chunk?);
+            }
+            let stored = StoredResponse { status: res.status(), headers: res.headers().clone(), body: buf.freeze() };
+
+            // 5xx 多半是暂时的错误, 不保存, 让客户端可以重试
+            if !stored.status.is_server_error() {
+                guard.complete(stored.clone());
+            }
+            Ok(res.map_body(move |_, _| ResponseBody::Body(Body::from(stored.body))))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn stored(body: &'static str) -> StoredResponse {
+        StoredResponse { status: StatusCode::OK, headers: HeaderMap::new(), body: Bytes::from_static(body.as_bytes()) }
+    }
+
+    fn len(store: &IdempotencyStore) -> usize {
+        store.records.lock().unwrap().map.len()
+    }
+
+    #[test]
+    fn full_store_evicts_oldest_completed_record() {
+        let store = IdempotencyStore::new(Duration::from_secs(60)).max_records(2);
+        assert!(matches!(store.begin("c", "a", [1; 32]), Ok(Begin::Proceed)));
+        store.complete("c\na", stored("a"));
+        assert!(matches!(store.begin("c", "b", [2; 32]), Ok(Begin::Proceed)));
+        store.complete("c\nb", stored("b"));
+
+        assert!(matches!(store.begin("c", "c", [3; 32]), Ok(Begin::Proceed)));
+        assert_eq!(len(&store), 2);
+        assert!(matches!(store.begin("c", "b", [2; 32]), Ok(Begin::Replay(_))));
+        // "a" 已经被淘汰, 同一个键被当作新的请求, 这次淘汰的是 "b"
+        assert!(matches!(store.begin("c", "a", [1; 32]), Ok(Begin::Proceed)));
+        assert!(!store.records.lock().unwrap().map.contains_key("c\nb"));
+    }
+
+    #[test]
+    fn full_store_rejects_when_all_in_flight() {
+        let store = IdempotencyStore::new(Duration::from_secs(60)).max_records(1);
+        assert!(matches!(store.begin("c", "a", [1; 32]), Ok(Begin::Proceed)));
+        assert!(matches!(store.begin("c", "b", [2; 32]), Err(IdempotencyError::StoreFull)));
+        store.release("c\na");
+        assert!(matches!(store.begin("c", "b", [2; 32]), Ok(Begin::Proceed)));
+    }
+
+    #[test]
+    fn expired_records_are_dropped_from_the_front_of_the_queue() {
+        let store = IdempotencyStore::new(Duration::from_millis(50));
+        assert!(matches!(store.begin("c", "a", [1; 32]), Ok(Begin::Proceed)));
+        store.complete("c\na", stored("a"));
+        assert!(matches!(store.begin("c", "in-flight", [2; 32]), Ok(Begin::Proceed)));
+        std::thread::sleep(Duration::from_millis(60));
+
+        assert!(matches!(store.begin("c", "b", [3; 32]), Ok(Begin::Proceed)));
+        let records = store.records.lock().unwrap();
+        assert!(!records.map.contains_key("c\na"));
+        // 处理中的记录不会过期
+        assert!(records.map.contains_key("c\nin-flight"));
+        assert_eq!(records.per_client["c"], 2);
+    }
+
+    #[test]
+    fn per_client_cap_does_not_affect_other_clients() {
+        let store = IdempotencyStore::new(Duration::from_secs(60)).max_records_per_client(2);
+        assert!(matches!(store.begin("c1", "a", [1; 32]), Ok(Begin::Proceed)));
+        assert!(matches!(store.begin("c1", "b", [2; 32]), Ok(Begin::Proceed)));
+        assert!(matches!(store.begin("c1", "c", [3; 32]), Err(IdempotencyError::TooManyKeys)));
+        assert!(matches!(store.begin("c2", "a", [1; 32]), Ok(Begin::Proceed)));
+        store.release("c1\na");
+        assert!(matches!(store.begin("c1", "c", [3; 32]), Ok(Begin::Proceed)));
+    }
+
+    #[test]
+    fn in_flight_key_conflicts() {
+        let store = IdempotencyStore::new(Duration::from_secs(60));
+        assert!(matches!(store.begin("c", "a", [1; 32]), Ok(Begin::Proceed)));
+        assert!(matches!(store.begin("c", "a", [1; 32]), Err(IdempotencyError::InProgress)));
+        assert_eq!(IdempotencyError::InProgress.status_code(), StatusCode::CONFLICT);
+        // 不同的客户端使用相同的键互不影响
+        assert!(matches!(store.begin("other", "a", [1; 32]), Ok(Begin::Proceed)));
+    }
+
+    #[test]
+    fn replays_the_stored_response_and_rejects_a_different_body() {
+        actix_web::rt::System::new("idempotency").block_on(async {
+            let store = Arc::new(IdempotencyStore::new(Duration::from_secs(60)));
+            let mut app = test::init_service(App::new()
+                .app_data(web::Data::new(AtomicU64::new(0)))
+                .wrap(Idempotency::new(store))
+                .route("/json", web::post().to(index_json))).await;
+            let order = |number: i32| test::TestRequest::post().uri("/json")
+                .header(IDEMPOTENCY_KEY, "order-1")
+                .set_json(&MyObj { name: "dsl".to_string(), number })
+                .to_request();
+
+            let res = test::call_service(&mut app, order(1)).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert!(res.headers().get(REPLAYED).is_none());
+            let first = test::read_body(res).await;
+
+            let res = test::call_service(&mut app, order(1)).await;
+            assert_eq!(res.status(), StatusCode::CREATED);
+            assert_eq!(res.headers().get(REPLAYED).unwrap(), "true");
+            assert_eq!(test::read_body(res).await, first);
+
+            // 中间件返回的是错误, 由 actix-web 转换成响应
+            let err = app.call(order(2)).await.err().unwrap();
+            assert_eq!(err.as_response_error().status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        });
+    }
+}
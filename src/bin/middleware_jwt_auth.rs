@@ -0,0 +1,481 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, Error, HttpServer, App, HttpRequest,
+                HttpResponse, HttpMessage, FromRequest, ResponseError, web, error, http::header, http::StatusCode};
+use actix_service::{Service, Transform};
+use futures::future::{ok, err, Ready};
+use futures::Future;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::fmt;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// ## JWT 认证 (Bearer Token)
+/// 中间件校验 `Authorization: Bearer <token>` 中的 JWT:
+/// * 支持 HS256, RS256 与 EdDSA 三种签名算法, 密钥来自配置(密钥字符串, PEM 文件)或者本地的 JWKS 文件.
+///   每个密钥都绑定了一个算法, token 头部的 `alg` 与密钥不一致时直接拒绝, 防止算法混淆攻击;
+///   token 头部带有 `kid` 时优先使用 `kid` 完全相同的密钥, 没有时才使用不带 `kid` 的密钥
+/// * 校验 `exp`, `nbf`, `aud`, `iss`, 允许一定的时钟偏差(`leeway`)
+/// * 校验通过后把 claims 保存在请求的 extensions 中, 处理函数通过 `Claims<T>` 提取器获取
+/// * 校验失败时返回 401 与符合 RFC 6750 的 `WWW-Authenticate` 响应头
+/// * 公开的路由可以通过 `exclude()` 排除, 以 `*` 结尾时按前缀匹配
+///
+/// 环境变量:
+/// * `JWT_SECRET` - HS256 密钥, 默认是一个演示用的密钥
+/// * `JWT_RSA_PUBLIC`/`JWT_ED_PUBLIC` - RS256/EdDSA 公钥 PEM 文件
+/// * `JWKS_FILE` - 本地 JWKS 文件
+/// * `JWT_DEV_TOKENS=1` - 注册 `/token`, 为任意 `sub` 签发有效的 token, 只能在本地开发时使用, 默认不注册
+/// * `JWT_RSA_PRIVATE`/`JWT_ED_PRIVATE` - 私钥 PEM 文件, 仅用于 `/token` 签发测试用的 token
+///
+/// 可以使用如下命令生成密钥, 注意不要把私钥提交到代码仓库中:
+/// ```shell
+/// openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -out rsa_private.pem
+/// openssl pkey -in rsa_private.pem -pubout -out rsa_public.pem
+/// openssl genpkey -algorithm ED25519 -out ed_private.pem
+/// openssl pkey -in ed_private.pem -pubout -out ed_public.pem
+/// ```
+///
+/// 然后可以这样测试:
+/// ```shell
+/// JWT_DEV_TOKENS=1 cargo run --bin middleware_jwt_auth
+/// TOKEN=$(curl -s "http://127.0.0.1:8080/token?sub=dsl")
+/// curl -i -H "Authorization: Bearer $TOKEN" http://127.0.0.1:8080/api/me
+/// curl -i http://127.0.0.1:8080/api/me   # 401
+/// TOKEN=$(curl -s "http://127.0.0.1:8080/token?sub=dsl&alg=EdDSA")   # 需要配置 JWT_ED_PRIVATE 与 JWT_ED_PUBLIC
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "actix-web3-demo-secret".to_string());
+
+    let mut auth = JwtAuth::new()
+        .audience(AUDIENCE)
+        .issuer(ISSUER)
+        .leeway(30)
+        .realm("actix-web3-demo")
+        .hs256(None, secret.as_bytes())
+        .exclude("/public/*");
+    if let Ok(path) = std::env::var("JWT_RSA_PUBLIC") {
+        auth = auth.rs256_pem(None, &std::fs::read(path)?)?;
+    }
+    if let Ok(path) = std::env::var("JWT_ED_PUBLIC") {
+        auth = auth.eddsa_pem(None, &std::fs::read(path)?)?;
+    }
+    if let Ok(path) = std::env::var("JWKS_FILE") {
+        auth = auth.jwks_file(&path)?;
+    }
+    // `/token` 可以为任何用户签发 token, 只有显式开启时才注册
+    let issuer = if std::env::var("JWT_DEV_TOKENS").is_ok_and(|v| v == "1") {
+        eprintln!("JWT_DEV_TOKENS is set, /token issues valid tokens for any subject");
+        auth = auth.exclude("/token");
+        Some(web::Data::new(TokenIssuer::from_env(secret)?))
+    } else {
+        None
+    };
+
+    HttpServer::new(move || {
+        let issuer = issuer.clone();
+        App::new().wrap(auth.clone())
+            .configure(|cfg| if let Some(issuer) = issuer {
+                cfg.service(web::resource("/token").app_data(issuer).route(web::get().to(token)));
+            })
+            .route("/public/hello", web::get().to(|| async { "Hello anonymous" }))
+            .route("/api/me", web::get().to(me))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+const AUDIENCE: &str = "actix-web3-demo";
+const ISSUER: &str = "https://auth.example.com";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MyClaims {
+    sub: String,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+async fn me(claims: Claims<MyClaims>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "user": claims.sub, "roles": claims.roles }))
+}
+
+/// 签发测试用 token 的私钥
+struct TokenIssuer {
+    secret: String,
+    rsa: Option<EncodingKey>,
+    ed: Option<EncodingKey>,
+}
+
+impl TokenIssuer {
+    fn from_env(secret: String) -> std::io::Result<Self> {
+        let load = |var: &str, parse: fn(&[u8]) -> jsonwebtoken::errors::Result<EncodingKey>| {
+            match std::env::var(var) {
+                Ok(path) => parse(&std::fs::read(path)?).map(Some).map_err(invalid_key),
+                Err(_) => Ok(None),
+            }
+        };
+        Ok(TokenIssuer {
+            secret,
+            rsa: load("JWT_RSA_PRIVATE", EncodingKey::from_rsa_pem)?,
+            ed: load("JWT_ED_PRIVATE", EncodingKey::from_ed_pem)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    sub: String,
+    alg: Option<String>,
+    /// 有效期(秒), 可以是负数, 用来测试过期的 token
+    ttl: Option<i64>,
+}
+
+/// 签发一个测试用的 token
+async fn token(query: web::Query<TokenQuery>, issuer: web::Data<TokenIssuer>) -> Result<HttpResponse, Error> {
+    let (alg, key) = match query.alg.as_deref().unwrap_or("HS256") {
+        "HS256" => (Algorithm::HS256, EncodingKey::from_secret(issuer.secret.as_bytes())),
+        "RS256" => (Algorithm::RS256, issuer.rsa.clone().ok_or_else(|| actix_web::error::ErrorBadRequest("JWT_RSA_PRIVATE is not set"))?),
+        "EdDSA" => (Algorithm::EdDSA, issuer.ed.clone().ok_or_else(|| actix_web::error::ErrorBadRequest("JWT_ED_PRIVATE is not set"))?),
+        other => return Err(actix_web::error::ErrorBadRequest(format!("unsupported alg: {}", other))),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let claims = serde_json::json!({
+        "sub": query.sub,
+        "roles": ["user"],
+        "aud": AUDIENCE,
+        "iss": ISSUER,
+        "iat": now,
+        "nbf": now,
+        "exp": now + query.ttl.unwrap_or(3600),
+    });
+    let token = jsonwebtoken::encode(&Header::new(alg), &claims, &key).map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().content_type("text/plain").body(token))
+}
+
+fn invalid_key<E: fmt::Display>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid jwt key: {}", e))
+}
+
+/// 一个验证密钥, 绑定了签名算法
+#[derive(Clone)]
+struct VerifyingKey {
+    kid: Option<String>,
+    alg: Algorithm,
+    key: DecodingKey,
+}
+
+/// JWT 认证中间件的配置
+#[derive(Clone)]
+pub struct JwtAuth {
+    keys: Vec<VerifyingKey>,
+    audience: Vec<String>,
+    issuer: Vec<String>,
+    /// 允许的时钟偏差(秒)
+    leeway: u64,
+    excluded: Vec<String>,
+    realm: String,
+}
+
+impl Default for JwtAuth {
+    fn default() -> Self {
+        JwtAuth { keys: Vec::new(), audience: Vec::new(), issuer: Vec::new(), leeway: 60, excluded: Vec::new(), realm: "api".to_string() }
+    }
+}
+
+impl JwtAuth {
+    pub fn new() -> Self {
+        JwtAuth::default()
+    }
+
+    pub fn hs256(mut self, kid: Option<&str>, secret: &[u8]) -> Self {
+        self.keys.push(VerifyingKey { kid: kid.map(String::from), alg: Algorithm::HS256, key: DecodingKey::from_secret(secret) });
+        self
+    }
+
+    pub fn rs256_pem(mut self, kid: Option<&str>, pem: &[u8]) -> std::io::Result<Self> {
+        let key = DecodingKey::from_rsa_pem(pem).map_err(invalid_key)?;
+        self.keys.push(VerifyingKey { kid: kid.map(String::from), alg: Algorithm::RS256, key });
+        Ok(self)
+    }
+
+    pub fn eddsa_pem(mut self, kid: Option<&str>, pem: &[u8]) -> std::io::Result<Self> {
+        let key = DecodingKey::from_ed_pem(pem).map_err(invalid_key)?;
+        self.keys.push(VerifyingKey { kid: kid.map(String::from), alg: Algorithm::EdDSA, key });
+        Ok(self)
+    }
+
+    /// 从本地 JWKS 文件加载密钥. 没有 `alg` 的密钥按密钥类型推断算法
+    pub fn jwks_file(mut self, path: &str) -> std::io::Result<Self> {
+        let jwks: JwkSet = serde_json::from_slice(&std::fs::read(path)?).map_err(invalid_key)?;
+        for jwk in &jwks.keys {
+            let alg = match (jwk.common.algorithm, &jwk.algorithm) {
+                (Some(alg), _) => alg,
+                (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+                (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+                (None, AlgorithmParameters::OctetKey(_)) => Algorithm::HS256,
+                (None, AlgorithmParameters::EllipticCurve(_)) => Algorithm::ES256,
+            };
+            let key = DecodingKey::from_jwk(jwk).map_err(invalid_key)?;
+            self.keys.push(VerifyingKey { kid: jwk.common.key_id.clone(), alg, key });
+        }
+        Ok(self)
+    }
+
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.audience.push(audience.to_string());
+        self
+    }
+
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.issuer.push(issuer.to_string());
+        self
+    }
+
+    pub fn leeway(mut self, seconds: u64) -> Self {
+        self.leeway = seconds;
+        self
+    }
+
+    /// `WWW-Authenticate` 中的 realm, 默认为 `api`
+    pub fn realm(mut self, realm: &str) -> Self {
+        self.realm = realm.to_string();
+        self
+    }
+
+    /// 不需要认证的路径, 以 `*` 结尾时按前缀匹配
+    pub fn exclude(mut self, path: &str) -> Self {
+        self.excluded.push(path.to_string());
+        self
+    }
+
+    fn is_excluded(&self, path: &str) -> bool {
+        self.excluded.iter().any(|excluded| match excluded.strip_suffix('*') {
+            Some(prefix) => path.starts_with(prefix),
+            None => path == excluded,
+        })
+    }
+
+    /// 校验 token, 返回其中的 claims
+    fn verify(&self, token: &str) -> Result<Value, AuthError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| self.invalid_token(e.to_string()))?;
+        let key = self.select_key(&header)
+            .ok_or_else(|| self.invalid_token(format!("no key for alg {:?} and kid {:?}", header.alg, header.kid)))?;
+
+        let mut validation = Validation::new(key.alg);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        if !self.audience.is_empty() {
+            validation.set_audience(&self.audience);
+        }
+        if !self.issuer.is_empty() {
+            validation.set_issuer(&self.issuer);
+        }
+
+        jsonwebtoken::decode::<Value>(token, &key.key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| {
+                let description = match e.kind() {
+                    ErrorKind::ExpiredSignature => "token expired".to_string(),
+                    ErrorKind::ImmatureSignature => "token not yet valid".to_string(),
+                    ErrorKind::InvalidAudience => "invalid audience".to_string(),
+                    ErrorKind::InvalidIssuer => "invalid issuer".to_string(),
+                    ErrorKind::InvalidSignature => "invalid signature".to_string(),
+                    _ => e.to_string(),
+                };
+                self.invalid_token(description)
+            })
+    }
+
+    /// 选择算法相同的密钥. token 带有 `kid` 时优先选择 `kid` 相同的密钥, 没有时使用不带 `kid` 的密钥;
+    /// token 没有 `kid` 时优先选择不带 `kid` 的密钥, 没有时使用第一个算法相同的密钥
+    fn select_key(&self, header: &Header) -> Option<&VerifyingKey> {
+        let candidates = || self.keys.iter().filter(move |key| key.alg == header.alg);
+        let kid_less = || candidates().find(|key| key.kid.is_none());
+        match &header.kid {
+            Some(kid) => candidates().find(|key| key.kid.as_ref() == Some(kid)).or_else(kid_less),
+            None => kid_less().or_else(|| candidates().next()),
+        }
+    }
+
+    fn invalid_token(&self, description: String) -> AuthError {
+        AuthError { realm: self.realm.clone(), error: Some(("invalid_token", description)) }
+    }
+}
+
+/// 认证失败, 返回 401 与 `WWW-Authenticate` 响应头.
+/// 请求中没有 token 时按 RFC 6750 不返回 error 参数.
+#[derive(Debug)]
+pub struct AuthError {
+    realm: String,
+    error: Option<(&'static str, String)>,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some((_, description)) => write!(f, "unauthorized: {}", description),
+            None => write!(f, "unauthorized"),
+        }
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::UNAUTHORIZED
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut challenge = format!("Bearer realm=\"{}\"", quote(&self.realm));
+        if let Some((error, description)) = &self.error {
+            challenge.push_str(&format!(", error=\"{}\", error_description=\"{}\"", error, quote(description)));
+        }
+        HttpResponse::Unauthorized()
+            .set_header(header::WWW_AUTHENTICATE, challenge)
+            .json(serde_json::json!({ "error": self.to_string() }))
+    }
+}
+
+/// 保存在请求 extensions 中的已验证的 claims
+struct VerifiedClaims(Rc<Value>);
+
+/// `JwtAuth` 配置的 realm, 保存在请求 extensions 中供 `Claims` 提取器使用
+struct AuthRealm(String);
+
+/// 获取已验证的 claims, 反序列化为 `T`. 没有经过 `JwtAuth` 验证的请求(比如被排除的路由)返回 401.
+pub struct Claims<T>(T);
+
+impl<T> Deref for Claims<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Claims<T> {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let realm = match req.extensions().get::<AuthRealm>() {
+            Some(realm) => realm.0.clone(),
+            None => return err(error::ErrorInternalServerError("JwtAuth is not registered")),
+        };
+        let unauthorized = |error| AuthError { realm: realm.clone(), error };
+        let claims = match req.extensions().get::<VerifiedClaims>() {
+            Some(claims) => claims.0.clone(),
+            None => return err(unauthorized(None).into()),
+        };
+        match T::deserialize(&*claims) {
+            Ok(claims) => ok(Claims(claims)),
+            Err(e) => err(unauthorized(Some(("invalid_token", format!("invalid claims: {}", e)))).into()),
+        }
+    }
+}
+
+impl<S, B> Transform<S> for JwtAuth
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = JwtAuthMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(JwtAuthMiddleware { service, config: Rc::new(self.clone()) })
+    }
+}
+
+pub struct JwtAuthMiddleware<S> {
+    service: S,
+    config: Rc<JwtAuth>,
+}
+
+impl<S, B> Service for JwtAuthMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        req.extensions_mut().insert(AuthRealm(self.config.realm.clone()));
+        if self.config.is_excluded(req.path()) {
+            return Box::pin(self.service.call(req));
+        }
+
+        let token = req.headers().get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                let mut parts = v.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+                    _ => None,
+                }
+            });
+        let claims = match token {
+            Some(token) => self.config.verify(token),
+            None => Err(AuthError { realm: self.config.realm.clone(), error: None }),
+        };
+
+        match claims {
+            Ok(claims) => {
+                req.extensions_mut().insert(VerifiedClaims(Rc::new(claims)));
+                Box::pin(self.service.call(req))
+            }
+            Err(e) => Box::pin(err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::encode;
+
+    fn token(kid: Option<&str>, secret: &[u8]) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = kid.map(String::from);
+        let exp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        encode(&header, &serde_json::json!({ "sub": "tom", "exp": exp }), &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    fn auth() -> JwtAuth {
+        JwtAuth::new().hs256(None, b"default").hs256(Some("k1"), b"first").hs256(Some("k2"), b"second")
+    }
+
+    #[test]
+    fn exact_kid_is_preferred_over_kid_less_key() {
+        assert!(auth().verify(&token(Some("k2"), b"second")).is_ok());
+        assert!(auth().verify(&token(Some("k1"), b"first")).is_ok());
+        assert!(auth().verify(&token(Some("k2"), b"default")).is_err());
+    }
+
+    #[test]
+    fn kid_less_key_is_the_fallback() {
+        assert!(auth().verify(&token(None, b"default")).is_ok());
+        assert!(auth().verify(&token(Some("unknown"), b"default")).is_ok());
+        assert!(auth().verify(&token(None, b"second")).is_err());
+    }
+}
@@ -1,19 +1,439 @@
-use actix_web::{HttpServer, App, HttpResponse, middleware::Logger, web, middleware};
+use actix_web::{HttpServer, App, HttpResponse, middleware::Logger, web, middleware, Error, HttpRequest, HttpMessage,
+                FromRequest, dev::Payload, dev::ServiceRequest, dev::ServiceResponse, http::header, http::HeaderName,
+                http::HeaderValue};
+use actix_service::{Service, Transform};
 use env_logger::Env;
+use futures::future::{ok, Ready};
+use futures::Future;
 
+use std::fmt;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+/// ## 日志与客户端 IP
+/// `Logger` 的 `%{r}a` 会直接相信请求中的 `Forwarded`/`X-Forwarded-For`, 任何客户端都可以伪造;
+/// 而部署在反向代理之后时, `%a` 输出的 socket 对端地址又只是代理的地址.
+///
+/// `ClientIpResolver` 中间件只有在对端地址属于配置的可信代理网段(CIDR)时才会读取代理设置的请求头, 从右往左跳过可信代理,
+/// 第一个不可信的地址就是客户端地址. 只读取配置的那一个请求头(`ForwardedHeader`), 代理没有设置的另一个请求头完全由客户端控制,
+/// 不能作为依据. 解析的结果:
+/// * 写入 `X-Client-IP` 请求头(请求中原有的会被删除), `Logger` 可以用 `%{X-Client-IP}i` 输出
+/// * 保存在请求的 extensions 中, 其它中间件和处理函数可以通过 `ClientIp` 获取
+///
+/// `IpFilter` 中间件按 CIDR 列表允许或拒绝访问, 可以注册在不同的 scope 上, 需要注册在 `ClientIpResolver` 里面.
+///
+/// 可信代理通过 `TRUSTED_PROXIES` 环境变量配置, 默认是本机; 代理使用的请求头通过 `FORWARDED_HEADER` 配置,
+/// 可以是 `x-forwarded-for`(默认) 或者 `forwarded`:
+/// ```shell
+/// curl -H "X-Forwarded-For: 10.1.2.3" http://127.0.0.1:8080/admin/status      # 客户端是 10.1.2.3, 允许访问
+/// curl -H "X-Forwarded-For: 8.8.8.8" http://127.0.0.1:8080/admin/status       # 403
+/// # FORWARDED_HEADER=forwarded cargo run --bin middleware_logging
+/// curl -H "Forwarded: for=203.0.113.7" http://127.0.0.1:8080/logging          # 403, 在黑名单中
+/// ```
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // 初始化日志 info 级别
     env_logger::from_env(Env::default().default_filter_or("info")).init();
 
-    HttpServer::new(|| {
-        App::new().wrap(Logger::default())
-            // 设置日志格式
-            .wrap(Logger::new("%a %{User-Agent}i"))
+    let trusted = std::env::var("TRUSTED_PROXIES").unwrap_or_else(|_| "127.0.0.1/32,::1/128".to_string());
+    let trusted = Cidr::parse_list(&trusted).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+    let forwarded_header = std::env::var("FORWARDED_HEADER").unwrap_or_else(|_| "x-forwarded-for".to_string());
+    let forwarded_header = forwarded_header.parse::<ForwardedHeader>()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    HttpServer::new(move || {
+        // 与 Logger::default() 的格式相同, 只是把 %a 换成了解析之后的客户端地址
+        App::new().wrap(Logger::new(r#"%{X-Client-IP}i "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#))
             // 包装一个中间件 设置默认响应header
             .wrap(middleware::DefaultHeaders::new().header("X-Version", "0.2"))
-            .route("/logging", web::get().to(|| HttpResponse::Ok().body("Hello logging")))
+            // 最后注册的在最外层, 先解析出客户端地址
+            .wrap(ClientIpResolver::new(trusted.clone(), forwarded_header))
+            .service(web::scope("/admin")
+                .wrap(IpFilter::allow(&["10.0.0.0/8", "127.0.0.1/32", "::1/128"]))
+                .route("/status", web::get().to(|ip: ClientIp| async move { format!("admin status for {}", ip) })))
+            .service(web::scope("")
+                .wrap(IpFilter::deny(&["203.0.113.0/24", "2001:db8::/32"]))
+                .route("/logging", web::get().to(|| HttpResponse::Ok().body("Hello logging"))))
     }).bind("127.0.0.1:8080")?
         .run().await
 }
 
+/// 一个 IPv4 或 IPv6 网段
+#[derive(Clone, Copy, Debug)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// 解析逗号分隔的网段列表
+    pub fn parse_list(list: &str) -> Result<Vec<Cidr>, String> {
+        list.split(',').map(str::trim).filter(|s| !s.is_empty()).map(Cidr::from_str).collect()
+    }
+
+    /// 把地址转换成 128 位整数, IPv4 放在低 32 位
+    fn bits(addr: IpAddr) -> (u128, u8) {
+        match addr {
+            IpAddr::V4(v4) => (u128::from(u32::from(v4)), 32),
+            IpAddr::V6(v6) => (u128::from(v6), 128),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 映射的 IPv6 地址(::ffff:a.b.c.d)按 IPv4 处理
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4().filter(|_| v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff]).map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        if self.addr.is_ipv4() != ip.is_ipv4() {
+            return false;
+        }
+        let (net, len) = Cidr::bits(self.addr);
+        let (ip, _) = Cidr::bits(ip);
+        let shift = u32::from(len - self.prefix);
+        shift >= 128 || (net >> shift) == (ip >> shift)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| format!("invalid ip address in {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|p| *p <= max).ok_or_else(|| format!("invalid prefix in {}", s))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+/// 解析之后的客户端地址, 保存在请求的 extensions 中
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    /// 优先使用 `ClientIpResolver` 解析的地址, 没有注册时使用对端地址
+    pub fn of(req: &HttpRequest) -> Option<IpAddr> {
+        req.extensions().get::<ClientIp>().map(|ip| ip.0).or_else(|| req.peer_addr().map(|addr| addr.ip()))
+    }
+}
+
+impl fmt::Display for ClientIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        match ClientIp::of(req) {
+            Some(ip) => ok(ClientIp(ip)),
+            None => futures::future::err(actix_web::error::ErrorInternalServerError("unknown client address")),
+        }
+    }
+}
+
+/// 解析 `Forwarded` 中 `for=` 的值: `1.2.3.4`, `"1.2.3.4:80"`, `"[2001:db8::1]:4711"`
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok()))
+}
+
+/// 可信代理用来传递客户端地址的请求头
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ForwardedHeader {
+    /// RFC 7239 `Forwarded: for=...`
+    Forwarded,
+    /// `X-Forwarded-For: client, proxy1, proxy2`
+    XForwardedFor,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            _ => Err(format!("unknown forwarded header {}", s)),
+        }
+    }
+}
+
+impl ForwardedHeader {
+    /// 代理链上的地址, 从左到右是客户端到最后一个代理
+    fn chain(self, headers: &header::HeaderMap) -> Vec<Option<IpAddr>> {
+        match self {
+            ForwardedHeader::Forwarded => headers.get_all(header::FORWARDED)
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .filter_map(|element| element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") { Some(parse_forwarded_node(value)) } else { None }
+                }))
+                .collect(),
+            ForwardedHeader::XForwardedFor => headers.get_all("x-forwarded-for")
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(','))
+                .map(|ip| ip.trim().parse().ok())
+                .collect(),
+        }
+    }
+}
+
+/// 解析客户端地址的中间件
+#[derive(Clone)]
+pub struct ClientIpResolver {
+    trusted: Rc<Vec<Cidr>>,
+    header: ForwardedHeader,
+}
+
+impl ClientIpResolver {
+    /// `header` 是可信代理设置的请求头, 另一个请求头会被忽略
+    pub fn new(trusted_proxies: Vec<Cidr>, header: ForwardedHeader) -> Self {
+        ClientIpResolver { trusted: Rc::new(trusted_proxies), header }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn resolve(&self, req: &ServiceRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        // 从右往左跳过可信的代理; 无法解析的地址(比如 `unknown`)之前的内容都不可信, 使用最后一个可信的代理
+        let mut client = peer;
+        for hop in self.header.chain(req.headers()).into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+const CLIENT_IP_HEADER: &str = "x-client-ip";
+
+impl<S, B> Transform<S> for ClientIpResolver
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = ClientIpResolverMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ClientIpResolverMiddleware { service, resolver: self.clone() })
+    }
+}
+
+pub struct ClientIpResolverMiddleware<S> {
+    service: S,
+    resolver: ClientIpResolver,
+}
+
+impl<S, B> Service for ClientIpResolverMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let name = HeaderName::from_static(CLIENT_IP_HEADER);
+        // 客户端自己带上的 X-Client-IP 不可信
+        req.headers_mut().remove(&name);
+        if let Some(ip) = self.resolver.resolve(&req) {
+            if let Ok(value) = HeaderValue::from_str(&ip.to_string()) {
+                req.headers_mut().insert(name, value);
+            }
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+        self.service.call(req)
+    }
+}
+
+/// 按 CIDR 列表允许或拒绝访问
+#[derive(Clone)]
+pub struct IpFilter {
+    cidrs: Rc<Vec<Cidr>>,
+    /// true 表示列表是白名单
+    allow: bool,
+}
+
+impl IpFilter {
+    /// 只允许列表中的地址, 无效的网段在启动时直接 panic
+    pub fn allow(cidrs: &[&str]) -> Self {
+        IpFilter { cidrs: Rc::new(IpFilter::parse(cidrs)), allow: true }
+    }
+
+    /// 拒绝列表中的地址
+    pub fn deny(cidrs: &[&str]) -> Self {
+        IpFilter { cidrs: Rc::new(IpFilter::parse(cidrs)), allow: false }
+    }
+
+    fn parse(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs.iter().map(|cidr| cidr.parse().unwrap_or_else(|e| panic!("{}", e))).collect()
+    }
+
+    fn permits(&self, ip: IpAddr) -> bool {
+        self.cidrs.iter().any(|cidr| cidr.contains(ip)) == self.allow
+    }
+}
+
+impl<S, B> Transform<S> for IpFilter
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = IpFilterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(IpFilterMiddleware { service, filter: self.clone() })
+    }
+}
+
+pub struct IpFilterMiddleware<S> {
+    service: S,
+    filter: IpFilter,
+}
+
+impl<S, B> Service for IpFilterMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let ip = req.extensions().get::<ClientIp>().map(|ip| ip.0).or_else(|| req.peer_addr().map(|addr| addr.ip()));
+        match ip {
+            Some(ip) if self.filter.permits(ip) => Box::pin(self.service.call(req)),
+            _ => {
+                let ip = ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+                log::warn!("ip filter denied {} {} from {}", req.method(), req.path(), ip);
+                Box::pin(ok(req.into_response(HttpResponse::Forbidden().finish().into_body())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_cidrs() {
+        assert_eq!(Cidr::from_str("10.0.0.0/8").unwrap().prefix, 8);
+        assert_eq!(Cidr::from_str("::1").unwrap().prefix, 128);
+        assert_eq!(Cidr::from_str("192.168.1.1").unwrap().prefix, 32);
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("2001:db8::/129").is_err());
+        assert!(Cidr::from_str("10.0.0/8").is_err());
+        assert!(Cidr::from_str("10.0.0.0/x").is_err());
+        assert_eq!(Cidr::parse_list("127.0.0.1/32, ,::1/128").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert!(net.contains(ip("10.255.0.1")));
+        assert!(!net.contains(ip("11.0.0.1")));
+        // IPv4 映射的 IPv6 地址
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("::a01:203")));
+
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(ip("8.8.8.8")));
+        assert!(!Cidr::from_str("0.0.0.0/0").unwrap().contains(ip("2001:db8::1")));
+        let v6 = Cidr::from_str("2001:db8::/32").unwrap();
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+    }
+
+    fn resolve(header: ForwardedHeader, peer: &str, headers: &[(&str, &str)]) -> IpAddr {
+        let resolver = ClientIpResolver::new(Cidr::parse_list("127.0.0.1/32,10.0.0.0/8").unwrap(), header);
+        let mut req = TestRequest::default().peer_addr(format!("{}:4000", peer).parse().unwrap());
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        resolver.resolve(&req.to_srv_request()).unwrap()
+    }
+
+    #[test]
+    fn walks_the_chain_from_the_right() {
+        let xff = ForwardedHeader::XForwardedFor;
+        // 对端不可信时忽略请求头
+        assert_eq!(resolve(xff, "8.8.4.4", &[("x-forwarded-for", "1.1.1.1")]), ip("8.8.4.4"));
+        // 跳过右边的可信代理, 左边伪造的地址不会被使用
+        assert_eq!(resolve(xff, "127.0.0.1", &[("x-forwarded-for", "6.6.6.6, 1.1.1.1, 10.0.0.2")]), ip("1.1.1.1"));
+        // 全部是可信代理时使用最左边的
+        assert_eq!(resolve(xff, "127.0.0.1", &[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]), ip("10.0.0.3"));
+        // 无法解析的地址之前的内容都不可信
+        assert_eq!(resolve(xff, "127.0.0.1", &[("x-forwarded-for", "1.1.1.1, unknown, 10.0.0.2")]), ip("10.0.0.2"));
+        assert_eq!(resolve(xff, "127.0.0.1", &[]), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn only_reads_the_configured_header() {
+        let headers = [("forwarded", r#"for="[2001:db8::1]:4711", for=10.0.0.2"#), ("x-forwarded-for", "9.9.9.9")];
+        assert_eq!(resolve(ForwardedHeader::Forwarded, "127.0.0.1", &headers), ip("2001:db8::1"));
+        assert_eq!(resolve(ForwardedHeader::XForwardedFor, "127.0.0.1", &headers), ip("9.9.9.9"));
+        assert_eq!(resolve(ForwardedHeader::Forwarded, "127.0.0.1", &[("x-forwarded-for", "9.9.9.9")]), ip("127.0.0.1"));
+    }
+}
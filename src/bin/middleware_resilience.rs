@@ -0,0 +1,860 @@
+use actix_web::{dev::RequestHead, dev::ServiceRequest, dev::ServiceResponse, Error, HttpServer, App, HttpResponse,
+                ResponseError, guard, web, http::header, http::StatusCode, middleware::Logger};
+use actix_service::{Service, Transform};
+use futures::channel::oneshot;
+use futures::future::{ok, Ready};
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use env_logger::Env;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// ## 舱壁与熔断 (Bulkhead & Circuit Breaker)
+/// 一个很慢的处理函数可能占满所有 worker, 一个故障的下游也会拖慢所有调用它的请求. 这里提供两个中间件:
+/// * `Bulkhead` - 限制一个路由的并发数, 超出的请求在一个有界队列中等待, 队列满了或者等待超时返回 503
+/// * `CircuitBreaker` - 在一个滑动时间窗口内统计错误率(处理函数返回错误或 5xx)与慢调用比例, 超过阈值后熔断器打开(open),
+///   之后的请求直接返回 503 而不调用处理函数; 经过一段时间进入半开(half-open)状态, 放行少量试探请求,
+///   都成功后关闭(closed), 有一个失败就重新打开. 熔断时返回的错误可以通过 `on_open()` 配置
+///
+/// 两个中间件的状态在所有 worker 之间共享, 需要在 `HttpServer::new` 之外创建. 熔断器的状态变化会输出到日志,
+/// 并可以通过 `/metrics`(Prometheus 文本格式)与 `/admin/resilience` 查看.
+///
+/// `POST /admin/breakers/{name}/reset` 可以手动关闭熔断器, 只有设置了 `ADMIN_TOKEN` 环境变量时才会注册,
+/// 请求需要带上 `Authorization: Bearer <ADMIN_TOKEN>`, 否则返回 404.
+///
+/// ```shell
+/// for i in $(seq 10); do curl -s -o /dev/null -w "%{http_code}\n" "http://127.0.0.1:8080/flaky?fail=true"; done
+/// curl http://127.0.0.1:8080/admin/resilience
+/// curl http://127.0.0.1:8080/metrics
+/// for i in $(seq 6); do curl -s -o /dev/null -w "%{http_code}\n" http://127.0.0.1:8080/slow/2000 & done; wait
+/// ADMIN_TOKEN=secret cargo run --bin middleware_resilience
+/// curl -X POST -H "Authorization: Bearer secret" http://127.0.0.1:8080/admin/breakers/flaky/reset
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let flaky_breaker = CircuitBreaker::new("flaky")
+        .window(Duration::from_secs(10))
+        .min_calls(5)
+        .failure_rate(0.5)
+        .slow_calls(Duration::from_millis(500), 0.8)
+        .open_for(Duration::from_secs(5))
+        .half_open_calls(2);
+    let slow_bulkhead = Bulkhead::new("slow", 2, 2).queue_timeout(Duration::from_secs(1));
+    let slow_breaker = CircuitBreaker::new("slow")
+        .on_open(|retry_after| Unavailable::new("slow service is temporarily disabled", retry_after).into());
+
+    let registry = web::Data::new(Registry {
+        breakers: vec![flaky_breaker.clone(), slow_breaker.clone()],
+        bulkheads: vec![slow_bulkhead.clone()],
+    });
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    if admin_token.is_none() {
+        log::warn!("ADMIN_TOKEN is not set, POST /admin/breakers/{{name}}/reset is disabled");
+    }
+
+    HttpServer::new(move || {
+        App::new().wrap(Logger::default())
+            .app_data(registry.clone())
+            .service(web::resource("/flaky")
+                .wrap(flaky_breaker.clone())
+                .route(web::get().to(flaky)))
+            // 最后注册的在最外层: 先经过舱壁, 熔断器拒绝的请求会立即释放名额
+            .service(web::resource("/slow/{millis}")
+                .wrap(slow_breaker.clone())
+                .wrap(slow_bulkhead.clone())
+                .route(web::get().to(slow)))
+            .route("/metrics", web::get().to(metrics))
+            .route("/admin/resilience", web::get().to(admin))
+            .configure(|cfg| admin_routes(cfg, admin_token.clone()))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+#[derive(Deserialize)]
+struct FlakyQuery {
+    #[serde(default)]
+    fail: bool,
+    delay: Option<u64>,
+}
+
+/// 模拟一个不稳定的下游服务
+async fn flaky(query: web::Query<FlakyQuery>) -> HttpResponse {
+    if let Some(delay) = query.delay {
+        actix_web::rt::time::delay_for(Duration::from_millis(delay)).await;
+    }
+    if query.fail {
+        HttpResponse::BadGateway().body("upstream failed")
+    } else {
+        HttpResponse::Ok().body("ok")
+    }
+}
+
+async fn slow(millis: web::Path<u64>) -> String {
+    actix_web::rt::time::delay_for(Duration::from_millis(*millis)).await;
+    format!("finished after {}ms", millis)
+}
+
+/// 所有的熔断器与舱壁, 用于指标与管理接口
+struct Registry {
+    breakers: Vec<CircuitBreaker>,
+    bulkheads: Vec<Bulkhead>,
+}
+
+/// Prometheus 文本格式的指标
+async fn metrics(registry: web::Data<Registry>) -> HttpResponse {
+    let mut out = String::new();
+    out.push_str("# TYPE circuit_breaker_state gauge\n");
+    for breaker in &registry.breakers {
+        let snapshot = breaker.snapshot();
+        for state in &["closed", "open", "half_open"] {
+            let value = if snapshot.state == *state { 1 } else { 0 };
+            let _ = writeln!(out, "circuit_breaker_state{{name=\"{}\",state=\"{}\"}} {}", snapshot.name, state, value);
+        }
+    }
+    out.push_str("# TYPE circuit_breaker_transitions_total counter\n");
+    for breaker in &registry.breakers {
+        let snapshot = breaker.snapshot();
+        for state in &["closed", "open", "half_open"] {
+            let count = snapshot.transitions_total.iter().find(|(s, _)| s == state).map_or(0, |(_, c)| *c);
+            let _ = writeln!(out, "circuit_breaker_transitions_total{{name=\"{}\",to=\"{}\"}} {}", snapshot.name, state, count);
+        }
+    }
+    out.push_str("# TYPE circuit_breaker_rejected_total counter\n");
+    for breaker in &registry.breakers {
+        let snapshot = breaker.snapshot();
+        let _ = writeln!(out, "circuit_breaker_rejected_total{{name=\"{}\"}} {}", snapshot.name, snapshot.rejected_total);
+    }
+    out.push_str("# TYPE bulkhead_active gauge\n# TYPE bulkhead_queued gauge\n# TYPE bulkhead_rejected_total counter\n");
+    for bulkhead in &registry.bulkheads {
+        let snapshot = bulkhead.snapshot();
+        let _ = writeln!(out, "bulkhead_active{{name=\"{}\"}} {}", snapshot.name, snapshot.active);
+        let _ = writeln!(out, "bulkhead_queued{{name=\"{}\"}} {}", snapshot.name, snapshot.queued);
+        let _ = writeln!(out, "bulkhead_rejected_total{{name=\"{}\"}} {}", snapshot.name, snapshot.rejected_total);
+    }
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(out)
+}
+
+async fn admin(registry: web::Data<Registry>) -> HttpResponse {
+    let breakers: Vec<BreakerSnapshot> = registry.breakers.iter().map(CircuitBreaker::snapshot).collect();
+    let bulkheads: Vec<BulkheadSnapshot> = registry.bulkheads.iter().map(Bulkhead::snapshot).collect();
+    HttpResponse::Ok().json(serde_json::json!({ "breakers": breakers, "bulkheads": bulkheads }))
+}
+
+/// 需要认证的管理接口, 没有配置令牌时不注册
+fn admin_routes(cfg: &mut web::ServiceConfig, admin_token: Option<String>) {
+    if let Some(token) = admin_token {
+        let expected = format!("Bearer {}", token);
+        cfg.route("/admin/breakers/{name}/reset", web::post()
+            .guard(guard::fn_guard(move |head: &RequestHead| {
+                head.headers().get(header::AUTHORIZATION)
+                    .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+            }))
+            .to(reset_breaker));
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn reset_breaker(name: web::Path<String>, registry: web::Data<Registry>) -> HttpResponse {
+    match registry.breakers.iter().find(|b| b.name() == name.as_str()) {
+        Some(breaker) => {
+            breaker.reset();
+            HttpResponse::Ok().json(breaker.snapshot())
+        }
+        None => HttpResponse::NotFound().body(format!("no circuit breaker named {}", name)),
+    }
+}
+
+/// 服务暂时不可用, 返回 503 与 `Retry-After`
+#[derive(Debug)]
+pub struct Unavailable {
+    reason: String,
+    retry_after: Duration,
+}
+
+impl Unavailable {
+    pub fn new(reason: &str, retry_after: Duration) -> Self {
+        Unavailable { reason: reason.to_string(), retry_after }
+    }
+}
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl ResponseError for Unavailable {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        // Retry-After 的单位是秒, 向上取整
+        let secs = self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0);
+        HttpResponse::ServiceUnavailable()
+            .set_header(header::RETRY_AFTER, secs.max(1).to_string())
+            .body(self.reason.clone())
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+struct BulkheadState {
+    active: usize,
+    /// 等待名额的请求, 释放名额时直接转交给队首的请求
+    waiters: VecDeque<oneshot::Sender<()>>,
+    rejected_total: u64,
+}
+
+/// 舱壁中间件, clone 之后共享同一个状态
+#[derive(Clone)]
+pub struct Bulkhead {
+    name: Arc<str>,
+    max_concurrent: usize,
+    max_queued: usize,
+    queue_timeout: Duration,
+    state: Arc<Mutex<BulkheadState>>,
+}
+
+#[derive(Serialize)]
+struct BulkheadSnapshot {
+    name: String,
+    active: usize,
+    queued: usize,
+    max_concurrent: usize,
+    max_queued: usize,
+    rejected_total: u64,
+}
+
+/// 一个并发名额, drop 时释放
+struct Permit(Arc<Mutex<BulkheadState>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.0.lock().unwrap();
+        // 等待者可能已经超时放弃, 此时 send 会失败, 转交给下一个
+        while let Some(waiter) = state.waiters.pop_front() {
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+impl Bulkhead {
+    pub fn new(name: &str, max_concurrent: usize, max_queued: usize) -> Self {
+        Bulkhead {
+            name: Arc::from(name),
+            max_concurrent,
+            max_queued,
+            queue_timeout: Duration::from_secs(5),
+            state: Arc::new(Mutex::new(BulkheadState { active: 0, waiters: VecDeque::new(), rejected_total: 0 })),
+        }
+    }
+
+    /// 在队列中等待的最长时间
+    pub fn queue_timeout(mut self, timeout: Duration) -> Self {
+        self.queue_timeout = timeout;
+        self
+    }
+
+    fn snapshot(&self) -> BulkheadSnapshot {
+        let state = self.state.lock().unwrap();
+        BulkheadSnapshot {
+            name: self.name.to_string(),
+            active: state.active,
+            queued: state.waiters.iter().filter(|w| !w.is_canceled()).count(),
+            max_concurrent: self.max_concurrent,
+            max_queued: self.max_queued,
+            rejected_total: state.rejected_total,
+        }
+    }
+
+    fn reject(&self, reason: &str) -> Error {
+        self.state.lock().unwrap().rejected_total += 1;
+        log::warn!("bulkhead {} rejected a request: {}", self.name, reason);
+        Unavailable::new(&format!("too many concurrent requests: {}", reason), self.queue_timeout).into()
+    }
+
+    /// 获取一个名额, 没有空闲名额时排队等待
+    async fn acquire(&self) -> Result<Permit, Error> {
+        let mut rx = {
+            let mut state = self.state.lock().unwrap();
+            if state.active < self.max_concurrent {
+                state.active += 1;
+                return Ok(Permit(self.state.clone()));
+            }
+            // 清理已经放弃等待的请求后再判断队列是否已满
+            state.waiters.retain(|w| !w.is_canceled());
+            if state.waiters.len() >= self.max_queued {
+                drop(state);
+                return Err(self.reject("queue is full"));
+            }
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push_back(tx);
+            rx
+        };
+
+        match actix_web::rt::time::timeout(self.queue_timeout, &mut rx).await {
+            Ok(Ok(())) => Ok(Permit(self.state.clone())),
+            _ => {
+                // 超时的同时名额可能刚好转交过来, 先关闭 channel 再检查一次, 避免名额丢失
+                rx.close();
+                if let Ok(Some(())) = rx.try_recv() {
+                    return Ok(Permit(self.state.clone()));
+                }
+                Err(self.reject("timed out in queue"))
+            }
+        }
+    }
+}
+
+impl<S, B> Transform<S> for Bulkhead
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = BulkheadMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(BulkheadMiddleware { service: Rc::new(std::cell::RefCell::new(service)), bulkhead: self.clone() })
+    }
+}
+
+pub struct BulkheadMiddleware<S> {
+    // 拿到名额之后才调用下一个服务, 所以需要共享
+    service: Rc<std::cell::RefCell<S>>,
+    bulkhead: Bulkhead,
+}
+
+impl<S, B> Service for BulkheadMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let bulkhead = self.bulkhead.clone();
+
+        Box::pin(async move {
+            // 名额一直持有到响应头生成, 处理函数被取消时也会释放
+            let _permit = bulkhead.acquire().await?;
+            let fut = service.borrow_mut().call(req);
+            fut.await
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum BreakerState {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: usize, succeeded: usize },
+}
+
+impl BreakerState {
+    fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open { .. } => "open",
+            BreakerState::HalfOpen { .. } => "half_open",
+        }
+    }
+}
+
+/// 窗口内的一次调用
+struct Call {
+    at: Instant,
+    failed: bool,
+    slow: bool,
+}
+
+#[derive(Clone, Serialize)]
+struct Transition {
+    from: &'static str,
+    to: &'static str,
+    reason: String,
+    at_unix_ms: u64,
+}
+
+struct BreakerInner {
+    state: BreakerState,
+    calls: VecDeque<Call>,
+    transitions: VecDeque<Transition>,
+    transitions_total: Vec<(&'static str, u64)>,
+    rejected_total: u64,
+}
+
+/// 熔断器的配置
+struct BreakerConfig {
+    window: Duration,
+    min_calls: usize,
+    failure_rate: f64,
+    slow_call: Duration,
+    slow_rate: f64,
+    open_for: Duration,
+    half_open_calls: usize,
+    on_open: Box<dyn Fn(Duration) -> Error + Send + Sync>,
+}
+
+/// 熔断器中间件, clone 之后共享同一个状态.
+/// 配置方法需要在 clone 之前调用.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    name: Arc<str>,
+    config: Arc<BreakerConfig>,
+    inner: Arc<Mutex<BreakerInner>>,
+}
+
+#[derive(Serialize)]
+struct BreakerSnapshot {
+    name: String,
+    state: &'static str,
+    /// open 状态下还有多久进入 half-open
+    retry_after_ms: Option<u64>,
+    calls: usize,
+    failure_rate: f64,
+    slow_rate: f64,
+    rejected_total: u64,
+    transitions_total: Vec<(&'static str, u64)>,
+    recent_transitions: Vec<Transition>,
+}
+
+/// 最多保留多少条最近的状态变化
+const MAX_TRANSITIONS: usize = 20;
+
+/// 调用的许可: 是否是 half-open 状态下的试探请求
+enum Admission {
+    Normal,
+    Trial,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str) -> Self {
+        CircuitBreaker {
+            name: Arc::from(name),
+            config: Arc::new(BreakerConfig {
+                window: Duration::from_secs(30),
+                min_calls: 10,
+                failure_rate: 0.5,
+                slow_call: Duration::from_secs(1),
+                slow_rate: 1.0,
+                open_for: Duration::from_secs(10),
+                half_open_calls: 3,
+                on_open: Box::new(|retry_after| Unavailable::new("circuit breaker is open", retry_after).into()),
+            }),
+            inner: Arc::new(Mutex::new(BreakerInner {
+                state: BreakerState::Closed,
+                calls: VecDeque::new(),
+                transitions: VecDeque::new(),
+                transitions_total: Vec::new(),
+                rejected_total: 0,
+            })),
+        }
+    }
+
+    fn config_mut(&mut self) -> &mut BreakerConfig {
+        Arc::get_mut(&mut self.config).expect("configure the circuit breaker before cloning it")
+    }
+
+    /// 统计错误率的滑动时间窗口
+    pub fn window(mut self, window: Duration) -> Self {
+        self.config_mut().window = window;
+        self
+    }
+
+    /// 窗口内至少有这么多次调用才会计算错误率
+    pub fn min_calls(mut self, min_calls: usize) -> Self {
+        self.config_mut().min_calls = min_calls;
+        self
+    }
+
+    /// 错误率达到这个比例时打开
+    pub fn failure_rate(mut self, rate: f64) -> Self {
+        self.config_mut().failure_rate = rate;
+        self
+    }
+
+    /// 超过 `threshold` 的调用是慢调用, 慢调用比例达到 `rate` 时打开
+    pub fn slow_calls(mut self, threshold: Duration, rate: f64) -> Self {
+        let config = self.config_mut();
+        config.slow_call = threshold;
+        config.slow_rate = rate;
+        self
+    }
+
+    /// 打开之后经过多久进入 half-open
+    pub fn open_for(mut self, duration: Duration) -> Self {
+        self.config_mut().open_for = duration;
+        self
+    }
+
+    /// half-open 状态下放行的试探请求数, 全部成功后关闭
+    pub fn half_open_calls(mut self, calls: usize) -> Self {
+        self.config_mut().half_open_calls = calls.max(1);
+        self
+    }
+
+    /// 熔断时返回的错误, 参数是距离进入 half-open 的时间
+    pub fn on_open<F: Fn(Duration) -> Error + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.config_mut().on_open = Box::new(f);
+        self
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transition(&self, inner: &mut BreakerInner, to: BreakerState, reason: String) {
+        let from = inner.state.name();
+        log::warn!("circuit breaker {} {} -> {}: {}", self.name, from, to.name(), reason);
+        inner.state = to;
+        if to == BreakerState::Closed {
+            inner.calls.clear();
+        }
+        match inner.transitions_total.iter_mut().find(|(state, _)| *state == to.name()) {
+            Some((_, count)) => *count += 1,
+            None => inner.transitions_total.push((to.name(), 1)),
+        }
+        inner.transitions.push_back(Transition { from, to: to.name(), reason, at_unix_ms: unix_millis() });
+        while inner.transitions.len() > MAX_TRANSITIONS {
+            inner.transitions.pop_front();
+        }
+    }
+
+    /// 判断请求是否可以通过, 不能通过时返回熔断的错误
+    fn admit(&self) -> Result<Admission, Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        if let BreakerState::Open { until } = inner.state {
+            if now >= until {
+                self.transition(&mut inner, BreakerState::HalfOpen { in_flight: 0, succeeded: 0 }, "open timeout elapsed".to_string());
+            }
+        }
+        match inner.state {
+            BreakerState::Closed => Ok(Admission::Normal),
+            BreakerState::HalfOpen { in_flight, succeeded } if in_flight + succeeded < self.config.half_open_calls => {
+                inner.state = BreakerState::HalfOpen { in_flight: in_flight + 1, succeeded };
+                Ok(Admission::Trial)
+            }
+            // 试探请求已经足够, 其它请求仍然拒绝
+            BreakerState::HalfOpen { .. } => {
+                inner.rejected_total += 1;
+                Err((self.config.on_open)(Duration::from_secs(1)))
+            }
+            BreakerState::Open { until } => {
+                inner.rejected_total += 1;
+                Err((self.config.on_open)(until - now))
+            }
+        }
+    }
+
+    /// 记录一次调用的结果
+    fn record(&self, admission: Admission, failed: bool, latency: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let slow = latency >= self.config.slow_call;
+
+        match (admission, inner.state) {
+            (Admission::Trial, BreakerState::HalfOpen { in_flight, succeeded }) => {
+                if failed || slow {
+                    let reason = if failed { "trial call failed" } else { "trial call was slow" };
+                    self.transition(&mut inner, BreakerState::Open { until: now + self.config.open_for }, reason.to_string());
+                } else if succeeded + 1 >= self.config.half_open_calls {
+                    self.transition(&mut inner, BreakerState::Closed, format!("{} trial calls succeeded", succeeded + 1));
+                } else {
+                    inner.state = BreakerState::HalfOpen { in_flight: in_flight - 1, succeeded: succeeded + 1 };
+                }
+            }
+            (Admission::Normal, BreakerState::Closed) => {
+                inner.calls.push_back(Call { at: now, failed, slow });
+                let window = self.config.window;
+                while inner.calls.front().is_some_and(|call| now - call.at > window) {
+                    inner.calls.pop_front();
+                }
+                let (failure_rate, slow_rate) = rates(&inner.calls);
+                if inner.calls.len() >= self.config.min_calls {
+                    let reason = if failure_rate >= self.config.failure_rate {
+                        Some(format!("failure rate {:.0}% over {} calls", failure_rate * 100.0, inner.calls.len()))
+                    } else if slow_rate >= self.config.slow_rate {
+                        Some(format!("slow call rate {:.0}% over {} calls", slow_rate * 100.0, inner.calls.len()))
+                    } else {
+                        None
+                    };
+                    if let Some(reason) = reason {
+                        self.transition(&mut inner, BreakerState::Open { until: now + self.config.open_for }, reason);
+                    }
+                }
+            }
+            // 调用期间状态已经变化(比如被其它请求打开), 这次的结果不再统计
+            _ => {}
+        }
+    }
+
+    /// 调用被取消(比如客户端断开), 不统计结果, 但要归还试探请求的名额
+    fn cancel(&self, admission: Admission) {
+        let mut inner = self.inner.lock().unwrap();
+        if let (Admission::Trial, BreakerState::HalfOpen { in_flight, succeeded }) = (admission, inner.state) {
+            inner.state = BreakerState::HalfOpen { in_flight: in_flight.saturating_sub(1), succeeded };
+        }
+    }
+
+    fn reset(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        self.transition(&mut inner, BreakerState::Closed, "reset by admin".to_string());
+    }
+
+    fn snapshot(&self) -> BreakerSnapshot {
+        let inner = self.inner.lock().unwrap();
+        let (failure_rate, slow_rate) = rates(&inner.calls);
+        let retry_after_ms = match inner.state {
+            BreakerState::Open { until } => Some(until.saturating_duration_since(Instant::now()).as_millis() as u64),
+            _ => None,
+        };
+        BreakerSnapshot {
+            name: self.name.to_string(),
+            state: inner.state.name(),
+            retry_after_ms,
+            calls: inner.calls.len(),
+            failure_rate,
+            slow_rate,
+            rejected_total: inner.rejected_total,
+            transitions_total: inner.transitions_total.clone(),
+            recent_transitions: inner.transitions.iter().cloned().collect(),
+        }
+    }
+}
+
+/// 窗口内的 (错误率, 慢调用比例)
+fn rates(calls: &VecDeque<Call>) -> (f64, f64) {
+    if calls.is_empty() {
+        return (0.0, 0.0);
+    }
+    let total = calls.len() as f64;
+    let failed = calls.iter().filter(|c| c.failed).count() as f64;
+    let slow = calls.iter().filter(|c| c.slow).count() as f64;
+    (failed / total, slow / total)
+}
+
+impl<S, B> Transform<S> for CircuitBreaker
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CircuitBreakerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CircuitBreakerMiddleware { service, breaker: self.clone() })
+    }
+}
+
+pub struct CircuitBreakerMiddleware<S> {
+    service: S,
+    breaker: CircuitBreaker,
+}
+
+impl<S, B> Service for CircuitBreakerMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let admission = match self.breaker.admit() {
+            Ok(admission) => admission,
+            // 熔断时不调用处理函数, 直接返回
+            Err(e) => return Box::pin(futures::future::err(e)),
+        };
+
+        let mut call = PendingCall { breaker: self.breaker.clone(), admission: Some(admission) };
+        let start = Instant::now();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await;
+            let failed = match &res {
+                Ok(res) => res.status().is_server_error(),
+                Err(_) => true,
+            };
+            if let Some(admission) = call.admission.take() {
+                call.breaker.record(admission, failed, start.elapsed());
+            }
+            res
+        })
+    }
+}
+
+/// 正在进行的调用, 没有完成就被 drop 时通知熔断器
+struct PendingCall {
+    breaker: CircuitBreaker,
+    admission: Option<Admission>,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        if let Some(admission) = self.admission.take() {
+            self.breaker.cancel(admission);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn is_open(breaker: &CircuitBreaker) -> bool {
+        breaker.snapshot().state == "open"
+    }
+
+    #[test]
+    fn breaker_opens_half_opens_and_closes() {
+        let breaker = CircuitBreaker::new("test")
+            .min_calls(2)
+            .failure_rate(0.5)
+            .open_for(Duration::from_millis(50))
+            .half_open_calls(2);
+        breaker.record(breaker.admit().unwrap(), false, Duration::from_millis(1));
+        assert_eq!(breaker.snapshot().state, "closed");
+        breaker.record(breaker.admit().unwrap(), true, Duration::from_millis(1));
+        assert!(is_open(&breaker));
+        assert!(breaker.admit().is_err());
+
+        std::thread::sleep(Duration::from_millis(60));
+        let first = breaker.admit().unwrap();
+        assert_eq!(breaker.snapshot().state, "half_open");
+        let second = breaker.admit().unwrap();
+        // 试探请求已经足够
+        assert!(breaker.admit().is_err());
+        breaker.record(first, false, Duration::from_millis(1));
+        assert_eq!(breaker.snapshot().state, "half_open");
+        breaker.record(second, false, Duration::from_millis(1));
+        assert_eq!(breaker.snapshot().state, "closed");
+        assert_eq!(breaker.snapshot().rejected_total, 2);
+    }
+
+    #[test]
+    fn failed_or_slow_trial_reopens() {
+        let breaker = CircuitBreaker::new("test")
+            .min_calls(1)
+            .slow_calls(Duration::from_millis(100), 1.0)
+            .open_for(Duration::from_millis(20));
+        breaker.record(breaker.admit().unwrap(), false, Duration::from_millis(200));
+        assert!(is_open(&breaker));
+        std::thread::sleep(Duration::from_millis(30));
+        breaker.record(breaker.admit().unwrap(), true, Duration::from_millis(1));
+        assert!(is_open(&breaker));
+
+        // 取消的试探请求归还名额
+        std::thread::sleep(Duration::from_millis(30));
+        let trial = breaker.admit().unwrap();
+        breaker.cancel(trial);
+        assert!(breaker.admit().is_ok());
+    }
+
+    #[test]
+    fn bulkhead_queues_then_rejects() {
+        actix_web::rt::System::new("bulkhead").block_on(async {
+            let bulkhead = Bulkhead::new("test", 1, 1).queue_timeout(Duration::from_millis(50));
+            let permit = bulkhead.acquire().await.unwrap();
+
+            // 队列中等待超时
+            let error = bulkhead.acquire().await.err().unwrap();
+            assert_eq!(error.as_response_error().status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+            // 队列满了立即拒绝, 释放的名额转交给排队的请求
+            let queued = bulkhead.acquire();
+            let rejected = async {
+                let error = bulkhead.acquire().await.err().unwrap();
+                drop(permit);
+                error
+            };
+            let (queued, rejected) = futures::join!(queued, rejected);
+            assert!(rejected.to_string().contains("queue is full"));
+            assert!(queued.is_ok());
+            let snapshot = bulkhead.snapshot();
+            assert_eq!((snapshot.active, snapshot.rejected_total), (1, 2));
+            drop(queued);
+            assert_eq!(bulkhead.snapshot().active, 0);
+        });
+    }
+
+    #[test]
+    fn reset_requires_the_admin_token() {
+        actix_web::rt::System::new("admin").block_on(async {
+            let breaker = CircuitBreaker::new("flaky").min_calls(1);
+            breaker.record(breaker.admit().unwrap(), true, Duration::from_millis(1));
+            let registry = web::Data::new(Registry { breakers: vec![breaker.clone()], bulkheads: vec![] });
+            let mut app = test::init_service(App::new()
+                .app_data(registry)
+                .configure(|cfg| admin_routes(cfg, Some("secret".to_string())))).await;
+
+            let reset = || test::TestRequest::post().uri("/admin/breakers/flaky/reset");
+            let res = test::call_service(&mut app, reset().to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            let res = test::call_service(&mut app, reset().header(header::AUTHORIZATION, "Bearer wrong").to_request()).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+            assert!(is_open(&breaker));
+
+            let res = test::call_service(&mut app, reset().header(header::AUTHORIZATION, "Bearer secret").to_request()).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(breaker.snapshot().state, "closed");
+        });
+    }
+
+    #[test]
+    fn reset_is_not_registered_without_a_token() {
+        actix_web::rt::System::new("admin").block_on(async {
+            let registry = web::Data::new(Registry { breakers: vec![CircuitBreaker::new("flaky")], bulkheads: vec![] });
+            let mut app = test::init_service(App::new().app_data(registry).configure(|cfg| admin_routes(cfg, None))).await;
+            let req = test::TestRequest::post().uri("/admin/breakers/flaky/reset").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::NOT_FOUND);
+        });
+    }
+}
@@ -0,0 +1,734 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Body, dev::ResponseBody, dev::BodyEncoding, Error,
+                HttpServer, App, HttpRequest, HttpResponse, web, middleware, http::header, http::ContentEncoding,
+                http::HeaderMap, http::HeaderName, http::HeaderValue, http::Method, http::StatusCode};
+use actix_service::{Service, Transform};
+use bytes::Bytes;
+use futures::future::{ok, Ready};
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use env_logger::Env;
+
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// ## 响应缓存 (Response Cache)
+/// 像 `/state/getState`, `/app/getAppInfo` 与 `/br` 这样幂等的 GET 请求, 每次都会重新计算响应. `ResponseCache`
+/// 中间件把响应保存在一个有容量上限的 LRU 中, 相同的请求直接返回保存的响应:
+/// * 缓存键由请求方法, URI 以及响应 `Vary` 头中列出的请求头组成, `Vary: *` 的响应不缓存
+/// * 只缓存带有 `max-age` 或 `s-maxage` 的响应, `no-store`, `no-cache`, `private` 与设置了 cookie 的响应不缓存
+/// * 过期之后在 `stale-while-revalidate` 时间内, 第一个请求去重新获取, 同时其他请求直接返回旧的内容
+/// * 请求带有 `Cache-Control: no-cache` 或 `max-age=0` 时跳过缓存, `no-store` 时响应也不会被保存
+/// * 返回的响应带有 `Age` 与 `X-Cache: HIT/MISS/STALE` 头
+/// * 对同一 URI 成功的 POST/PUT/DELETE 请求会让缓存的 GET/HEAD 响应失效
+///
+/// 缓存只保存内存中的 body, 流式响应不会被缓存. actix-web 的请求不能复制, 无法在后台重放一个请求,
+/// 所以 `stale-while-revalidate` 由遇到过期内容的第一个请求同步完成.
+///
+/// 缓存在所有 worker 之间共享, `GET /admin/cache` 查看缓存内容, `DELETE /admin/cache?route=..` 按路由模式清除,
+/// `DELETE /admin/cache?key=..` 按缓存键(或不含 Vary 部分的 `方法 URI`)清除, 不带参数时清空缓存.
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/state/getState   # X-Cache: MISS
+/// curl -i http://127.0.0.1:8080/state/getState   # X-Cache: HIT, Age: 1
+/// curl -i -H "Accept-Language: zh" http://127.0.0.1:8080/lang
+/// curl http://127.0.0.1:8080/admin/cache
+/// curl -X DELETE "http://127.0.0.1:8080/admin/cache?route=/state/getState"
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let cache = web::Data::new(CacheStore::new(100).max_entry_size(64 * 1024));
+    let counter = web::Data::new(AtomicU64::new(0));
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(cache.clone())
+            .app_data(counter.clone())
+            // 第一个注册的在最内层: 缓存保存的是压缩之前的响应, 每次按客户端的 Accept-Encoding 压缩
+            .wrap(ResponseCache::new(cache.clone().into_inner()))
+            .wrap(middleware::Compress::default())
+            .wrap(middleware::Logger::default())
+            .route("/state/getState", web::get().to(get_state))
+            .route("/state/reset", web::post().to(reset_state))
+            .route("/app/getAppInfo", web::get().to(app_info))
+            .route("/br", web::get().to(index_br))
+            .route("/lang", web::get().to(lang))
+            .route("/me", web::get().to(me))
+            .service(web::resource("/admin/cache")
+                .route(web::get().to(list_cache))
+                .route(web::delete().to(purge_cache)))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 模拟一个计算代价较高的状态, 计数器表示真正计算了多少次
+async fn get_state(counter: web::Data<AtomicU64>) -> HttpResponse {
+    actix_web::rt::time::delay_for(Duration::from_millis(200)).await;
+    let computed = counter.fetch_add(1, Ordering::SeqCst) + 1;
+    HttpResponse::Ok()
+        .set_header(header::CACHE_CONTROL, "max-age=10, stale-while-revalidate=30")
+        .json(serde_json::json!({ "state": "ok", "computed": computed }))
+}
+
+/// 修改状态之后, `/state/getState` 的缓存不会自动失效, 只有对同一 URI 的修改请求才会
+async fn reset_state(counter: web::Data<AtomicU64>) -> HttpResponse {
+    counter.store(0, Ordering::SeqCst);
+    HttpResponse::NoContent().finish()
+}
+
+async fn app_info() -> HttpResponse {
+    HttpResponse::Ok()
+        .set_header(header::CACHE_CONTROL, "public, max-age=60")
+        .body("This is app Info")
+}
+
+async fn index_br() -> HttpResponse {
+    HttpResponse::Ok()
+        .encoding(ContentEncoding::Br)
+        .set_header(header::CACHE_CONTROL, "max-age=30")
+        .body("data")
+}
+
+/// 根据 Accept-Language 返回不同的内容, 每种语言分别缓存
+async fn lang(req: HttpRequest) -> HttpResponse {
+    let accept = req.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    let greeting = if accept.starts_with("zh") { "你好" } else { "hello" };
+    HttpResponse::Ok()
+        .set_header(header::CACHE_CONTROL, "max-age=60")
+        .set_header(header::VARY, "Accept-Language")
+        .body(greeting)
+}
+
+/// 私有的内容只能由浏览器缓存, 共享缓存不会保存
+async fn me() -> HttpResponse {
+    HttpResponse::Ok()
+        .set_header(header::CACHE_CONTROL, "private, max-age=60")
+        .body("my profile")
+}
+
+async fn list_cache(cache: web::Data<CacheStore>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.snapshot())
+}
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    route: Option<String>,
+    key: Option<String>,
+}
+
+async fn purge_cache(cache: web::Data<CacheStore>, query: web::Query<PurgeQuery>) -> HttpResponse {
+    let purged = match (&query.route, &query.key) {
+        (Some(route), _) => cache.purge_route(route),
+        (None, Some(key)) => cache.purge_key(key),
+        (None, None) => cache.clear(),
+    };
+    log::info!("purged {} cache entries", purged);
+    HttpResponse::Ok().json(serde_json::json!({ "purged": purged }))
+}
+
+/// 请求或响应中的 `Cache-Control` 指令
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    public: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_while_revalidate: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut cc = CacheControl::default();
+        let directives = headers.get_all(header::CACHE_CONTROL)
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','));
+        for directive in directives {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let secs = value.and_then(|v| v.parse().ok());
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" => cc.no_store = true,
+                "no-cache" => cc.no_cache = true,
+                "private" => cc.private = true,
+                "public" => cc.public = true,
+                "max-age" => cc.max_age = secs,
+                "s-maxage" => cc.s_maxage = secs,
+                "stale-while-revalidate" => cc.stale_while_revalidate = secs,
+                _ => {}
+            }
+        }
+        cc
+    }
+
+    /// 共享缓存优先使用 `s-maxage`
+    fn freshness(&self) -> Option<Duration> {
+        self.s_maxage.or(self.max_age).filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+}
+
+/// 缓存中的一个响应
+struct Entry {
+    /// 不含 Vary 部分的键, 即 `方法 URI`
+    base: String,
+    route: String,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    encoding: Option<ContentEncoding>,
+    stored: Instant,
+    max_age: Duration,
+    stale_while_revalidate: Duration,
+    revalidating: bool,
+    hits: u64,
+    tick: u64,
+}
+
+impl Entry {
+    fn to_response(&self, cache_status: &'static str) -> HttpResponse {
+        let mut res = HttpResponse::with_body(self.status, Body::from(self.body.clone()));
+        *res.headers_mut() = self.headers.clone();
+        if let Some(encoding) = self.encoding {
+            res.encoding(encoding);
+        }
+        let age = self.stored.elapsed().as_secs();
+        res.headers_mut().insert(header::AGE, HeaderValue::from(age));
+        res.headers_mut().insert(HeaderName::from_static(X_CACHE), HeaderValue::from_static(cache_status));
+        res
+    }
+}
+
+const X_CACHE: &str = "x-cache";
+
+/// 查找的结果
+enum Lookup {
+    Fresh(HttpResponse),
+    /// 过期了但还在 stale-while-revalidate 时间内, 已经有其他请求在重新获取
+    Stale(HttpResponse),
+    /// 没有可用的缓存, 需要调用处理函数
+    Miss,
+    /// 过期了, 由当前请求负责刷新这个键, 刷新完成之前其他请求拿到旧的内容
+    Revalidate(String),
+}
+
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// 最近使用的顺序, 最小的 tick 最久没有使用
+    order: BTreeMap<u64, String>,
+    /// 每个 `方法 URI` 的响应所声明的 Vary 请求头, 以及这个 `方法 URI` 下的条目数量,
+    /// 最后一个条目被移除时一起移除
+    vary: HashMap<String, (Vec<HeaderName>, usize)>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.tick);
+            entry.tick = tick;
+            self.order.insert(tick, key.to_string());
+        }
+    }
+
+    fn insert(&mut self, key: String, mut entry: Entry, vary: Vec<HeaderName>, capacity: usize) {
+        self.remove(&key);
+        while self.entries.len() >= capacity {
+            let oldest = match self.order.iter().next() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            log::debug!("evicting {}", oldest);
+            self.remove(&oldest);
+        }
+        let slot = self.vary.entry(entry.base.clone()).or_insert((Vec::new(), 0));
+        slot.0 = vary;
+        slot.1 += 1;
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(entry.tick, key.clone());
+        self.entries.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.remove(&entry.tick);
+                if let Some((_, count)) = self.vary.get_mut(&entry.base) {
+                    *count -= 1;
+                    if *count == 0 {
+                        self.vary.remove(&entry.base);
+                    }
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove_where<F: Fn(&str, &Entry) -> bool>(&mut self, f: F) -> usize {
+        let keys: Vec<String> = self.entries.iter().filter(|(k, e)| f(k, e)).map(|(k, _)| k.clone()).collect();
+        keys.iter().filter(|key| self.remove(key)).count()
+    }
+}
+
+/// 所有 worker 共享的缓存存储
+pub struct CacheStore {
+    capacity: usize,
+    max_entry_size: usize,
+    inner: Mutex<Lru>,
+}
+
+impl CacheStore {
+    /// 最多保存 `capacity` 个响应
+    pub fn new(capacity: usize) -> Self {
+        CacheStore {
+            capacity: capacity.max(1),
+            max_entry_size: 1024 * 1024,
+            inner: Mutex::new(Lru {
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+                vary: HashMap::new(),
+                tick: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// 超过这个大小的 body 不缓存, 默认 1MB
+    pub fn max_entry_size(mut self, bytes: usize) -> Self {
+        self.max_entry_size = bytes;
+        self
+    }
+
+    fn lookup(&self, base: &str, headers: &HeaderMap) -> Lookup {
+        let mut lru = self.inner.lock().unwrap();
+        let key = match lru.vary.get(base) {
+            Some((names, _)) => variant_key(base, names, headers),
+            None => {
+                lru.misses += 1;
+                return Lookup::Miss;
+            }
+        };
+        lru.touch(&key);
+
+        let lookup = match lru.entries.get_mut(&key) {
+            None => Lookup::Miss,
+            Some(entry) => {
+                let age = entry.stored.elapsed();
+                if age < entry.max_age {
+                    entry.hits += 1;
+                    Lookup::Fresh(entry.to_response("HIT"))
+                } else if age < entry.max_age + entry.stale_while_revalidate && entry.revalidating {
+                    entry.hits += 1;
+                    Lookup::Stale(entry.to_response("STALE"))
+                } else if age < entry.max_age + entry.stale_while_revalidate {
+                    entry.revalidating = true;
+                    Lookup::Revalidate(key.clone())
+                } else {
+                    Lookup::Miss
+                }
+            }
+        };
+        match lookup {
+            Lookup::Miss | Lookup::Revalidate(_) => lru.misses += 1,
+            _ => lru.hits += 1,
+        }
+        lookup
+    }
+
+    /// 保存一个响应. 响应本身不能缓存时清除这个 URI 已有的条目; 只是这个请求不允许保存(`no-store` 或者带认证信息)时
+    /// 不影响其他请求已经缓存的内容
+    fn store(&self, base: String, route: String, req_headers: &HeaderMap, res: &ServiceResponse<Body>) {
+        let request_cc = CacheControl::parse(req_headers);
+        let cc = CacheControl::parse(res.headers());
+        if request_cc.no_store {
+            return;
+        }
+        // 带认证信息的请求, 只有响应明确允许时才能共享
+        if req_headers.contains_key(header::AUTHORIZATION) && !cc.public && cc.s_maxage.is_none() {
+            return;
+        }
+
+        let mut lru = self.inner.lock().unwrap();
+        let (vary, entry) = match self.cacheable(&cc, res) {
+            Some(cacheable) => cacheable,
+            None => {
+                lru.remove_where(|_, entry| entry.base == base);
+                return;
+            }
+        };
+
+        let key = variant_key(&base, &vary, req_headers);
+        let entry = Entry { base, route, ..entry };
+        log::debug!("caching {} for {:?}", key, entry.max_age);
+        lru.insert(key, entry, vary, self.capacity);
+    }
+
+    /// 刷新没有完成(处理函数返回错误或者请求被取消)时, 让之后的请求可以重新负责刷新
+    fn end_revalidation(&self, key: &str) {
+        if let Some(entry) = self.inner.lock().unwrap().entries.get_mut(key) {
+            entry.revalidating = false;
+        }
+    }
+
+    /// 判断一个响应本身能否被共享缓存保存, 可以时返回 Vary 的请求头与缓存条目
+    fn cacheable(&self, cc: &CacheControl, res: &ServiceResponse<Body>) -> Option<(Vec<HeaderName>, Entry)> {
+        if cc.no_store || cc.no_cache || cc.private {
+            return None;
+        }
+        let max_age = cc.freshness()?;
+        if !is_cacheable_status(res.status()) || res.headers().contains_key(header::SET_COOKIE) {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        for value in res.headers().get_all(header::VARY) {
+            for name in value.to_str().ok()?.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                if name == "*" {
+                    return None;
+                }
+                vary.push(HeaderName::from_bytes(name.to_ascii_lowercase().as_bytes()).ok()?);
+            }
+        }
+
+        let body = match res.response().body() {
+            ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => bytes.clone(),
+            ResponseBody::Body(Body::Empty) | ResponseBody::Other(Body::Empty) => Bytes::new(),
+            // 流式的 body 无法提前读取
+            _ => return None,
+        };
+        if body.len() > self.max_entry_size {
+            return None;
+        }
+
+        let mut headers = res.headers().clone();
+        headers.remove(header::AGE);
+        headers.remove(X_CACHE);
+        let entry = Entry {
+            base: String::new(),
+            route: String::new(),
+            status: res.status(),
+            headers,
+            body,
+            encoding: res.response().get_encoding(),
+            stored: Instant::now(),
+            max_age,
+            stale_while_revalidate: Duration::from_secs(cc.stale_while_revalidate.unwrap_or(0)),
+            revalidating: false,
+            hits: 0,
+            tick: 0,
+        };
+        Some((vary, entry))
+    }
+
+    /// 修改类请求成功之后, 同一 URI 的 GET/HEAD 缓存失效
+    fn invalidate_uri(&self, uri: &str) -> usize {
+        let bases = [cache_base(&Method::GET, uri), cache_base(&Method::HEAD, uri)];
+        self.inner.lock().unwrap().remove_where(|_, entry| bases.contains(&entry.base))
+    }
+
+    /// 清除某个路由模式下的所有条目, 比如 `/state/getState`
+    pub fn purge_route(&self, route: &str) -> usize {
+        self.inner.lock().unwrap().remove_where(|_, entry| entry.route == route)
+    }
+
+    /// 清除一个缓存键, 也可以只给出 `方法 URI` 清除它的所有 Vary 变体
+    pub fn purge_key(&self, key: &str) -> usize {
+        self.inner.lock().unwrap().remove_where(|k, entry| k == key || entry.base == key)
+    }
+
+    pub fn clear(&self) -> usize {
+        self.inner.lock().unwrap().remove_where(|_, _| true)
+    }
+
+    fn snapshot(&self) -> CacheSnapshot {
+        let lru = self.inner.lock().unwrap();
+        // 按最近使用的顺序列出
+        let entries = lru.order.values().rev()
+            .filter_map(|key| lru.entries.get(key).map(|entry| (key, entry)))
+            .map(|(key, entry)| EntrySnapshot {
+                key: key.clone(),
+                route: entry.route.clone(),
+                status: entry.status.as_u16(),
+                size: entry.body.len(),
+                age: entry.stored.elapsed().as_secs(),
+                max_age: entry.max_age.as_secs(),
+                stale_while_revalidate: entry.stale_while_revalidate.as_secs(),
+                hits: entry.hits,
+            })
+            .collect();
+        CacheSnapshot { capacity: self.capacity, hits: lru.hits, misses: lru.misses, entries }
+    }
+}
+
+#[derive(Serialize)]
+struct CacheSnapshot {
+    capacity: usize,
+    hits: u64,
+    misses: u64,
+    entries: Vec<EntrySnapshot>,
+}
+
+#[derive(Serialize)]
+struct EntrySnapshot {
+    key: String,
+    route: String,
+    status: u16,
+    size: usize,
+    age: u64,
+    max_age: u64,
+    stale_while_revalidate: u64,
+    hits: u64,
+}
+
+fn is_cacheable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 200 | 203 | 204 | 300 | 301 | 404 | 405 | 410 | 414 | 501)
+}
+
+fn cache_base(method: &Method, uri: &str) -> String {
+    format!("{} {}", method, uri)
+}
+
+/// 在 `方法 URI` 后面加上 Vary 请求头的值, 比如 `GET /lang|accept-language=zh`
+fn variant_key(base: &str, vary: &[HeaderName], headers: &HeaderMap) -> String {
+    let mut key = base.to_string();
+    for name in vary {
+        let values: Vec<&str> = headers.get_all(name).filter_map(|v| v.to_str().ok()).collect();
+        key.push('|');
+        key.push_str(name.as_str());
+        key.push('=');
+        key.push_str(&values.join(", "));
+    }
+    key
+}
+
+/// 响应缓存中间件, 需要包装直接返回 `Body` 的服务, 也就是放在 `Compress` 这类改变 body 的中间件里面
+pub struct ResponseCache {
+    store: Arc<CacheStore>,
+}
+
+impl ResponseCache {
+    pub fn new(store: Arc<CacheStore>) -> Self {
+        ResponseCache { store }
+    }
+}
+
+impl<S> Transform<S> for ResponseCache
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Transform = ResponseCacheMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ResponseCacheMiddleware { service, store: self.store.clone() })
+    }
+}
+
+pub struct ResponseCacheMiddleware<S> {
+    service: S,
+    store: Arc<CacheStore>,
+}
+
+impl<S> Service for ResponseCacheMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<Body>, Error = Error>,
+        S::Future: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let store = self.store.clone();
+        let method = req.method().clone();
+        let uri = req.uri().to_string();
+
+        if method != Method::GET && method != Method::HEAD {
+            let fut = self.service.call(req);
+            return Box::pin(async move {
+                let res = fut.await?;
+                let is_unsafe = !matches!(method, Method::OPTIONS | Method::TRACE);
+                if is_unsafe && (res.status().is_success() || res.status().is_redirection()) {
+                    store.invalidate_uri(&uri);
+                }
+                Ok(res)
+            });
+        }
+
+        let base = cache_base(&method, &uri);
+        let request_cc = CacheControl::parse(req.headers());
+        let mut guard = RevalidationGuard { store: store.clone(), key: None };
+        // 客户端要求跳过缓存
+        if !request_cc.no_cache && request_cc.max_age != Some(0) {
+            match store.lookup(&base, req.headers()) {
+                Lookup::Fresh(res) | Lookup::Stale(res) => {
+                    return Box::pin(ok(req.into_response(res)));
+                }
+                Lookup::Revalidate(key) => guard.key = Some(key),
+                Lookup::Miss => {}
+            }
+        }
+
+        let route = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+        let req_headers = req.headers().clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            store.store(base, route, &req_headers, &res);
+            drop(guard);
+            res.headers_mut().insert(HeaderName::from_static(X_CACHE), HeaderValue::from_static("MISS"));
+            Ok(res)
+        })
+    }
+}
+
+/// 负责刷新的请求结束时清除条目的 `revalidating` 标记, 包括处理函数返回错误以及请求被丢弃的情况
+struct RevalidationGuard {
+    store: Arc<CacheStore>,
+    key: Option<String>,
+}
+
+impl Drop for RevalidationGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.store.end_revalidation(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(HeaderName::from_static(name), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn response(cache_control: &'static str, body: &'static str) -> ServiceResponse<Body> {
+        TestRequest::default().to_srv_response(HttpResponse::Ok().header(header::CACHE_CONTROL, cache_control).body(body))
+    }
+
+    fn entry(base: &str, max_age: u64, stale_while_revalidate: u64, age: u64) -> Entry {
+        Entry {
+            base: base.to_string(),
+            route: String::new(),
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::from_static(b"body"),
+            encoding: None,
+            stored: Instant::now() - Duration::from_secs(age),
+            max_age: Duration::from_secs(max_age),
+            stale_while_revalidate: Duration::from_secs(stale_while_revalidate),
+            revalidating: false,
+            hits: 0,
+            tick: 0,
+        }
+    }
+
+    fn cached_keys(store: &CacheStore) -> Vec<String> {
+        let mut keys: Vec<String> = store.snapshot().entries.into_iter().map(|e| e.key).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn variant_key_includes_vary_headers_in_order() {
+        let vary = [header::ACCEPT_LANGUAGE, header::ACCEPT_ENCODING];
+        let req = headers(&[("accept-encoding", "gzip"), ("accept-language", "zh")]);
+        assert_eq!(variant_key("GET /lang", &vary, &req), "GET /lang|accept-language=zh|accept-encoding=gzip");
+        assert_eq!(variant_key("GET /lang", &vary, &HeaderMap::new()), "GET /lang|accept-language=|accept-encoding=");
+        assert_eq!(variant_key("GET /lang", &[], &req), "GET /lang");
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_entry() {
+        let store = CacheStore::new(2);
+        {
+            let mut lru = store.inner.lock().unwrap();
+            lru.insert("GET /a".to_string(), entry("GET /a", 60, 0, 0), Vec::new(), 2);
+            lru.insert("GET /b".to_string(), entry("GET /b", 60, 0, 0), Vec::new(), 2);
+            lru.touch("GET /a");
+            lru.insert("GET /c".to_string(), entry("GET /c", 60, 0, 0), Vec::new(), 2);
+            // vary 信息随着最后一个条目一起移除
+            assert!(!lru.vary.contains_key("GET /b"));
+        }
+        assert_eq!(cached_keys(&store), vec!["GET /a", "GET /c"]);
+    }
+
+    #[test]
+    fn lookup_moves_from_fresh_to_stale_to_miss() {
+        let store = CacheStore::new(10);
+        let insert = |age: u64| {
+            store.inner.lock().unwrap().insert("GET /s".to_string(), entry("GET /s", 10, 20, age), Vec::new(), 10);
+        };
+
+        insert(0);
+        assert!(matches!(store.lookup("GET /s", &HeaderMap::new()), Lookup::Fresh(_)));
+
+        // 过期之后第一个请求负责刷新, 其他请求拿到旧的内容
+        insert(15);
+        assert!(matches!(store.lookup("GET /s", &HeaderMap::new()), Lookup::Revalidate(ref key) if key == "GET /s"));
+        assert!(matches!(store.lookup("GET /s", &HeaderMap::new()), Lookup::Stale(_)));
+        // 刷新没有完成时, 下一个请求重新负责刷新
+        store.end_revalidation("GET /s");
+        assert!(matches!(store.lookup("GET /s", &HeaderMap::new()), Lookup::Revalidate(_)));
+
+        insert(40);
+        assert!(matches!(store.lookup("GET /s", &HeaderMap::new()), Lookup::Miss));
+        assert!(matches!(store.lookup("GET /other", &HeaderMap::new()), Lookup::Miss));
+    }
+
+    #[test]
+    fn only_uncacheable_responses_purge_existing_entries() {
+        let store = CacheStore::new(10);
+        let store_response = |req: HeaderMap, cache_control| {
+            store.store("GET /s".to_string(), "/s".to_string(), &req, &response(cache_control, "body"));
+        };
+
+        store_response(HeaderMap::new(), "max-age=60");
+        assert_eq!(cached_keys(&store), vec!["GET /s"]);
+
+        // 请求不允许保存时, 已有的条目保留
+        store_response(headers(&[("cache-control", "no-store")]), "no-store");
+        store_response(headers(&[("authorization", "Bearer x")]), "max-age=60");
+        assert_eq!(cached_keys(&store), vec!["GET /s"]);
+
+        // 响应本身不能缓存时清除
+        store_response(HeaderMap::new(), "no-store");
+        assert!(cached_keys(&store).is_empty());
+
+        // 响应明确允许共享时, 带认证信息的请求也可以保存
+        store_response(headers(&[("authorization", "Bearer x")]), "public, max-age=60");
+        assert_eq!(cached_keys(&store), vec!["GET /s"]);
+    }
+}
@@ -0,0 +1,493 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, dev::RequestHead, Error, FromRequest,
+                HttpMessage, HttpRequest, HttpServer, App, HttpResponse, ResponseError, web, guard, middleware, error,
+                http::header, http::HeaderMap, http::HeaderName, http::HeaderValue, http::StatusCode};
+use actix_service::{Service, Transform};
+use futures::future::{ok, ready, Ready};
+use futures::Future;
+use rand::Rng;
+use serde_json::Value;
+use env_logger::Env;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// ## 安全响应头 (Security Headers)
+/// `DefaultHeaders` 只能添加固定的响应头. `SecurityHeaders` 中间件按一组合理的默认值添加常用的安全响应头:
+/// * `Strict-Transport-Security` - 只在 https 请求上添加(经过代理时根据 `X-Forwarded-Proto` 判断)
+/// * `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY`, `Referrer-Policy`, `Permissions-Policy`
+/// * `Content-Security-Policy` - 策略中的 `{nonce}` 会被替换成每个请求随机生成的 nonce, 处理函数通过 `CspNonce`
+///   提取器拿到同一个值, 写到内联 `<script nonce="..">` 中. `report_only()` 改为输出
+///   `Content-Security-Policy-Report-Only`, 只报告不拦截, 方便在上线新的策略之前观察
+///
+/// 处理函数自己设置了的响应头不会被覆盖, 内层中间件(比如认证)返回错误时生成的错误响应同样带有这些响应头.
+/// `report_uri()` 让浏览器把违反策略的报告发送到 `/csp-report`,
+/// 这里同时支持旧的 `application/csp-report` 格式与 Reporting API 的 `application/reports+json` 格式,
+/// 收到的报告会输出到日志. 报告中有用户访问的地址, 最近的100条只有设置了 `ADMIN_TOKEN` 环境变量时才可以通过
+/// `GET /csp-report` 查看, 请求需要带上 `Authorization: Bearer <ADMIN_TOKEN>`, 否则返回 404.
+///
+/// 设置环境变量 `CSP_REPORT_ONLY=1` 使用 report-only 模式.
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/
+/// curl -i -H "X-Forwarded-Proto: https" http://127.0.0.1:8080/      # 带有 HSTS
+/// curl -i -H "Content-Type: application/csp-report" \
+///      -d '{"csp-report":{"document-uri":"http://127.0.0.1:8080/","violated-directive":"script-src-elem","blocked-uri":"inline"}}' \
+///      http://127.0.0.1:8080/csp-report
+/// curl -H "Authorization: Bearer $ADMIN_TOKEN" http://127.0.0.1:8080/csp-report
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    let report_only = std::env::var("CSP_REPORT_ONLY").is_ok_and(|v| v == "1");
+    let reports = web::Data::new(CspReports::new(100));
+    let admin_token = std::env::var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+    if admin_token.is_none() {
+        log::warn!("ADMIN_TOKEN is not set, GET /csp-report is disabled");
+    }
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(reports.clone())
+            .wrap(SecurityHeaders::new()
+                .hsts(Some(Duration::from_secs(2 * 365 * 24 * 60 * 60)), true)
+                .referrer_policy(Some("no-referrer"))
+                .permissions_policy(Some("camera=(), microphone=(), geolocation=(self)"))
+                .csp("default-src 'self'; script-src 'self' 'nonce-{nonce}'; object-src 'none'; base-uri 'none'; frame-ancestors 'none'")
+                .report_uri("/csp-report")
+                .report_only(report_only))
+            .wrap(middleware::Logger::default())
+            .route("/", web::get().to(index))
+            // 这个页面允许被同源的页面嵌入, 自己设置的响应头不会被中间件覆盖
+            .route("/embed", web::get().to(|| HttpResponse::Ok()
+                .set_header(header::X_FRAME_OPTIONS, "SAMEORIGIN")
+                .set_header(header::CONTENT_SECURITY_POLICY, "default-src 'self'; frame-ancestors 'self'")
+                .body("embeddable")))
+            .configure(|cfg| report_routes(cfg, admin_token.clone()))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 接收报告的接口, 查看报告需要管理员令牌, 没有配置令牌时不注册
+fn report_routes(cfg: &mut web::ServiceConfig, admin_token: Option<String>) {
+    let mut resource = web::resource("/csp-report")
+        // 报告一般都很小, 限制 body 大小
+        .app_data(web::PayloadConfig::new(16 * 1024))
+        .route(web::post().to(collect_report));
+    if let Some(token) = admin_token {
+        let expected = format!("Bearer {}", token);
+        resource = resource.route(web::get()
+            .guard(guard::fn_guard(move |head: &RequestHead| {
+                head.headers().get(header::AUTHORIZATION)
+                    .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()))
+            }))
+            .to(list_reports));
+    }
+    cfg.service(resource);
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 带有 nonce 的内联脚本可以执行, 另一个会被浏览器拦截并报告
+async fn index(nonce: CspNonce) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(format!(
+        r#"<!doctype html>
+<html><body>
+<p id="ok">waiting</p>
+<script nonce="{}">document.getElementById("ok").textContent = "inline script with nonce ran";</script>
+<script>document.body.append("inline script without nonce ran");</script>
+</body></html>"#,
+        nonce
+    ))
+}
+
+/// 最近收到的 CSP 报告, 所有 worker 共享
+struct CspReports {
+    capacity: usize,
+    reports: Mutex<VecDeque<Value>>,
+}
+
+impl CspReports {
+    fn new(capacity: usize) -> Self {
+        CspReports { capacity, reports: Mutex::new(VecDeque::new()) }
+    }
+
+    fn push(&self, report: Value) {
+        let mut reports = self.reports.lock().unwrap();
+        if reports.len() == self.capacity {
+            reports.pop_front();
+        }
+        reports.push_back(report);
+    }
+}
+
+/// 接收浏览器发送的报告, 两种格式统一成 Reporting API 中 `body` 的形式
+async fn collect_report(req: HttpRequest, body: web::Bytes, reports: web::Data<CspReports>) -> Result<HttpResponse, Error> {
+    let content_type = req.content_type().to_ascii_lowercase();
+    let payload: Value = serde_json::from_slice(&body).map_err(error::ErrorBadRequest)?;
+
+    let violations: Vec<Value> = match content_type.as_str() {
+        // 旧格式: {"csp-report": {...}}
+        "application/csp-report" | "application/json" => payload.get("csp-report").cloned().into_iter().collect(),
+        // Reporting API: [{"type": "csp-violation", "body": {...}}, ...]
+        "application/reports+json" => payload.as_array()
+            .map(|items| items.iter()
+                .filter(|item| item.get("type").and_then(Value::as_str) == Some("csp-violation"))
+                .filter_map(|item| item.get("body").cloned())
+                .collect())
+            .unwrap_or_default(),
+        _ => return Ok(HttpResponse::UnsupportedMediaType().finish()),
+    };
+    if violations.is_empty() {
+        return Err(error::ErrorBadRequest("no csp violation in report"));
+    }
+
+    for violation in violations {
+        let field = |names: &[&str]| names.iter()
+            .find_map(|name| violation.get(*name).and_then(Value::as_str))
+            .unwrap_or("-")
+            .to_string();
+        log::warn!("csp violation on {}: {} blocked {}",
+                   field(&["document-uri", "documentURL"]),
+                   field(&["effective-directive", "effectiveDirective", "violated-directive"]),
+                   field(&["blocked-uri", "blockedURL"]));
+        reports.push(violation);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+async fn list_reports(reports: web::Data<CspReports>) -> HttpResponse {
+    HttpResponse::Ok().json(&*reports.reports.lock().unwrap())
+}
+
+/// 当前请求的 CSP nonce
+#[derive(Clone)]
+pub struct CspNonce(Rc<str>);
+
+impl fmt::Display for CspNonce {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromRequest for CspNonce {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<CspNonce>().cloned()
+            .ok_or_else(|| error::ErrorInternalServerError("SecurityHeaders middleware is not registered")))
+    }
+}
+
+const REPORT_GROUP: &str = "csp-endpoint";
+
+/// 安全响应头中间件
+#[derive(Clone)]
+pub struct SecurityHeaders {
+    hsts: Option<String>,
+    frame_options: Option<String>,
+    referrer_policy: Option<String>,
+    permissions_policy: Option<String>,
+    csp: Option<String>,
+    report_uri: Option<String>,
+    report_only: bool,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        SecurityHeaders::new()
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        SecurityHeaders {
+            hsts: Some("max-age=31536000; includeSubDomains".to_string()),
+            frame_options: Some("DENY".to_string()),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), microphone=(), geolocation=(), payment=()".to_string()),
+            csp: Some("default-src 'self'; object-src 'none'; base-uri 'none'; frame-ancestors 'none'".to_string()),
+            report_uri: None,
+            report_only: false,
+        }
+    }
+
+    /// HSTS 的有效期, `None` 表示不输出
+    pub fn hsts(mut self, max_age: Option<Duration>, include_subdomains: bool) -> Self {
+        self.hsts = max_age.map(|age| {
+            let mut value = format!("max-age={}", age.as_secs());
+            if include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            value
+        });
+        self
+    }
+
+    /// `X-Frame-Options`, 比如 `DENY` 或 `SAMEORIGIN`
+    #[allow(dead_code)] // 示例中使用默认值
+    pub fn frame_options(mut self, value: Option<&str>) -> Self {
+        self.frame_options = value.map(str::to_string);
+        self
+    }
+
+    pub fn referrer_policy(mut self, value: Option<&str>) -> Self {
+        self.referrer_policy = value.map(str::to_string);
+        self
+    }
+
+    pub fn permissions_policy(mut self, value: Option<&str>) -> Self {
+        self.permissions_policy = value.map(str::to_string);
+        self
+    }
+
+    /// CSP 策略, 其中的 `{nonce}` 会被替换成每个请求的 nonce
+    pub fn csp(mut self, policy: &str) -> Self {
+        self.csp = Some(policy.to_string());
+        self
+    }
+
+    /// 违反策略时浏览器发送报告的地址
+    pub fn report_uri(mut self, uri: &str) -> Self {
+        self.report_uri = Some(uri.to_string());
+        self
+    }
+
+    /// 只报告, 不拦截
+    pub fn report_only(mut self, report_only: bool) -> Self {
+        self.report_only = report_only;
+        self
+    }
+
+    /// 添加到响应中的头, 已经存在的不覆盖
+    fn headers(&self, nonce: &str, https: bool) -> Vec<(HeaderName, String)> {
+        let mut headers = vec![(header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string())];
+        if let (Some(hsts), true) = (&self.hsts, https) {
+            headers.push((header::STRICT_TRANSPORT_SECURITY, hsts.clone()));
+        }
+        if let Some(value) = &self.frame_options {
+            headers.push((header::X_FRAME_OPTIONS, value.clone()));
+        }
+        if let Some(value) = &self.referrer_policy {
+            headers.push((header::REFERRER_POLICY, value.clone()));
+        }
+        if let Some(value) = &self.permissions_policy {
+            headers.push((HeaderName::from_static("permissions-policy"), value.clone()));
+        }
+        if let Some(policy) = &self.csp {
+            let mut policy = policy.replace("{nonce}", nonce);
+            if let Some(uri) = &self.report_uri {
+                policy.push_str(&format!("; report-uri {}; report-to {}", uri, REPORT_GROUP));
+                headers.push((HeaderName::from_static("reporting-endpoints"), format!("{}=\"{}\"", REPORT_GROUP, uri)));
+            }
+            let name = if self.report_only {
+                header::CONTENT_SECURITY_POLICY_REPORT_ONLY
+            } else {
+                header::CONTENT_SECURITY_POLICY
+            };
+            headers.push((name, policy));
+        }
+        headers
+    }
+}
+
+/// 添加响应中还没有的头
+fn add_headers(headers: &mut HeaderMap, values: Vec<(HeaderName, String)>) {
+    for (name, value) in values {
+        if !headers.contains_key(&name) {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                headers.insert(name, value);
+            }
+        }
+    }
+}
+
+/// 带有安全响应头的错误
+#[derive(Debug)]
+struct SecuredError {
+    error: Error,
+    headers: Vec<(HeaderName, String)>,
+}
+
+impl fmt::Display for SecuredError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl ResponseError for SecuredError {
+    fn status_code(&self) -> StatusCode {
+        self.error.as_response_error().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut res = self.error.as_response_error().error_response();
+        add_headers(res.headers_mut(), self.headers.clone());
+        res
+    }
+}
+
+impl<S, B> Transform<S> for SecurityHeaders
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = SecurityHeadersMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(SecurityHeadersMiddleware { service, config: Rc::new(self.clone()) })
+    }
+}
+
+pub struct SecurityHeadersMiddleware<S> {
+    service: S,
+    config: Rc<SecurityHeaders>,
+}
+
+impl<S, B> Service for SecurityHeadersMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let bytes: [u8; 16] = rand::thread_rng().gen();
+        let nonce: Rc<str> = base64::encode(bytes).into();
+        req.extensions_mut().insert(CspNonce(nonce.clone()));
+        let https = req.connection_info().scheme() == "https";
+
+        let config = self.config.clone();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let headers = config.headers(&nonce, https);
+            match fut.await {
+                Ok(mut res) => {
+                    add_headers(res.headers_mut(), headers);
+                    Ok(res)
+                }
+                // 内层中间件返回的错误在外层才会生成响应, 包装一层让错误响应也带上这些响应头
+                Err(e) => Err(SecuredError { error: e, headers }.into()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn find<'a>(headers: &'a [(HeaderName, String)], name: &str) -> Option<&'a str> {
+        headers.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn substitutes_the_nonce_and_adds_the_report_endpoint() {
+        let config = SecurityHeaders::new().csp("script-src 'nonce-{nonce}'").report_uri("/csp-report");
+        let headers = config.headers("abc", false);
+        assert_eq!(find(&headers, "content-security-policy"),
+                   Some("script-src 'nonce-abc'; report-uri /csp-report; report-to csp-endpoint"));
+        assert_eq!(find(&headers, "reporting-endpoints"), Some("csp-endpoint=\"/csp-report\""));
+        assert_eq!(find(&headers, "content-security-policy-report-only"), None);
+        // 只在 https 上输出 HSTS
+        assert_eq!(find(&headers, "strict-transport-security"), None);
+        assert!(find(&config.headers("abc", true), "strict-transport-security").is_some());
+    }
+
+    #[test]
+    fn report_only_mode_uses_the_report_only_header() {
+        let headers = SecurityHeaders::new().csp("default-src 'self'").report_only(true).headers("abc", false);
+        assert_eq!(find(&headers, "content-security-policy"), None);
+        assert_eq!(find(&headers, "content-security-policy-report-only"), Some("default-src 'self'"));
+    }
+
+    #[test]
+    fn keeps_handler_headers_and_covers_errors() {
+        actix_web::rt::System::new("headers").block_on(async {
+            let mut app = test::init_service(App::new()
+                // 内层中间件返回错误
+                .wrap_fn(|req, srv| {
+                    let rejected = req.path() == "/rejected";
+                    let fut = srv.call(req);
+                    async move {
+                        if rejected {
+                            return Err(error::ErrorForbidden("rejected"));
+                        }
+                        fut.await
+                    }
+                })
+                .wrap(SecurityHeaders::new().csp("script-src 'nonce-{nonce}'"))
+                .route("/", web::get().to(|nonce: CspNonce| HttpResponse::Ok().body(nonce.to_string())))
+                .route("/embed", web::get().to(|| HttpResponse::Ok()
+                    .set_header(header::X_FRAME_OPTIONS, "SAMEORIGIN")
+                    .finish()))
+                .route("/error", web::get().to(|| async { Err::<HttpResponse, _>(error::ErrorBadRequest("bad")) }))).await;
+
+            let res = test::call_service(&mut app, test::TestRequest::get().uri("/").to_request()).await;
+            let csp = res.headers().get(header::CONTENT_SECURITY_POLICY).unwrap().to_str().unwrap().to_string();
+            let nonce = test::read_body(res).await;
+            assert_eq!(csp, format!("script-src 'nonce-{}'", std::str::from_utf8(&nonce).unwrap()));
+
+            let res = test::call_service(&mut app, test::TestRequest::get().uri("/embed").to_request()).await;
+            assert_eq!(res.headers().get(header::X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+
+            let res = test::call_service(&mut app, test::TestRequest::get().uri("/error").to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(res.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+            assert_eq!(res.headers().get(header::X_FRAME_OPTIONS).unwrap(), "DENY");
+
+            let error = app.call(test::TestRequest::get().uri("/rejected").to_request()).await.err().unwrap();
+            let res = error.as_response_error().error_response();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            assert_eq!(res.headers().get(header::X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+            assert!(res.headers().contains_key(header::CONTENT_SECURITY_POLICY));
+        });
+    }
+
+    #[test]
+    fn listing_reports_requires_the_admin_token() {
+        actix_web::rt::System::new("reports").block_on(async {
+            let reports = web::Data::new(CspReports::new(10));
+            let mut app = test::init_service(App::new()
+                .app_data(reports.clone())
+                .configure(|cfg| report_routes(cfg, Some("secret".to_string())))).await;
+            let req = test::TestRequest::post().uri("/csp-report")
+                .header(header::CONTENT_TYPE, "application/csp-report")
+                .set_payload(r#"{"csp-report":{"blocked-uri":"inline"}}"#)
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::NO_CONTENT);
+
+            let req = test::TestRequest::get().uri("/csp-report").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::METHOD_NOT_ALLOWED);
+            let req = test::TestRequest::get().uri("/csp-report").header(header::AUTHORIZATION, "Bearer secret").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(test::read_body(res).await, r#"[{"blocked-uri":"inline"}]"#);
+        });
+    }
+}
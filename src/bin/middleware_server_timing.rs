@@ -0,0 +1,391 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, dev::MessageBody, dev::BodySize,
+                dev::ResponseBody, Error, HttpServer, App, HttpRequest, HttpResponse, HttpMessage, FromRequest, web,
+                http::HeaderValue, middleware::Logger};
+use actix_service::{Service, Transform};
+use bytes::Bytes;
+use futures::future::{ok, Ready, LocalBoxFuture};
+use futures::Future;
+use env_logger::Env;
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// ## 服务器计时 (Server-Timing)
+/// `middleware.rs` 中的 `SayHi` 只是打印了两行日志. 这里用同样的 Transform/Service 结构实现一个计时中间件,
+/// 把每个阶段的耗时通过 [Server-Timing](https://www.w3.org/TR/server-timing/) 响应头返回,
+/// 浏览器开发者工具的 Network -> Timing 面板可以直接显示这些数据.
+///
+/// 记录的阶段:
+/// * `mw` - 请求与响应经过中间件链的耗时, 需要在最里层注册 `ServerTiming::dispatch()` 标记请求到达路由的时间
+/// * `extract` - 提取器的耗时, 用 `Timed<T>` 包装需要计时的提取器(比如 `Timed<web::Json<T>>`)
+/// * `handler` - 处理函数本身的耗时
+/// * `total` - 从进入 `ServerTiming` 到响应头生成的总耗时
+/// * 处理函数通过 `Timing` 提取器添加的自定义指标, 比如 `db;dur=15.2;desc="select user"`
+///
+/// 响应头在 body 之前发送, 所以 body 的发送耗时(`body`)无法出现在响应头中, 只在 body 发送完成后输出到日志.
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/users/1
+/// curl -i -H "Content-Type: application/json" -d '{"name":"dsl"}' http://127.0.0.1:8080/users
+/// curl -i http://127.0.0.1:8080/report
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    HttpServer::new(|| {
+        // 最后注册的中间件在最外层: ServerTiming -> Logger -> dispatch 标记 -> 路由
+        App::new().wrap(ServerTiming::dispatch())
+            .wrap(Logger::default())
+            .wrap(ServerTiming::new().timing_allow_origin("*"))
+            .route("/users/{id}", web::get().to(user))
+            .route("/users", web::post().to(create_user))
+            .route("/report", web::get().to(report))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+async fn user(id: Timed<web::Path<u32>>, timing: Timing) -> String {
+    let id = id.into_inner().into_inner();
+
+    // 计时器 drop 时记录一个指标
+    let _db = timing.start_with_desc("db", "select user");
+    actix_web::rt::time::delay_for(Duration::from_millis(15)).await;
+    format!("user {}", id)
+}
+
+async fn create_user(user: Timed<web::Json<serde_json::Value>>, timing: Timing) -> HttpResponse {
+    let started = Instant::now();
+    let user = user.into_inner().into_inner();
+    timing.record("validate", started.elapsed());
+    HttpResponse::Created().json(user)
+}
+
+/// 分多块返回的响应, body 的发送耗时可以在日志中看到
+async fn report() -> HttpResponse {
+    let chunks = (0..5).map(|i| Ok::<_, Error>(Bytes::from(format!("line {}\n", i))));
+    HttpResponse::Ok().streaming(futures::stream::iter(chunks))
+}
+
+/// 一个指标: 名称, 耗时和可选的描述
+struct Metric {
+    name: String,
+    duration: Duration,
+    desc: Option<String>,
+}
+
+/// 一个请求的计时数据, 保存在请求的 extensions 中
+struct Timings {
+    start: Instant,
+    /// 请求经过中间件链到达路由的时间
+    dispatched: Option<Instant>,
+    /// 路由返回响应的时间
+    handled: Option<Instant>,
+    extract: Duration,
+    metrics: Vec<Metric>,
+}
+
+type SharedTimings = Rc<RefCell<Timings>>;
+
+fn shared_timings(req: &HttpRequest) -> Option<SharedTimings> {
+    req.extensions().get::<SharedTimings>().cloned()
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// 指标名称必须是一个 token, 不合法的字符替换成 `_`
+fn metric_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c) { c } else { '_' })
+        .collect()
+}
+
+fn push_metric(header: &mut String, name: &str, duration: Duration, desc: Option<&str>) {
+    if !header.is_empty() {
+        header.push_str(", ");
+    }
+    let _ = write!(header, "{};dur={:.3}", metric_name(name), millis(duration));
+    if let Some(desc) = desc {
+        let desc = desc.replace('\\', "\\\\").replace('"', "\\\"");
+        let _ = write!(header, ";desc=\"{}\"", desc);
+    }
+}
+
+impl Timings {
+    fn new() -> Self {
+        Timings { start: Instant::now(), dispatched: None, handled: None, extract: Duration::default(), metrics: Vec::new() }
+    }
+
+    /// 生成 Server-Timing 响应头的值
+    fn header_value(&self, total: Duration) -> String {
+        let mut header = String::new();
+        if let (Some(dispatched), Some(handled)) = (self.dispatched, self.handled) {
+            let routed = handled - dispatched;
+            push_metric(&mut header, "mw", total.checked_sub(routed).unwrap_or_default(), None);
+            push_metric(&mut header, "extract", self.extract, None);
+            push_metric(&mut header, "handler", routed.checked_sub(self.extract).unwrap_or_default(), None);
+        } else if self.extract > Duration::default() {
+            push_metric(&mut header, "extract", self.extract, None);
+        }
+        for metric in &self.metrics {
+            push_metric(&mut header, &metric.name, metric.duration, metric.desc.as_deref());
+        }
+        push_metric(&mut header, "total", total, None);
+        header
+    }
+}
+
+/// 处理函数添加自定义指标的提取器. 没有注册 `ServerTiming` 中间件时所有操作都会被忽略.
+pub struct Timing(Option<SharedTimings>);
+
+impl Timing {
+    /// 记录一个已经测量好的耗时
+    pub fn record(&self, name: &str, duration: Duration) {
+        self.push(name, duration, None);
+    }
+
+    /// 记录一个带描述的耗时
+    pub fn record_with_desc(&self, name: &str, duration: Duration, desc: &str) {
+        self.push(name, duration, Some(desc.to_string()));
+    }
+
+    /// 开始一个计时器, 计时器 drop 或者调用 `stop()` 时记录耗时
+    pub fn start(&self, name: &str) -> Timer {
+        Timer { timings: self.0.clone(), name: name.to_string(), desc: None, start: Instant::now() }
+    }
+
+    pub fn start_with_desc(&self, name: &str, desc: &str) -> Timer {
+        let mut timer = self.start(name);
+        timer.desc = Some(desc.to_string());
+        timer
+    }
+
+    fn push(&self, name: &str, duration: Duration, desc: Option<String>) {
+        if let Some(timings) = &self.0 {
+            timings.borrow_mut().metrics.push(Metric { name: name.to_string(), duration, desc });
+        }
+    }
+}
+
+impl FromRequest for Timing {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ok(Timing(shared_timings(req)))
+    }
+}
+
+/// `Timing::start()` 返回的计时器
+pub struct Timer {
+    timings: Option<SharedTimings>,
+    name: String,
+    desc: Option<String>,
+    start: Instant,
+}
+
+impl Timer {
+    pub fn stop(self) {}
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(timings) = &self.timings {
+            timings.borrow_mut().metrics.push(Metric {
+                name: std::mem::take(&mut self.name),
+                duration: self.start.elapsed(),
+                desc: self.desc.take(),
+            });
+        }
+    }
+}
+
+/// 记录提取器的耗时, 用法: `Timed<web::Json<T>>`. 多个 `Timed` 提取器的耗时会累加到 `extract` 中.
+pub struct Timed<T>(T);
+
+impl<T> Timed<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> FromRequest for Timed<T>
+    where
+        T: FromRequest + 'static,
+{
+    type Error = T::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = T::Config;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let timings = shared_timings(req);
+        let start = Instant::now();
+        let fut = T::from_request(req, payload);
+        Box::pin(async move {
+            let result = fut.await;
+            if let Some(timings) = timings {
+                timings.borrow_mut().extract += start.elapsed();
+            }
+            result.map(Timed)
+        })
+    }
+}
+
+/// 计时中间件. `ServerTiming::new()` 注册在最外层, `ServerTiming::dispatch()` 注册在最里层.
+#[derive(Clone, Default)]
+pub struct ServerTiming {
+    /// `Timing-Allow-Origin` 响应头, 跨域请求时浏览器需要它才会显示计时数据
+    allow_origin: Option<String>,
+    /// 是否是最里层的标记
+    dispatch: bool,
+}
+
+impl ServerTiming {
+    pub fn new() -> Self {
+        ServerTiming::default()
+    }
+
+    /// 标记请求经过中间件链到达路由的时间, 需要注册在最里层
+    pub fn dispatch() -> Self {
+        ServerTiming { dispatch: true, ..ServerTiming::default() }
+    }
+
+    pub fn timing_allow_origin(mut self, origin: &str) -> Self {
+        self.allow_origin = Some(origin.to_string());
+        self
+    }
+}
+
+impl<S, B> Transform<S> for ServerTiming
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<TimedBody<B>>;
+    type Error = Error;
+    type Transform = ServerTimingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(ServerTimingMiddleware { service, config: Rc::new(self.clone()) })
+    }
+}
+
+pub struct ServerTimingMiddleware<S> {
+    service: S,
+    config: Rc<ServerTiming>,
+}
+
+impl<S, B> Service for ServerTimingMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<TimedBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        if self.config.dispatch {
+            return self.dispatch(req);
+        }
+
+        let timings: SharedTimings = Rc::new(RefCell::new(Timings::new()));
+        req.extensions_mut().insert(timings.clone());
+        let config = self.config.clone();
+        let request_line = format!("{} {}", req.method(), req.path());
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+
+            let timings = timings.borrow();
+            let total = timings.start.elapsed();
+            let header = timings.header_value(total);
+            if let Ok(value) = HeaderValue::from_str(&header) {
+                res.headers_mut().insert("server-timing".parse().unwrap(), value);
+            }
+            if let Some(origin) = config.allow_origin.as_deref().and_then(|o| HeaderValue::from_str(o).ok()) {
+                res.headers_mut().insert("timing-allow-origin".parse().unwrap(), origin);
+            }
+
+            let log = Some(BodyLog { request_line, header, headers_sent: Instant::now(), total });
+            Ok(res.map_body(move |_, body| ResponseBody::Body(TimedBody { body: Box::pin(body), log })))
+        })
+    }
+}
+
+impl<S, B> ServerTimingMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: MessageBody + 'static,
+{
+    /// 最里层的标记只记录时间, 不改动响应
+    fn dispatch(&mut self, req: ServiceRequest) -> <Self as Service>::Future {
+        let timings = req.extensions().get::<SharedTimings>().cloned();
+        if let Some(timings) = &timings {
+            timings.borrow_mut().dispatched = Some(Instant::now());
+        }
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let res = fut.await?;
+            if let Some(timings) = &timings {
+                timings.borrow_mut().handled = Some(Instant::now());
+            }
+            Ok(res.map_body(|_, body| ResponseBody::Body(TimedBody { body: Box::pin(body), log: None })))
+        })
+    }
+}
+
+/// body 发送完成后输出到日志的数据
+struct BodyLog {
+    request_line: String,
+    header: String,
+    headers_sent: Instant,
+    total: Duration,
+}
+
+/// 包装响应 body, 记录 body 的发送耗时
+pub struct TimedBody<B> {
+    body: Pin<Box<ResponseBody<B>>>,
+    log: Option<BodyLog>,
+}
+
+impl<B: MessageBody> MessageBody for TimedBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Error>>> {
+        self.body.as_mut().poll_next(cx)
+    }
+}
+
+/// body 发送完毕或者客户端断开后 body 被 drop, 此时输出包括 body 耗时在内的完整计时
+impl<B> Drop for TimedBody<B> {
+    fn drop(&mut self) {
+        if let Some(log) = self.log.take() {
+            let body = log.headers_sent.elapsed();
+            log::info!("{} server-timing: {}, body;dur={:.3}, total+body;dur={:.3}",
+                       log.request_line, log.header, millis(body), millis(log.total + body));
+        }
+    }
+}
@@ -1,13 +1,58 @@
-use actix_web::{HttpServer, App, HttpResponse, Error, get};
-use actix_session::{Session, CookieSession};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, error::PayloadError, Error, FromRequest,
+                HttpMessage, HttpRequest, HttpServer, App, HttpResponse, ResponseError, web, get, post,
+                http::header, http::Method, http::StatusCode, http::Cookie};
+use actix_service::{Service, Transform};
+use actix_session::{Session, CookieSession, UserSession};
+use bytes::BytesMut;
+use derive_more::Display;
+use futures::future::{ok, ready, Ready};
+use futures::{Future, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::Sha256;
 
+use std::cell::RefCell;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
 
+/// ## Session 与 CSRF 防护
+/// `CookieSession` 把 session 保存在签名的 cookie 中, 浏览器会在其他网站发起的请求中自动带上这个 cookie,
+/// 所以修改状态的请求需要 CSRF 防护. `Csrf` 中间件支持两种方式:
+/// * `Csrf::session()` - 同步令牌(synchronizer token), 令牌保存在 session 中, 需要放在 `CookieSession` 里面
+/// * `Csrf::double_submit(key)` - 签名的双重提交 cookie, 令牌签名后放在 `csrf_token` cookie 中. session 中只保存一个随机的
+///   session 标识, 签名覆盖这个标识, 所以攻击者在自己的 session 中拿到的令牌与 cookie 不能用于其他用户的 session.
+///   同样需要放在 `CookieSession` 里面
+///
+/// 对 POST/PUT/PATCH/DELETE 请求, 中间件从 `X-CSRF-Token` 头或者表单的 `csrf_token` 字段中取出令牌,
+/// 与 session 或 cookie 中的令牌比较, 缺少或者不一致时返回 403. 处理函数通过 `CsrfToken` 提取器拿到令牌,
+/// 渲染到表单的隐藏字段中, 或者交给前端放到请求头中.
+///
+/// 设置环境变量 `CSRF_MODE=double-submit` 使用双重提交 cookie 的方式.
+///
+/// ```shell
+/// curl -c cookies -b cookies http://127.0.0.1:8080/form                           # 页面中包含令牌
+/// curl -b cookies -d "amount=10" http://127.0.0.1:8080/transfer                    # 403
+/// curl -b cookies -d "amount=10&csrf_token=<令牌>" http://127.0.0.1:8080/transfer
+/// curl -b cookies -X POST -H "X-CSRF-Token: <令牌>" http://127.0.0.1:8080/cookie/reset
+/// ```
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(||{
-        App::new().wrap(CookieSession::signed(&[0;32]) // 基于 Session 中间件创建一个cookie
-            .secure(false)
-        ).service(index)
+    let double_submit = std::env::var("CSRF_MODE").is_ok_and(|mode| mode == "double-submit");
+
+    HttpServer::new(move ||{
+        let csrf = if double_submit { Csrf::double_submit(&[1; 32]) } else { Csrf::session() };
+        App::new()
+            // 最后注册的在最外层, session 中间件需要在 csrf 中间件外面
+            .wrap(csrf)
+            .wrap(CookieSession::signed(&[0;32]) // 基于 Session 中间件创建一个cookie
+                .secure(false)
+            )
+            .service(index)
+            .service(reset)
+            .service(form)
+            .service(transfer)
     }).bind("127.0.0.1:8080")?
         .run().await
 }
@@ -26,3 +71,377 @@ async fn index(session: Session) -> Result<HttpResponse, Error> {
             session.get::<i32>("counter")?.unwrap() // get::<i32> 类型必须声明
     )))
 }
+
+/// 修改状态的请求, 需要在 `X-CSRF-Token` 头中带上令牌
+#[post("/cookie/reset")]
+async fn reset(session: Session) -> HttpResponse {
+    session.remove("counter");
+    HttpResponse::Ok().body("Counter reset")
+}
+
+#[get("/form")]
+async fn form(token: CsrfToken) -> HttpResponse {
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(format!(
+        r#"<form method="post" action="/transfer">{}<input name="amount"><button>transfer</button></form>"#,
+        token.form_field()
+    ))
+}
+
+#[derive(Deserialize)]
+struct Transfer {
+    amount: u32,
+}
+
+/// 表单中的 `csrf_token` 字段已经被中间件检查过了, 这里不需要声明
+#[post("/transfer")]
+async fn transfer(data: web::Form<Transfer>) -> HttpResponse {
+    HttpResponse::Ok().body(format!("transferred {}", data.amount))
+}
+
+const TOKEN_HEADER: &str = "x-csrf-token";
+const TOKEN_FIELD: &str = "csrf_token";
+const SESSION_KEY: &str = "csrf_token";
+const COOKIE_NAME: &str = "csrf_token";
+/// 双重提交方式下 session 中保存的随机标识
+const SESSION_ID_KEY: &str = "csrf_sid";
+/// 读取表单字段时最多缓存的 body 大小
+const MAX_FORM_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Display)]
+enum CsrfError {
+    #[display(fmt = "CSRF token missing")]
+    Missing,
+    #[display(fmt = "CSRF token invalid")]
+    Invalid,
+    #[display(fmt = "Csrf middleware is not registered")]
+    NotConfigured,
+}
+
+impl ResponseError for CsrfError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CsrfError::NotConfigured => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+enum Mode {
+    Session,
+    DoubleSubmit { key: Vec<u8> },
+}
+
+/// CSRF 中间件, 通过 request extensions 把配置交给 `CsrfToken` 提取器
+pub struct Csrf {
+    mode: Rc<Mode>,
+}
+
+impl Csrf {
+    /// 令牌保存在 session 中
+    pub fn session() -> Self {
+        Csrf { mode: Rc::new(Mode::Session) }
+    }
+
+    /// 令牌使用 `key` 签名后保存在 cookie 中, 请求中需要同时带上 cookie 与相同的令牌
+    pub fn double_submit(key: &[u8]) -> Self {
+        Csrf { mode: Rc::new(Mode::DoubleSubmit { key: key.to_vec() }) }
+    }
+}
+
+/// 对 session 标识与随机数签名, 得到双重提交方式的令牌 `随机数.签名`
+fn sign(key: &[u8], session_id: &str, nonce: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key size");
+    mac.update(session_id.as_bytes());
+    mac.update(b"\n");
+    mac.update(nonce.as_bytes());
+    format!("{}.{}", nonce, base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD))
+}
+
+/// 检查 cookie 中令牌的签名, 签名不属于当前 session 时失败
+fn verify(key: &[u8], session_id: &str, token: &str) -> bool {
+    match token.split_once('.') {
+        Some((nonce, _)) => constant_time_eq(sign(key, session_id, nonce).as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+fn session_id<R: UserSession>(req: &R) -> Option<String> {
+    req.get_session().get::<String>(SESSION_ID_KEY).ok().flatten()
+}
+
+impl Mode {
+    /// 当前请求应该提交的令牌
+    fn expected<R: HttpMessage + UserSession>(&self, req: &R) -> Option<String> {
+        match self {
+            Mode::Session => req.get_session().get::<String>(SESSION_KEY).ok().flatten(),
+            Mode::DoubleSubmit { key } => {
+                let session_id = session_id(req)?;
+                req.cookie(COOKIE_NAME)
+                    .map(|cookie| cookie.value().to_string())
+                    .filter(|token| verify(key, &session_id, token))
+            }
+        }
+    }
+}
+
+fn random_token() -> String {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// 双重提交方式下新生成的令牌, 由中间件写入响应的 cookie
+struct PendingCookie(String);
+
+/// 当前请求的 CSRF 令牌, 没有时会生成一个新的
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn token(&self) -> &str {
+        &self.0
+    }
+
+    /// 表单中的隐藏字段
+    pub fn form_field(&self) -> String {
+        format!(r#"<input type="hidden" name="{}" value="{}">"#, TOKEN_FIELD, self.token())
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    type Config = ();
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let mode = match req.extensions().get::<Rc<Mode>>() {
+            Some(mode) => mode.clone(),
+            None => return ready(Err(CsrfError::NotConfigured.into())),
+        };
+        if let Some(token) = mode.expected(req) {
+            return ready(Ok(CsrfToken(token)));
+        }
+
+        let session = req.get_session();
+        let token = match &*mode {
+            Mode::Session => {
+                let token = random_token();
+                if let Err(e) = session.set(SESSION_KEY, &token) {
+                    return ready(Err(e));
+                }
+                token
+            }
+            Mode::DoubleSubmit { key } => {
+                let session_id = match session_id(req) {
+                    Some(session_id) => session_id,
+                    None => {
+                        let session_id = random_token();
+                        if let Err(e) = session.set(SESSION_ID_KEY, &session_id) {
+                            return ready(Err(e));
+                        }
+                        session_id
+                    }
+                };
+                let token = sign(key, &session_id, &random_token());
+                req.extensions_mut().insert(PendingCookie(token.clone()));
+                token
+            }
+        };
+        ready(Ok(CsrfToken(token)))
+    }
+}
+
+impl<S, B> Transform<S> for Csrf
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(CsrfMiddleware { service: Rc::new(RefCell::new(service)), mode: self.mode.clone() })
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    // 需要先读取表单字段再调用下一个服务, 所以需要共享
+    service: Rc<RefCell<S>>,
+    mode: Rc<Mode>,
+}
+
+impl<S, B> Service for CsrfMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let mode = self.mode.clone();
+        req.extensions_mut().insert(mode.clone());
+
+        Box::pin(async move {
+            let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE);
+            if !safe {
+                let expected = mode.expected(&req);
+                let provided = match req.headers().get(TOKEN_HEADER).and_then(|v| v.to_str().ok()) {
+                    Some(token) => Some(token.to_string()),
+                    None => form_token(&mut req).await?,
+                };
+                match (expected, provided) {
+                    (Some(expected), Some(provided)) if constant_time_eq(expected.as_bytes(), provided.as_bytes()) => {}
+                    (_, None) => return Err(CsrfError::Missing.into()),
+                    _ => {
+                        log::warn!("rejected {} {}: CSRF token mismatch", req.method(), req.path());
+                        return Err(CsrfError::Invalid.into());
+                    }
+                }
+            }
+
+            let fut = service.borrow_mut().call(req);
+            let mut res = fut.await?;
+            let pending = res.request().extensions_mut().remove::<PendingCookie>();
+            if let Some(PendingCookie(token)) = pending {
+                // 前端脚本需要读取这个 cookie 放到请求头中, 所以不能设置 http_only
+                let cookie = Cookie::build(COOKIE_NAME, token).path("/").same_site(actix_web::cookie::SameSite::Strict).finish();
+                res.response_mut().add_cookie(&cookie)?;
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// 从表单 body 中读取令牌字段, 读到的 body 会交还给处理函数
+async fn form_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    let is_form = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok(None);
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > MAX_FORM_SIZE {
+            return Err(PayloadError::Overflow.into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    let token = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body)
+        .ok()
+        .and_then(|fields| fields.into_iter().find(|(name, _)| name == TOKEN_FIELD).map(|(_, value)| value));
+    req.set_payload(Payload::Stream(Box::pin(futures::stream::once(async move { Ok(body) }))));
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    macro_rules! app {
+        ($csrf:expr) => {
+            test::init_service(App::new()
+                .wrap($csrf)
+                .wrap(CookieSession::signed(&[0; 32]).secure(false))
+                .service(form)
+                .service(transfer)).await
+        };
+    }
+
+    /// 中间件拒绝请求时返回的是错误
+    async fn status<S, R, B>(app: &mut S, req: R) -> StatusCode
+        where S: Service<Request = R, Response = ServiceResponse<B>, Error = Error>,
+    {
+        match app.call(req).await {
+            Ok(res) => res.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    /// 访问 `/form`, 返回响应设置的 cookie 与页面中的令牌
+    macro_rules! fetch_form {
+        ($app:expr) => {{
+            let res = test::call_service($app, test::TestRequest::get().uri("/form").to_request()).await;
+            let cookies: Vec<Cookie<'static>> = res.response().cookies().map(|c| c.into_owned()).collect();
+            let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+            let token = body.split("value=\"").nth(1).unwrap().split('"').next().unwrap().to_string();
+            (cookies, token)
+        }};
+    }
+
+    fn transfer_request(cookies: &[Cookie<'static>], payload: &str) -> test::TestRequest {
+        let mut req = test::TestRequest::post().uri("/transfer")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .set_payload(payload.to_string());
+        for cookie in cookies {
+            req = req.cookie(cookie.clone());
+        }
+        req
+    }
+
+    #[test]
+    fn session_token_from_form_field_or_header() {
+        actix_web::rt::System::new("csrf").block_on(async {
+            let mut app = app!(Csrf::session());
+            let (cookies, token) = fetch_form!(&mut app);
+
+            let req = transfer_request(&cookies, "amount=10").to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+            let req = transfer_request(&cookies, "amount=10&csrf_token=wrong").to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+
+            let req = transfer_request(&cookies, &format!("amount=10&csrf_token={}", token)).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            // 表单 body 被交还给处理函数
+            assert_eq!(test::read_body(res).await, "transferred 10");
+
+            let req = transfer_request(&cookies, "amount=5").header(TOKEN_HEADER, token.as_str()).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn double_submit_token_is_bound_to_the_session() {
+        actix_web::rt::System::new("csrf").block_on(async {
+            let mut app = app!(Csrf::double_submit(&[1; 32]));
+            let (cookies, token) = fetch_form!(&mut app);
+            assert!(cookies.iter().any(|c| c.name() == COOKIE_NAME));
+
+            let req = transfer_request(&cookies, &format!("amount=10&csrf_token={}", token)).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::OK);
+
+            // 攻击者自己的 cookie 与令牌, 配合受害者的 session 不能通过
+            let (victim, _) = fetch_form!(&mut app);
+            let mut mixed: Vec<Cookie<'static>> = victim.into_iter().filter(|c| c.name() != COOKIE_NAME).collect();
+            mixed.extend(cookies.iter().filter(|c| c.name() == COOKIE_NAME).cloned());
+            let req = transfer_request(&mixed, &format!("amount=10&csrf_token={}", token)).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+
+            let session_only: Vec<Cookie<'static>> = cookies.into_iter().filter(|c| c.name() != COOKIE_NAME).collect();
+            let req = transfer_request(&session_only, &format!("amount=10&csrf_token={}", token)).to_request();
+            assert_eq!(status(&mut app, req).await, StatusCode::FORBIDDEN);
+        });
+    }
+}
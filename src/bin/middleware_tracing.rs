@@ -0,0 +1,504 @@
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, dev::Payload, Error, HttpServer, App, HttpRequest,
+                HttpResponse, FromRequest, web, client::Client, http::HeaderValue};
+use actix_service::{Service, Transform};
+use actix_web3_doc::request_id::{request_id, RequestId, RequestIdValue};
+use futures::future::{ok, Ready, LocalBoxFuture};
+use futures::Future;
+use rand::Rng;
+use serde_json::{json, Value};
+use tracing::{field, Instrument, Span, Subscriber};
+use tracing::span::{Attributes, Id, Record};
+use tracing_subscriber::layer::{Context as LayerContext, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::pin::Pin;
+use std::sync::{mpsc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// ## 链路追踪 (Tracing)
+/// `middleware_logging.rs` 中的 `Logger` 只能输出一行访问日志, 当一个请求经过多个处理函数时无法把它们串起来.
+///
+/// 这个示例使用 `tracing` 实现:
+/// * `Tracing` 中间件为每个请求创建一个 span, 记录匹配到的路由, 响应状态码和耗时
+/// * `Traced<T>` 提取器包装器为提取器创建子 span, 处理函数使用 `#[tracing::instrument]` 创建子 span
+/// * span 中记录 `RequestId` 分配的请求 ID(`http.request_id`), 与响应头 `X-Request-Id` 相同
+/// * 支持 W3C `traceparent`: 请求中带有 `traceparent` 时沿用其中的 trace id, 响应以及对外发起的请求中也会带上 `traceparent`
+///
+/// span 结束时由 `OtlpLayer` 转换成 OTLP/JSON 格式, 然后在后台线程中导出:
+/// * 设置了 `OTLP_ENDPOINT` 环境变量时(比如 `127.0.0.1:4318`), 发送到本地 collector 的 `/v1/traces`,
+///   可以使用 `cargo run --bin otlp_collector_stub` 启动一个 collector 桩服务
+/// * 否则追加写入 `OTLP_FILE` 指定的文件(默认 `traces.jsonl`), 每行一个 ExportTraceServiceRequest
+///
+/// 访问 `/chain` 时处理函数会再调用 `/hello/chain`, 两个请求的 span 属于同一个 trace:
+/// ```shell
+/// curl -i -H "traceparent: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01" http://127.0.0.1:8080/chain
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let exporter = match std::env::var("OTLP_ENDPOINT") {
+        Ok(endpoint) => Exporter::Collector(endpoint),
+        Err(_) => Exporter::File(std::env::var("OTLP_FILE").unwrap_or_else(|_| "traces.jsonl".to_string())),
+    };
+    let subscriber = Registry::default().with(OtlpLayer::new(SERVICE_NAME, exporter));
+    tracing::subscriber::set_global_default(subscriber).expect("set tracing subscriber");
+
+    HttpServer::new(|| {
+        App::new().wrap(Tracing)
+            // 在 Tracing 的外层, span 创建时已经有请求 ID
+            .wrap(RequestId)
+            .route("/hello/{name}", web::get().to(hello))
+            .route("/chain", web::get().to(chain))
+    }).bind(SERVER_ADDRESS)?
+        .run().await
+}
+
+const SERVICE_NAME: &str = "actix-web3-demo";
+const SERVER_ADDRESS: &str = "127.0.0.1:8080";
+
+#[tracing::instrument(name = "handler.hello", skip(name))]
+async fn hello(name: Traced<web::Path<String>>) -> String {
+    let name = name.into_inner().into_inner();
+    // 模拟一次耗时的数据库查询
+    async {
+        actix_web::rt::time::delay_for(Duration::from_millis(20)).await;
+    }.instrument(tracing::info_span!("db.query", db.statement = "select * from users where name = ?")).await;
+    format!("Hello {}!", name)
+}
+
+/// 调用下游服务, 并通过 `traceparent` 把当前的 trace 传递过去
+#[tracing::instrument(name = "handler.chain", skip(_req))]
+async fn chain(_req: HttpRequest) -> Result<HttpResponse, Error> {
+    let span = tracing::info_span!("http.client", otel.kind = "client", http.method = "GET",
+        http.url = field::Empty, http.status_code = field::Empty);
+    let url = format!("http://{}/hello/chain", SERVER_ADDRESS);
+    span.record("http.url", url.as_str());
+
+    let mut request = Client::default().get(url.as_str());
+    if let Some(traceparent) = traceparent(&span) {
+        request = request.header("traceparent", traceparent);
+    }
+    let body = async {
+        let mut res = request.send().await.map_err(actix_web::error::ErrorBadGateway)?;
+        Span::current().record("http.status_code", res.status().as_u16());
+        res.body().await.map_err(actix_web::error::ErrorBadGateway)
+    }.instrument(span).await?;
+
+    Ok(HttpResponse::Ok().body(body))
+}
+
+/// 为提取器创建一个子 span, 用法: `Traced<web::Path<String>>`
+pub struct Traced<T>(T);
+
+impl<T> Traced<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> FromRequest for Traced<T>
+    where
+        T: FromRequest + 'static,
+{
+    type Error = T::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+    type Config = T::Config;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let span = tracing::info_span!("extract", otel.name = %format!("extract {}", std::any::type_name::<T>()));
+        let fut = span.in_scope(|| T::from_request(req, payload));
+        Box::pin(async move { fut.await.map(Traced) }.instrument(span))
+    }
+}
+
+/// 请求追踪中间件, 与 `middleware.rs` 中的 SayHi 一样由 Transform 与 Service 两部分组成
+pub struct Tracing;
+
+impl<S, B> Transform<S> for Tracing
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = TracingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(TracingMiddleware { service })
+    }
+}
+
+pub struct TracingMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for TracingMiddleware<S>
+    where
+        S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        // 上游传递过来的 trace 上下文
+        let remote = req.headers().get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+        let span = tracing::info_span!("http.request",
+            otel.kind = "server",
+            otel.name = field::Empty,
+            remote.trace_id = remote.as_ref().map(|r| r.0.as_str()),
+            remote.parent_id = remote.as_ref().map(|r| r.1.as_str()),
+            http.request_id = request_id(&req).as_ref().map(RequestIdValue::as_str),
+            http.method = %req.method(),
+            http.target = %req.uri(),
+            http.route = field::Empty,
+            http.status_code = field::Empty,
+            http.latency_ms = field::Empty,
+        );
+        let start = Instant::now();
+        let fut = span.in_scope(|| self.service.call(req));
+
+        Box::pin(async move {
+            let result = fut.instrument(span.clone()).await;
+            let latency = start.elapsed().as_secs_f64() * 1000.0;
+            span.record("http.latency_ms", latency);
+            match result {
+                Ok(mut res) => {
+                    let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+                    span.record("otel.name", format!("{} {}", res.request().method(), route).as_str());
+                    span.record("http.route", route.as_str());
+                    span.record("http.status_code", res.status().as_u16());
+                    if let Some(value) = traceparent(&span).and_then(|v| HeaderValue::from_str(&v).ok()) {
+                        res.headers_mut().insert("traceparent".parse().unwrap(), value);
+                    }
+                    Ok(res)
+                }
+                Err(err) => {
+                    span.record("http.status_code", err.as_response_error().status_code().as_u16());
+                    Err(err)
+                }
+            }
+        })
+    }
+}
+
+/// 解析 `traceparent: 00-{trace-id}-{parent-id}-{flags}`, 全0的 id 是无效的
+fn parse_traceparent(value: &str) -> Option<(String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    match parts.as_slice() {
+        [version, trace_id, parent_id, flags]
+        if is_hex(version, 2) && *version != "ff" && is_hex(flags, 2)
+            && is_hex_id(trace_id, 32) && is_hex_id(parent_id, 16) => {
+            Some((trace_id.to_ascii_lowercase(), parent_id.to_ascii_lowercase()))
+        }
+        _ => None,
+    }
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// trace id 与 span id 不能全为0
+fn is_hex_id(id: &str, len: usize) -> bool {
+    is_hex(id, len) && id.chars().any(|c| c != '0')
+}
+
+/// 生成指定 span 的 `traceparent` 值
+fn traceparent(span: &Span) -> Option<String> {
+    let id = span.id()?;
+    tracing::dispatcher::get_default(|dispatch| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(&id)?;
+        let extensions = span.extensions();
+        let data = extensions.get::<SpanData>()?;
+        Some(format!("00-{}-{}-01", data.trace_id, data.span_id))
+    })
+}
+
+fn random_id(bytes: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..bytes).map(|_| format!("{:02x}", rng.gen::<u8>())).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0).to_string()
+}
+
+/// 保存在 span extensions 中的追踪数据
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+impl SpanData {
+    fn record(&mut self, key: &str, value: Value) {
+        match key {
+            "otel.name" => self.name = value.as_str().unwrap_or_default().to_string(),
+            // OTLP SpanKind: 1 internal, 2 server, 3 client
+            "otel.kind" => self.kind = match value.as_str() {
+                Some("server") => 2,
+                Some("client") => 3,
+                _ => 1,
+            },
+            "remote.trace_id" | "remote.parent_id" => {}
+            _ => self.attributes.push((key.to_string(), value)),
+        }
+    }
+
+    /// 转换成 OTLP/JSON 中的一个 span
+    fn to_otlp(&self, end: SystemTime) -> Value {
+        let attributes: Vec<Value> = self.attributes.iter().map(|(key, value)| {
+            let value = match value {
+                Value::Bool(b) => json!({ "boolValue": b }),
+                Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+                // OTLP/JSON 中 int64 使用字符串表示
+                Value::Number(n) => json!({ "intValue": n.to_string() }),
+                Value::String(s) => json!({ "stringValue": s }),
+                other => json!({ "stringValue": other.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        }).collect();
+        let is_error = self.attributes.iter()
+            .any(|(key, value)| key == "http.status_code" && value.as_u64().unwrap_or(0) >= 500);
+
+        json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "parentSpanId": self.parent_span_id.clone().unwrap_or_default(),
+            "name": self.name,
+            "kind": self.kind,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(end),
+            "attributes": attributes,
+            // OTLP StatusCode: 0 unset, 2 error
+            "status": { "code": if is_error { 2 } else { 0 } },
+        })
+    }
+}
+
+/// 把字段值收集成 json
+struct FieldVisitor<'a>(&'a mut SpanData);
+
+impl<'a> field::Visit for FieldVisitor<'a> {
+    fn record_f64(&mut self, field: &field::Field, value: f64) {
+        self.0.record(field.name(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &field::Field, value: i64) {
+        self.0.record(field.name(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &field::Field, value: u64) {
+        self.0.record(field.name(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &field::Field, value: bool) {
+        self.0.record(field.name(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &field::Field, value: &str) {
+        self.0.record(field.name(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+        self.0.record(field.name(), json!(format!("{:?}", value)));
+    }
+}
+
+/// 远端传入的 trace 上下文
+#[derive(Default)]
+struct RemoteVisitor {
+    trace_id: Option<String>,
+    parent_id: Option<String>,
+}
+
+impl field::Visit for RemoteVisitor {
+    fn record_str(&mut self, field: &field::Field, value: &str) {
+        match field.name() {
+            "remote.trace_id" => self.trace_id = Some(value.to_string()),
+            "remote.parent_id" => self.parent_id = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, _field: &field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// span 导出的目标
+enum Exporter {
+    /// 追加写入文件, 每行一个 OTLP/JSON 请求
+    File(String),
+    /// 发送到本地 collector 的 `/v1/traces`
+    Collector(String),
+}
+
+impl Exporter {
+    fn export(&self, body: &[u8]) -> std::io::Result<()> {
+        match self {
+            Exporter::File(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                file.write_all(body)?;
+                file.write_all(b"\n")
+            }
+            Exporter::Collector(addr) => {
+                let mut stream = TcpStream::connect(addr)?;
+                stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+                write!(stream, "POST /v1/traces HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n", addr, body.len())?;
+                stream.write_all(body)?;
+                // 等待 collector 处理完成, 响应内容不重要
+                let mut buf = [0; 256];
+                let _ = stream.read(&mut buf)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// 把结束的 span 转换成 OTLP/JSON 并交给后台线程导出的 Layer
+struct OtlpLayer {
+    sender: Mutex<mpsc::Sender<Value>>,
+}
+
+impl OtlpLayer {
+    fn new(service_name: &'static str, exporter: Exporter) -> Self {
+        let (sender, receiver) = mpsc::channel::<Value>();
+        thread::spawn(move || {
+            while let Ok(span) = receiver.recv() {
+                // 一次最多导出64个 span
+                let mut spans = vec![span];
+                while spans.len() < 64 {
+                    match receiver.try_recv() {
+                        Ok(span) => spans.push(span),
+                        Err(_) => break,
+                    }
+                }
+                let request = json!({
+                    "resourceSpans": [{
+                        "resource": {
+                            "attributes": [{ "key": "service.name", "value": { "stringValue": service_name } }]
+                        },
+                        "scopeSpans": [{ "scope": { "name": "middleware_tracing" }, "spans": spans }]
+                    }]
+                });
+                if let Err(err) = exporter.export(request.to_string().as_bytes()) {
+                    eprintln!("failed to export spans: {}", err);
+                }
+            }
+        });
+        OtlpLayer { sender: Mutex::new(sender) }
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: LayerContext<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        // 有父 span 时继承它的 trace id, 否则使用上游传入的 trace id 或者生成一个新的
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            extensions.get::<SpanData>().map(|data| (data.trace_id.clone(), data.span_id.clone()))
+        });
+        let (trace_id, parent_span_id) = match parent {
+            Some((trace_id, span_id)) => (trace_id, Some(span_id)),
+            None => {
+                let mut remote = RemoteVisitor::default();
+                attrs.record(&mut remote);
+                (remote.trace_id.unwrap_or_else(|| random_id(16)), remote.parent_id)
+            }
+        };
+
+        let mut data = SpanData {
+            trace_id,
+            span_id: random_id(8),
+            parent_span_id,
+            name: attrs.metadata().name().to_string(),
+            kind: 1,
+            start: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut FieldVisitor(&mut data));
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut FieldVisitor(data));
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(&id) {
+            if let Some(data) = span.extensions_mut().remove::<SpanData>() {
+                let _ = self.sender.lock().unwrap().send(data.to_otlp(SystemTime::now()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const PARENT_ID: &str = "00f067aa0ba902b7";
+
+    #[test]
+    fn parses_valid_traceparent() {
+        let value = format!("00-{}-{}-01", TRACE_ID, PARENT_ID);
+        assert_eq!(parse_traceparent(&value), Some((TRACE_ID.to_string(), PARENT_ID.to_string())));
+        // 大写与首尾空白
+        let value = format!(" 00-{}-{}-00 ", TRACE_ID.to_uppercase(), PARENT_ID.to_uppercase());
+        assert_eq!(parse_traceparent(&value), Some((TRACE_ID.to_string(), PARENT_ID.to_string())));
+    }
+
+    #[test]
+    fn rejects_invalid_traceparent() {
+        let zero_trace = "0".repeat(32);
+        let zero_parent = "0".repeat(16);
+        let invalid = [
+            format!("ff-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("zz-{}-{}-01", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-0g", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01", zero_trace, PARENT_ID),
+            format!("00-{}-{}-01", TRACE_ID, zero_parent),
+            format!("00-{}-{}-01", &TRACE_ID[1..], PARENT_ID),
+            format!("00-{}-{}", TRACE_ID, PARENT_ID),
+            format!("00-{}-{}-01-extra", TRACE_ID, PARENT_ID),
+            String::new(),
+        ];
+        for value in &invalid {
+            assert_eq!(parse_traceparent(value), None, "{}", value);
+        }
+    }
+}
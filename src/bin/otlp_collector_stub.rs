@@ -0,0 +1,67 @@
+use actix_web::{HttpServer, App, web, get, post, HttpResponse};
+use serde_json::Value;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+/// ## 本地 OTLP collector 桩服务
+/// 配合 `middleware_tracing.rs` 使用, 用来在本地接收 OTLP/JSON 格式的 span, 不需要安装真正的 collector.
+/// * `POST /v1/traces` - 接收 ExportTraceServiceRequest, 保存在内存中并追加写入 `collected_traces.jsonl`
+/// * `GET /traces/{trace_id}` - 按开始时间顺序返回某个 trace 的所有 span, 可以用来跟踪一个请求经过的所有处理函数
+///
+/// ```shell
+/// cargo run --bin otlp_collector_stub
+/// OTLP_ENDPOINT=127.0.0.1:4318 cargo run --bin middleware_tracing
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let spans = web::Data::new(Collected { spans: Mutex::new(Vec::new()) });
+
+    HttpServer::new(move || {
+        App::new().app_data(spans.clone())
+            // 一次导出的 span 可能比较多, 放宽 json 大小限制
+            .app_data(web::JsonConfig::default().limit(4 * 1024 * 1024))
+            .service(collect)
+            .service(trace)
+    }).bind("127.0.0.1:4318")?
+        .run().await
+}
+
+struct Collected {
+    spans: Mutex<Vec<Value>>,
+}
+
+#[post("/v1/traces")]
+async fn collect(request: web::Json<Value>, data: web::Data<Collected>) -> HttpResponse {
+    let request = request.into_inner();
+    if let Ok(mut file) = OpenOptions::new().create(true).append(true).open("collected_traces.jsonl") {
+        let _ = writeln!(file, "{}", request);
+    }
+
+    let mut collected = data.spans.lock().unwrap();
+    for resource in request["resourceSpans"].as_array().into_iter().flatten() {
+        for scope in resource["scopeSpans"].as_array().into_iter().flatten() {
+            for span in scope["spans"].as_array().into_iter().flatten() {
+                println!("trace={} span={} parent={} name={}",
+                         span["traceId"].as_str().unwrap_or("-"),
+                         span["spanId"].as_str().unwrap_or("-"),
+                         span["parentSpanId"].as_str().unwrap_or("-"),
+                         span["name"].as_str().unwrap_or("-"));
+                collected.push(span.clone());
+            }
+        }
+    }
+    // OTLP/HTTP 成功时返回一个空的 ExportTraceServiceResponse
+    HttpResponse::Ok().json(serde_json::json!({}))
+}
+
+#[get("/traces/{trace_id}")]
+async fn trace(trace_id: web::Path<String>, data: web::Data<Collected>) -> HttpResponse {
+    let mut spans: Vec<Value> = data.spans.lock().unwrap().iter()
+        .filter(|span| span["traceId"].as_str() == Some(trace_id.as_str()))
+        .cloned()
+        .collect();
+    // OTLP/JSON 中的时间戳是字符串形式的纳秒数
+    spans.sort_by_key(|span| span["startTimeUnixNano"].as_str().unwrap_or("").parse::<u128>().unwrap_or(0));
+    HttpResponse::Ok().json(spans)
+}
@@ -1,7 +1,6 @@
 use actix_web::{HttpServer, App, post, web, Error, HttpResponse, error};
 use futures::StreamExt;
 use serde::{Serialize, Deserialize};
-use serde_json;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
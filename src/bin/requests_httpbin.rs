@@ -0,0 +1,386 @@
+use actix_web::{HttpServer, App, web, get, HttpRequest, HttpResponse, HttpMessage, Error, error, http::header,
+                http::StatusCode};
+use actix_web::cookie::Cookie;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::io::Write;
+use std::time::Duration;
+
+/// ## 请求检查服务 (httpbin)
+/// `hello_world.rs` 中的 `/echo` 只会原样返回请求 body. 测试自己的 HTTP 客户端时, 往往还需要看到请求方法, 请求头,
+/// 查询参数等信息, 或者需要服务端返回指定的状态码, 延迟, 重定向等.
+///
+/// 这里实现一个类似 [httpbin](https://httpbin.org) 的检查服务:
+/// * `/anything` - 以 json 返回请求方法, 请求头, 查询参数, 表单, json, 上传的文件以及客户端地址, 支持任意方法
+/// * `/status/{code}` - 返回指定的状态码, 多个状态码用逗号分隔时随机返回一个
+/// * `/delay/{secs}` - 延迟指定秒数(最多10秒)后返回
+/// * `/bytes/{n}` - 返回 n 个随机字节, 可以通过 `?seed=` 得到固定的内容
+/// * `/stream/{n}` - 流式返回 n 行 json
+/// * `/redirect/{n}` - 重定向 n 次后到达 `/get`
+/// * `/cookies`, `/cookies/set?name=value`, `/cookies/delete?name` - 查看, 设置, 删除 cookie
+/// * `/basic-auth/{user}/{passwd}` - 要求 HTTP Basic 认证
+/// * `/gzip` - 返回 gzip 压缩的 json
+/// * `/etag/{etag}` - 根据 `If-None-Match`/`If-Match` 返回 304 或 412
+///
+/// ```shell
+/// curl -X POST -F name=dsl -F file=@Cargo.toml "http://127.0.0.1:8080/anything?a=1&a=2"
+/// curl -L http://127.0.0.1:8080/redirect/3
+/// curl -u user:passwd http://127.0.0.1:8080/basic-auth/user/passwd
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    HttpServer::new(|| {
+        App::new()
+            // 允许上传最大 1M 的 body
+            .app_data(web::PayloadConfig::new(1024 * 1024))
+            .route("/anything", web::to(anything))
+            .route("/anything/{tail:.*}", web::to(anything))
+            .route("/get", web::get().to(anything))
+            .service(status)
+            .service(delay)
+            .service(random_bytes)
+            .service(stream)
+            .service(redirect)
+            .service(cookies)
+            .service(set_cookies)
+            .service(delete_cookies)
+            .service(basic_auth)
+            .service(gzip)
+            .service(etag)
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+/// 单个响应最多返回多少字节
+const MAX_BYTES: usize = 100 * 1024;
+/// 流式响应最多返回多少行
+const MAX_LINES: usize = 100;
+const MAX_DELAY_SECS: u64 = 10;
+
+/// 把键值对转换成 json 对象, 同一个键出现多次时值是一个数组
+fn multimap<I: IntoIterator<Item = (String, Value)>>(pairs: I) -> Value {
+    let mut map = Map::new();
+    for (key, value) in pairs {
+        match map.get_mut(&key) {
+            Some(Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+            None => {
+                map.insert(key, value);
+            }
+        }
+    }
+    Value::Object(map)
+}
+
+fn query_args(req: &HttpRequest) -> Value {
+    let pairs = serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string()).unwrap_or_default();
+    multimap(pairs.into_iter().map(|(k, v)| (k, Value::String(v))))
+}
+
+fn headers(req: &HttpRequest) -> Value {
+    multimap(req.headers().iter().map(|(name, value)| {
+        (name.to_string(), Value::String(String::from_utf8_lossy(value.as_bytes()).into_owned()))
+    }))
+}
+
+fn origin(req: &HttpRequest) -> String {
+    req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default()
+}
+
+/// 请求的基本信息, 各个接口都会用到
+fn request_info(req: &HttpRequest) -> Map<String, Value> {
+    let mut info = Map::new();
+    info.insert("method".to_string(), json!(req.method().as_str()));
+    info.insert("url".to_string(), json!(req.uri().to_string()));
+    info.insert("args".to_string(), query_args(req));
+    info.insert("headers".to_string(), headers(req));
+    info.insert("origin".to_string(), json!(origin(req)));
+    info
+}
+
+/// 文件内容是文本时原样返回, 否则转换成 data url
+fn file_content(content_type: Option<&str>, data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) => text.to_string(),
+        Err(_) => format!("data:{};base64,{}", content_type.unwrap_or("application/octet-stream"), base64::encode(data)),
+    }
+}
+
+/// 表单中的键值对
+type Fields = Vec<(String, Value)>;
+
+/// 一个非常简单的 multipart/form-data 解析器, 整个 body 已经在内存中了.
+/// 返回 (表单字段, 文件)
+fn parse_multipart(boundary: &str, body: &[u8]) -> (Fields, Fields) {
+    let mut form = Vec::new();
+    let mut files = Vec::new();
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    for part in split_bytes(body, &delimiter).into_iter().skip(1) {
+        // 最后一个分隔符后面跟着 `--`
+        if part.starts_with(b"--") {
+            break;
+        }
+        let part = part.strip_prefix(b"\r\n").unwrap_or(part);
+        let part = part.strip_suffix(b"\r\n").unwrap_or(part);
+        let (head, data) = match find_bytes(part, b"\r\n\r\n") {
+            Some(pos) => (&part[..pos], &part[pos + 4..]),
+            None => continue,
+        };
+
+        let head = String::from_utf8_lossy(head);
+        let mut name = None;
+        let mut filename = None;
+        let mut content_type = None;
+        for line in head.lines() {
+            let mut kv = line.splitn(2, ':');
+            let (key, value) = (kv.next().unwrap_or("").trim(), kv.next().unwrap_or("").trim());
+            if key.eq_ignore_ascii_case("content-disposition") {
+                for param in value.split(';').skip(1) {
+                    let mut kv = param.trim().splitn(2, '=');
+                    let (k, v) = (kv.next().unwrap_or(""), kv.next().unwrap_or("").trim_matches('"'));
+                    match k {
+                        "name" => name = Some(v.to_string()),
+                        "filename" => filename = Some(v.to_string()),
+                        _ => {}
+                    }
+                }
+            } else if key.eq_ignore_ascii_case("content-type") {
+                content_type = Some(value.to_string());
+            }
+        }
+
+        if let Some(name) = name {
+            if filename.is_some() {
+                files.push((name, json!(file_content(content_type.as_deref(), data))));
+            } else {
+                form.push((name, json!(String::from_utf8_lossy(data))));
+            }
+        }
+    }
+    (form, files)
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+fn split_bytes<'a>(mut data: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    while let Some(pos) = find_bytes(data, delimiter) {
+        parts.push(&data[..pos]);
+        data = &data[pos + delimiter.len()..];
+    }
+    parts.push(data);
+    parts
+}
+
+/// 返回请求的所有信息
+async fn anything(req: HttpRequest, body: Bytes) -> HttpResponse {
+    let mut info = request_info(&req);
+    let mut form = Vec::new();
+    let mut files = Vec::new();
+    let mut data = String::new();
+    let mut json_body = Value::Null;
+
+    let mime = req.mime_type().ok().flatten();
+    match mime.as_ref().map(|m| (m.type_().as_str(), m.subtype().as_str())) {
+        Some(("application", "x-www-form-urlencoded")) => {
+            let pairs = serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap_or_default();
+            form = pairs.into_iter().map(|(k, v)| (k, json!(v))).collect();
+        }
+        Some(("multipart", "form-data")) => {
+            if let Some(boundary) = mime.as_ref().and_then(|m| m.get_param("boundary")) {
+                let (f, fs) = parse_multipart(boundary.as_str(), &body);
+                form = f;
+                files = fs;
+            }
+        }
+        _ => {
+            data = String::from_utf8_lossy(&body).into_owned();
+            json_body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        }
+    }
+
+    info.insert("data".to_string(), json!(data));
+    info.insert("form".to_string(), multimap(form));
+    info.insert("files".to_string(), multimap(files));
+    info.insert("json".to_string(), json_body);
+    HttpResponse::Ok().json(Value::Object(info))
+}
+
+#[get("/status/{codes}")]
+async fn status(codes: web::Path<String>) -> Result<HttpResponse, Error> {
+    let codes = codes.split(',')
+        .map(|code| code.trim().parse::<u16>().ok().and_then(|code| StatusCode::from_u16(code).ok()))
+        .collect::<Option<Vec<StatusCode>>>()
+        .ok_or_else(|| error::ErrorBadRequest("invalid status code"))?;
+    let code = codes[rand::thread_rng().gen_range(0, codes.len())];
+
+    let mut res = HttpResponse::build(code);
+    if code == StatusCode::UNAUTHORIZED {
+        res.set_header(header::WWW_AUTHENTICATE, r#"Basic realm="Fake Realm""#);
+    } else if code.is_redirection() {
+        res.set_header(header::LOCATION, "/redirect/1");
+    }
+    Ok(res.finish())
+}
+
+#[get("/delay/{secs}")]
+async fn delay(req: HttpRequest, secs: web::Path<u64>) -> HttpResponse {
+    actix_web::rt::time::delay_for(Duration::from_secs(secs.into_inner().min(MAX_DELAY_SECS))).await;
+    HttpResponse::Ok().json(Value::Object(request_info(&req)))
+}
+
+#[derive(Deserialize)]
+struct SeedQuery {
+    seed: Option<u64>,
+}
+
+#[get("/bytes/{n}")]
+async fn random_bytes(n: web::Path<usize>, query: web::Query<SeedQuery>) -> HttpResponse {
+    let mut rng = match query.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+    let data: Vec<u8> = (0..n.into_inner().min(MAX_BYTES)).map(|_| rng.gen()).collect();
+    HttpResponse::Ok().content_type("application/octet-stream").body(data)
+}
+
+#[get("/stream/{n}")]
+async fn stream(req: HttpRequest, n: web::Path<usize>) -> HttpResponse {
+    let info = request_info(&req);
+    let lines = (0..n.into_inner().min(MAX_LINES)).map(move |id| {
+        let mut line = info.clone();
+        line.insert("id".to_string(), json!(id));
+        Ok::<_, Error>(Bytes::from(format!("{}\n", Value::Object(line))))
+    });
+    HttpResponse::Ok().content_type("application/json").streaming(futures::stream::iter(lines))
+}
+
+#[get("/redirect/{n}")]
+async fn redirect(n: web::Path<u32>) -> Result<HttpResponse, Error> {
+    let location = match n.into_inner() {
+        0 => return Err(error::ErrorBadRequest("n must be greater than 0")),
+        1 => "/get".to_string(),
+        n => format!("/redirect/{}", n - 1),
+    };
+    Ok(HttpResponse::Found().set_header(header::LOCATION, location).finish())
+}
+
+#[get("/cookies")]
+async fn cookies(req: HttpRequest) -> HttpResponse {
+    let cookies: Map<String, Value> = req.cookies().map(|cookies| {
+        cookies.iter().map(|c| (c.name().to_string(), json!(c.value()))).collect()
+    }).unwrap_or_default();
+    HttpResponse::Ok().json(json!({ "cookies": cookies }))
+}
+
+#[get("/cookies/set")]
+async fn set_cookies(query: web::Query<Vec<(String, String)>>) -> HttpResponse {
+    let mut res = HttpResponse::Found();
+    res.set_header(header::LOCATION, "/cookies");
+    for (name, value) in query.into_inner() {
+        res.cookie(Cookie::build(name, value).path("/").finish());
+    }
+    res.finish()
+}
+
+#[get("/cookies/delete")]
+async fn delete_cookies(req: HttpRequest) -> HttpResponse {
+    let mut res = HttpResponse::Found();
+    res.set_header(header::LOCATION, "/cookies");
+    let names = serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string()).unwrap_or_default();
+    for (name, _) in names {
+        // 发送一个已经过期的同名 cookie, 浏览器会删除它
+        res.del_cookie(&Cookie::build(name, "").path("/").finish());
+    }
+    res.finish()
+}
+
+#[get("/basic-auth/{user}/{passwd}")]
+async fn basic_auth(req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (user, passwd) = path.into_inner();
+    let credentials = req.headers().get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|v| base64::decode(v.trim()).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+
+    if credentials.as_deref() == Some(format!("{}:{}", user, passwd).as_str()) {
+        HttpResponse::Ok().json(json!({ "authenticated": true, "user": user }))
+    } else {
+        HttpResponse::Unauthorized()
+            .set_header(header::WWW_AUTHENTICATE, r#"Basic realm="Fake Realm""#)
+            .finish()
+    }
+}
+
+#[get("/gzip")]
+async fn gzip(req: HttpRequest) -> Result<HttpResponse, Error> {
+    let mut info = request_info(&req);
+    info.insert("gzipped".to_string(), json!(true));
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(Value::Object(info).to_string().as_bytes())?;
+    let body = encoder.finish()?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .set_header(header::CONTENT_ENCODING, "gzip")
+        .body(body))
+}
+
+#[get("/etag/{etag}")]
+async fn etag(req: HttpRequest, tag: web::Path<String>) -> Result<HttpResponse, Error> {
+    let tag = tag.into_inner();
+    // EntityTag::strong 在 tag 不合法时会 panic
+    if !valid_etag(&tag) {
+        return Err(error::ErrorBadRequest("invalid etag"));
+    }
+    let etag = header::EntityTag::strong(tag);
+
+    let none_match = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(&etag)),
+        None => false,
+    };
+    if none_match {
+        return Ok(HttpResponse::NotModified().set(header::ETag(etag)).finish());
+    }
+
+    let if_match_failed = match req.get_header::<header::IfMatch>() {
+        Some(header::IfMatch::Items(items)) => !items.iter().any(|item| item.strong_eq(&etag)),
+        _ => false,
+    };
+    if if_match_failed {
+        return Ok(HttpResponse::PreconditionFailed().finish());
+    }
+
+    Ok(HttpResponse::Ok().set(header::ETag(etag)).json(Value::Object(request_info(&req))))
+}
+
+/// RFC 7232 中的 etagc: 除了 `"` 之外的可见 ASCII 字符以及 0x80 以上的字节
+fn valid_etag(tag: &str) -> bool {
+    tag.bytes().all(|b| b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn etag_validation() {
+        assert!(valid_etag("abc-123"));
+        assert!(valid_etag(""));
+        assert!(valid_etag("caf\u{e9}"));
+        assert!(!valid_etag("a\"b"));
+        assert!(!valid_etag("a b"));
+        assert!(!valid_etag("a\tb"));
+        assert!(!valid_etag("a\x7fb"));
+    }
+}
@@ -0,0 +1,363 @@
+use actix_web::{HttpServer, App, web, HttpRequest, HttpResponse, HttpMessage, Responder, Error, error, http::header,
+                http::Method, dev::{Body, ResponseBody}};
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// ## 条件请求与 ETag (Conditional Requests)
+/// 像 `show_count`, `get_state`, `index_json` 这样的处理函数, 每次请求都会重新生成完整的响应, 但并不告诉客户端
+/// 内容有没有变化.
+///
+/// 这里提供一个 `Conditional<R>` 响应包装器, 它包装任意一个 Responder:
+/// * 默认对响应 body 计算 sha256 摘要作为 ETag, 也可以由处理函数通过 `version()` 直接提供版本号,
+///   版本号中只能包含 ETag 允许的可见 ASCII 字符(不能有 `"` 与空白), 否则 `version()` 返回错误
+/// * 处理函数还可以通过 `last_modified()` 提供最后修改时间, 输出 `Last-Modified` 头
+/// * 对 GET 请求处理 `If-Match`(412), `If-None-Match`(304) 与 `If-Modified-Since`(304)
+/// * 按照路由上注册的 `CachePolicy` 输出 `Cache-Control`, 以及使用强 ETag 还是弱 ETag
+///
+/// 对于会修改状态的请求(POST/PUT/DELETE), 必须在修改之前检查 `If-Match`, 否则修改已经发生了,
+/// 所以这类处理函数需要先调用 `precondition()`, 不满足条件时返回 412 Precondition Failed.
+///
+/// 可以使用如下命令查看效果:
+/// ```shell
+/// curl -i http://127.0.0.1:8080/count
+/// curl -i -H 'If-None-Match: "<上一步返回的ETag>"' http://127.0.0.1:8080/count  # 304
+/// curl -i -X POST -H 'If-Match: "v1"' http://127.0.0.1:8080/state/add        # 412, 当前版本是 v0
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let state = web::Data::new(AppState {
+        inner: Mutex::new(State { count: 0, version: 0, updated: SystemTime::now() }),
+    });
+
+    HttpServer::new(move || {
+        App::new().app_data(state.clone())
+            // 计数器的内容随时会变, 每次都要向服务器确认
+            .service(web::resource("/count")
+                .app_data(CachePolicy::no_cache())
+                .route(web::get().to(show_count)))
+            // 状态使用处理函数提供的版本号, 允许私有缓存60秒
+            .service(web::scope("/state")
+                .app_data(CachePolicy::max_age(60).private())
+                .route("/getState", web::get().to(get_state))
+                .route("/add", web::post().to(add_one)))
+            // json 内容只要语义相同就可以认为没有变化, 使用弱 ETag
+            .service(web::resource("/json")
+                .app_data(CachePolicy::max_age(300).weak())
+                .route(web::get().to(index_json)))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+struct AppState {
+    inner: Mutex<State>,
+}
+
+struct State {
+    count: u64,
+    version: u64,
+    updated: SystemTime,
+}
+
+async fn show_count(data: web::Data<AppState>) -> impl Responder {
+    let state = data.inner.lock().unwrap();
+    Conditional::new(format!("count: {}", state.count))
+}
+
+async fn get_state(data: web::Data<AppState>) -> Result<impl Responder, Error> {
+    let state = data.inner.lock().unwrap();
+    Ok(Conditional::new(HttpResponse::Ok().json(StateResponse { count: state.count, version: state.version }))
+        .version(format!("v{}", state.version))?
+        .last_modified(state.updated))
+}
+
+/// 只有客户端持有的版本是最新版本时才允许修改
+async fn add_one(req: HttpRequest, data: web::Data<AppState>) -> Result<impl Responder, Error> {
+    let mut state = data.inner.lock().unwrap();
+    precondition(&req, &header::EntityTag::strong(format!("v{}", state.version)))?;
+
+    state.count += 1;
+    state.version += 1;
+    state.updated = SystemTime::now();
+    Ok(Conditional::new(HttpResponse::Ok().json(StateResponse { count: state.count, version: state.version }))
+        .version(format!("v{}", state.version))?
+        .last_modified(state.updated))
+}
+
+async fn index_json() -> impl Responder {
+    Conditional::new(HttpResponse::Ok().json(MyJsonResponse { result: "data".to_string() }))
+}
+
+#[derive(Serialize)]
+struct StateResponse {
+    count: u64,
+    version: u64,
+}
+
+#[derive(Serialize)]
+struct MyJsonResponse {
+    result: String,
+}
+
+/// 路由级别的缓存策略, 通过 resource 或 scope 的 `app_data()` 注册
+#[derive(Clone)]
+struct CachePolicy {
+    cache_control: String,
+    weak: bool,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        CachePolicy::no_cache()
+    }
+}
+
+impl CachePolicy {
+    /// 可以缓存, 但每次使用前都必须向服务器确认
+    fn no_cache() -> Self {
+        CachePolicy { cache_control: "no-cache".to_string(), weak: false }
+    }
+
+    /// 缓存指定秒数
+    fn max_age(secs: u32) -> Self {
+        CachePolicy { cache_control: format!("max-age={}", secs), weak: false }
+    }
+
+    /// 只允许浏览器这类私有缓存保存
+    fn private(mut self) -> Self {
+        self.cache_control = format!("private, {}", self.cache_control);
+        self
+    }
+
+    /// 使用弱 ETag
+    fn weak(mut self) -> Self {
+        self.weak = true;
+        self
+    }
+}
+
+/// 为任意 Responder 加上 ETag/Last-Modified 并处理条件请求
+struct Conditional<R> {
+    inner: R,
+    version: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+impl<R: Responder> Conditional<R> {
+    fn new(inner: R) -> Self {
+        Conditional { inner, version: None, last_modified: None }
+    }
+
+    /// 由处理函数提供版本号, 不再计算 body 摘要. 版本号不是合法的 ETag 时返回 500 错误
+    fn version(mut self, version: String) -> Result<Self, Error> {
+        // etagc = %x21 / %x23-7E
+        if !version.bytes().all(|b| b == 0x21 || (0x23..=0x7e).contains(&b)) {
+            return Err(error::ErrorInternalServerError(format!("invalid etag version {:?}", version)));
+        }
+        self.version = Some(version);
+        Ok(self)
+    }
+
+    fn last_modified(mut self, time: SystemTime) -> Self {
+        self.last_modified = Some(time);
+        self
+    }
+}
+
+impl<R: Responder + 'static> Responder for Conditional<R> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<HttpResponse, Error>>;
+
+    fn respond_to(self, req: &HttpRequest) -> Self::Future {
+        let req = req.clone();
+        let fut = self.inner.respond_to(&req);
+        let (version, last_modified) = (self.version, self.last_modified);
+
+        Box::pin(async move {
+            let mut res = fut.await.map_err(Into::into)?;
+            // 只处理成功的响应
+            if !res.status().is_success() {
+                return Ok(res);
+            }
+
+            let policy = req.app_data::<CachePolicy>().cloned().unwrap_or_default();
+            let etag = version.or_else(|| body_digest(res.body()))
+                .map(|tag| header::EntityTag::new(policy.weak, tag));
+            // http 日期只精确到秒
+            let last_modified = last_modified.map(truncate_to_secs);
+
+            let headers = res.headers_mut();
+            headers.insert(header::CACHE_CONTROL, header::HeaderValue::from_str(&policy.cache_control)?);
+            if let Some(etag) = &etag {
+                headers.insert(header::ETAG, header::HeaderValue::from_str(&etag.to_string())?);
+            }
+            if let Some(time) = last_modified {
+                headers.insert(header::LAST_MODIFIED, header::HeaderValue::from_str(&header::HttpDate::from(time).to_string())?);
+            }
+
+            // 修改类请求已经在处理函数中通过 precondition() 检查过了
+            if req.method() != Method::GET && req.method() != Method::HEAD {
+                return Ok(res);
+            }
+
+            if !if_match(&req, etag.as_ref()) {
+                return Ok(HttpResponse::PreconditionFailed().finish());
+            }
+            if is_not_modified(&req, etag.as_ref(), last_modified) {
+                let mut not_modified = HttpResponse::NotModified();
+                // 304 响应需要带上与 200 响应相同的校验头与缓存头
+                for name in &[header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL, header::VARY] {
+                    if let Some(value) = res.headers().get(name) {
+                        not_modified.set_header(name.clone(), value.clone());
+                    }
+                }
+                return Ok(not_modified.finish());
+            }
+            Ok(res)
+        })
+    }
+}
+
+/// 修改类请求在修改状态之前调用, `If-Match` 不满足时返回 412 并带上当前的 ETag
+fn precondition(req: &HttpRequest, current: &header::EntityTag) -> Result<(), Error> {
+    if if_match(req, Some(current)) {
+        Ok(())
+    } else {
+        Err(HttpResponse::PreconditionFailed()
+            .set_header(header::ETAG, current.to_string())
+            .finish()
+            .into())
+    }
+}
+
+/// `If-Match` 使用强比较, 没有这个头时视为匹配
+fn if_match(req: &HttpRequest, etag: Option<&header::EntityTag>) -> bool {
+    match req.get_header::<header::IfMatch>() {
+        None | Some(header::IfMatch::Any) => true,
+        Some(header::IfMatch::Items(items)) => {
+            etag.is_some_and(|etag| items.iter().any(|item| item.strong_eq(etag)))
+        }
+    }
+}
+
+/// `If-None-Match` 使用弱比较, 存在 `If-None-Match` 时忽略 `If-Modified-Since`
+fn is_not_modified(req: &HttpRequest, etag: Option<&header::EntityTag>, last_modified: Option<SystemTime>) -> bool {
+    match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(items)) => {
+            etag.is_some_and(|etag| items.iter().any(|item| item.weak_eq(etag)))
+        }
+        None => match (last_modified, req.get_header::<header::IfModifiedSince>()) {
+            (Some(modified), Some(header::IfModifiedSince(since))) => modified <= SystemTime::from(since),
+            _ => false,
+        },
+    }
+}
+
+/// 对内存中的 body 计算摘要, 流式 body 无法提前知道内容, 不生成 ETag
+fn body_digest(body: &ResponseBody<Body>) -> Option<String> {
+    let bytes: &[u8] = match body {
+        ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => bytes,
+        ResponseBody::Body(Body::Empty) | ResponseBody::Other(Body::Empty) => &[],
+        _ => return None,
+    };
+    let digest = Sha256::digest(bytes);
+    // 取前16个字节就足够区分不同的内容了
+    Some(digest.iter().take(16).map(|b| format!("{:02x}", b)).collect())
+}
+
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, http::StatusCode};
+
+    fn app_state() -> web::Data<AppState> {
+        web::Data::new(AppState {
+            inner: Mutex::new(State { count: 0, version: 0, updated: UNIX_EPOCH + Duration::from_secs(1_600_000_000) }),
+        })
+    }
+
+    macro_rules! app {
+        () => {
+            test::init_service(App::new().app_data(app_state())
+                .service(web::resource("/count").route(web::get().to(show_count)))
+                .service(web::scope("/state")
+                    .app_data(CachePolicy::max_age(60).private())
+                    .route("/getState", web::get().to(get_state))
+                    .route("/add", web::post().to(add_one)))
+                .service(web::resource("/json")
+                    .app_data(CachePolicy::max_age(300).weak())
+                    .route(web::get().to(index_json))))
+        };
+    }
+
+    #[test]
+    fn if_none_match_returns_304_with_validators() {
+        actix_web::rt::System::new("conditional").block_on(async {
+            let mut app = app!().await;
+            let res = test::call_service(&mut app, test::TestRequest::get().uri("/json").to_request()).await;
+            let etag = res.headers().get(header::ETAG).unwrap().clone();
+            assert!(etag.to_str().unwrap().starts_with("W/\""));
+
+            let req = test::TestRequest::get().uri("/json").header(header::IF_NONE_MATCH, etag.clone()).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers().get(header::ETAG), Some(&etag));
+            assert_eq!(res.headers().get(header::CACHE_CONTROL).unwrap(), "max-age=300");
+
+            let req = test::TestRequest::get().uri("/json").header(header::IF_NONE_MATCH, "\"other\"").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn if_modified_since_is_ignored_when_if_none_match_is_present() {
+        actix_web::rt::System::new("conditional").block_on(async {
+            let mut app = app!().await;
+            let since = header::HttpDate::from(UNIX_EPOCH + Duration::from_secs(1_600_000_001)).to_string();
+            let req = test::TestRequest::get().uri("/state/getState").header(header::IF_MODIFIED_SINCE, since.clone()).to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::NOT_MODIFIED);
+
+            let req = test::TestRequest::get().uri("/state/getState")
+                .header(header::IF_MODIFIED_SINCE, since)
+                .header(header::IF_NONE_MATCH, "\"v1\"")
+                .to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::OK);
+        });
+    }
+
+    #[test]
+    fn if_match_mismatch_returns_412() {
+        actix_web::rt::System::new("conditional").block_on(async {
+            let mut app = app!().await;
+            let req = test::TestRequest::get().uri("/state/getState").header(header::IF_MATCH, "\"v1\"").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), StatusCode::PRECONDITION_FAILED);
+
+            // 修改类请求在修改之前检查, 412 时状态不变
+            let req = test::TestRequest::post().uri("/state/add").header(header::IF_MATCH, "\"v1\"").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+            assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"v0\"");
+
+            let req = test::TestRequest::post().uri("/state/add").header(header::IF_MATCH, "\"v0\"").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get(header::ETAG).unwrap(), "\"v1\"");
+        });
+    }
+
+    #[test]
+    fn invalid_version_is_an_error() {
+        assert!(Conditional::new("body").version("v1".to_string()).is_ok());
+        assert!(Conditional::new("body").version("v\"1".to_string()).is_err());
+        assert!(Conditional::new("body").version("v 1".to_string()).is_err());
+        assert!(Conditional::new("body").version("v\n1".to_string()).is_err());
+    }
+}
@@ -0,0 +1,203 @@
+use actix_web::{HttpServer, App, HttpResponse, web, HttpRequest, http};
+use actix_web3_doc::routing::{MethodResource, RouteInfo, RouteRegistry, Routes, ScopeSpec};
+use serde::Deserialize;
+use env_logger::Env;
+use actix_web::guard::Guard;
+use actix_web::dev::RequestHead;
+
+/// ## 自定义 NOT FOUND 响应
+/// `url_dispatch_scoping.rs` 中的 `NormalizePath` 与 scope 上的 `ContentTypeHeader` guard 经常让请求以意想不到的方式匹配不到路由,
+/// 默认的 404 没有任何提示. 这里通过 `actix_web3_doc::routing::Routes` 注册相同的路由, 同时把方法, 完整路径与 scope 上的 guard 记录到 `RouteRegistry` 中,
+/// 再通过 `App::default_service()` 注册 `not_found`, 根据注册表给出解释:
+/// * 路径与 guard 都匹配但是方法不匹配时, 由 `MethodResource` 返回 405, `Allow` 头中列出这个路径支持的方法
+/// * 路径匹配, 但是被 guard 拒绝时返回 404, 并说明是哪个 guard 拒绝了请求
+/// * 都不匹配时返回 404, 并按编辑距离给出最接近的几个路由
+///
+/// ```shell
+/// curl -i http://127.0.0.1:8080/users/show            # 没有 Content-Type, 被 guard 拒绝
+/// curl -i -H "Content-Type: text/plain" http://127.0.0.1:8080/users/show
+/// curl -i -X POST -H "Content-Type: text/plain" http://127.0.0.1:8080/users/show/1   # 405
+/// curl -i http://127.0.0.1:8080/user/shwo/            # did you mean /users/show/
+/// ```
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::from_env(Env::default().default_filter_or("info")).init();
+
+    // 所有 worker 共享同一个注册表
+    let registry = web::Data::new(RouteRegistry::new());
+
+    HttpServer::new(move || {
+        App::new()
+            .app_data(registry.clone())
+            .configure(|cfg| routes(cfg, &registry))
+            .external_resource("youtube", "https://youtube.com/watch/{video_id}")
+            // 所有的路由都没有匹配上时使用
+            .default_service(web::route().to(not_found))
+    }).bind("127.0.0.1:8080")?
+        .run().await
+}
+
+fn routes(cfg: &mut web::ServiceConfig, registry: &RouteRegistry) {
+    Routes::new(cfg, registry)
+        // 路径规范化默认情况下会，总是在path尾部添加一个 /
+        // 这意味着scope中注册的 path都要以 / 结尾
+        // 否则将不能访问, 但Client 请求path /user/show/ 或 /user/show 都可以
+        // 甚至你的 path = /users//show/// 都能正常访问, 这就是NormalizePath的优点
+        .scope(ScopeSpec::new("/users").normalize_path().guard(ContentTypeHeader), |routes| {
+            routes
+                .resource(MethodResource::new("/show/").get(web::to(show_users)))
+                .resource(MethodResource::new("/show/{id}/").get(web::to(user_detail)))
+                .resource(MethodResource::new("/matcher/{v1}/{v2}/").get(web::to(get_matches)))
+                .resource(MethodResource::new("/{username}/index.html/").get(web::to(get_username)));
+        })
+        .resource(MethodResource::new("/external").get(web::to(external_resource)));
+}
+
+async fn show_users() -> HttpResponse {
+    HttpResponse::Ok().body("show_users")
+}
+
+async fn user_detail(path: web::Path<(u32, )>) -> HttpResponse {
+    HttpResponse::Ok().body(format!("User detail: {}", path.into_inner().0))
+}
+
+async fn get_matches(req: HttpRequest) -> String {
+    // 直接根据替换表达式名获取一个值
+    let v1:u8 = req.match_info().get("v1").unwrap().parse().unwrap();
+
+    let v2: String = req.match_info().query("v2").parse().unwrap();
+
+    // 还可以使用 元组的模式匹配
+    let (v3, v4): (u8, String) = req.match_info().load().unwrap();
+
+    format!("Values {}, {}, {}, {}", v1, v2, v3, v4)
+}
+
+#[derive(Debug, Deserialize)]
+struct Info {
+    username: String,
+}
+
+async fn get_username(data: web::Path<Info>) -> String {
+    data.username.clone()
+}
+
+async fn external_resource(req: HttpRequest) -> HttpResponse {
+    let url = req.url_for("youtube", ["oHg5SJYRHA0"]).unwrap();
+
+    assert_eq!(url.as_str(),"https://youtube.com/watch/oHg5SJYRHA0");
+
+    // 手动修改一下header中的内容
+    HttpResponse::Ok().header("Content-Type","text/plain").body(url.into_string())
+}
+
+struct ContentTypeHeader;
+
+impl Guard for ContentTypeHeader {
+    fn check(&self, request: &RequestHead) -> bool {
+        request.headers().contains_key(http::header::CONTENT_TYPE)
+    }
+}
+
+/// 默认服务, 解释请求为什么没有匹配到任何路由
+async fn not_found(req: HttpRequest, registry: web::Data<RouteRegistry>) -> HttpResponse {
+    let routes = registry.routes();
+    let matched: Vec<&RouteInfo> = routes.iter().filter(|route| route.is_match(req.path())).collect();
+    if matched.is_empty() {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "no route matches this path",
+            "path": req.path(),
+            "did_you_mean": suggestions(&routes, req.path(), 3),
+        }));
+    }
+
+    // 路径匹配, 只可能是被 guard 拒绝了, 方法不匹配时由 `MethodResource` 返回 405
+    let rejected: Vec<_> = matched.iter()
+        .map(|route| serde_json::json!({
+            "route": format!("{} {}", route.method, route.pattern),
+            "rejected_by": route.rejected_by(req.head()),
+        }))
+        .collect();
+    HttpResponse::NotFound().json(serde_json::json!({
+        "error": "a route matches this path but a guard rejected the request",
+        "path": req.path(),
+        "routes": rejected,
+    }))
+}
+
+/// 按编辑距离排序的最接近的路由, 距离太远的不返回
+fn suggestions(routes: &[RouteInfo], path: &str, limit: usize) -> Vec<String> {
+    let mut candidates: Vec<(usize, String)> = routes.iter()
+        .map(|route| {
+            let path = route.request_path(path);
+            (edit_distance(&path, &fill_pattern(&route.pattern, &path)), format!("{} {}", route.method, route.pattern))
+        })
+        .filter(|(distance, _)| *distance <= (path.len() / 3).max(2))
+        .collect();
+    candidates.sort();
+    candidates.dedup_by(|a, b| a.1 == b.1);
+    candidates.into_iter().take(limit).map(|(_, route)| route).collect()
+}
+
+/// 用请求路径中对应位置的片段替换模式中的 `{name}`, 这样动态片段不会增加编辑距离
+fn fill_pattern(pattern: &str, path: &str) -> String {
+    let segments: Vec<&str> = path.split('/').collect();
+    pattern.split('/')
+        .enumerate()
+        .map(|(i, segment)| match segments.get(i) {
+            Some(value) if segment.starts_with('{') && segment.ends_with('}') => value,
+            _ => segment,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Levenshtein 距离
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            cur[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_distance_counts_single_character_edits() {
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("/users/shwo/", "/users/show/"), 2);
+        assert_eq!(edit_distance("/users/", "/users/"), 0);
+    }
+
+    #[test]
+    fn fill_pattern_uses_request_segments() {
+        assert_eq!(fill_pattern("/users/show/{id}/", "/users/show/42/"), "/users/show/42/");
+        assert_eq!(fill_pattern("/users/{username}/index.html/", "/users/tom/"), "/users/tom/index.html/");
+        // 请求路径比模式短时, 超出的部分保留 `{name}`
+        assert_eq!(fill_pattern("/users/matcher/{v1}/{v2}/", "/users/matcher/"), "/users/matcher//{v2}/");
+    }
+
+    #[test]
+    fn suggests_routes_from_the_registry() {
+        let registry = RouteRegistry::new();
+        let _ = App::new().configure(|cfg| routes(cfg, &registry));
+        let routes = registry.routes();
+        assert_eq!(suggestions(&routes, "/user/shwo/", 1), vec!["GET /users/show/".to_string()]);
+        assert!(suggestions(&routes, "/completely/unrelated/path", 3).is_empty());
+
+        let show = routes.iter().find(|route| route.pattern == "/users/show/{id}/").unwrap();
+        assert!(show.is_match("/users//show/1"));
+        let req = actix_web::test::TestRequest::get().uri("/users/show/1").to_http_request();
+        assert_eq!(show.rejected_by(req.head()), vec!["ContentTypeHeader"]);
+    }
+}
@@ -60,12 +60,12 @@ struct Info {
 
 #[get("/{username}/index.html/")]
 async fn get_username(data: web::Path<Info>) -> String {
-    format!("{}", data.username)
+    data.username.clone()
 }
 
 #[get("/external")]
 async fn external_resource(req: HttpRequest) -> HttpResponse {
-    let url = req.url_for("youtube", &["oHg5SJYRHA0"]).unwrap();
+    let url = req.url_for("youtube", ["oHg5SJYRHA0"]).unwrap();
 
     assert_eq!(url.as_str(),"https://youtube.com/watch/oHg5SJYRHA0");
 
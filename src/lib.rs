@@ -0,0 +1,4 @@
+//! 多个例子共用的代码, `src/bin` 中的例子通过 `actix_web3_doc::...` 使用
+
+pub mod request_id;
+pub mod routing;
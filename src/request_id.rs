@@ -0,0 +1,125 @@
+//! ## 请求 ID
+//! `RequestId` 中间件为每个请求分配一个 ID, 客户端或者上游代理提供的 `X-Request-Id` 合法时直接使用.
+//! ID 保存在 request extensions 中(通过 `request_id()` 读取), 并写入响应头, 日志, 错误页面与链路追踪中
+//! 使用同一个 ID, 方便根据用户反馈查找日志.
+//!
+//! `RequestId` 需要在读取 ID 的中间件之后注册(在它们的外层).
+
+use actix_web::{dev, http, Error, HttpMessage};
+use actix_service::{Service, Transform};
+use futures::future::{ok, Ready};
+use futures::Future;
+use rand::Rng;
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+pub const REQUEST_ID: &str = "x-request-id";
+
+/// 请求 ID, 保存在 request extensions 中
+#[derive(Clone)]
+pub struct RequestIdValue(String);
+
+impl RequestIdValue {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// 读取 `RequestId` 分配的 ID, 没有注册 `RequestId` 时返回 `None`
+pub fn request_id<R: HttpMessage>(req: &R) -> Option<RequestIdValue> {
+    req.extensions().get::<RequestIdValue>().cloned()
+}
+
+/// 为每个请求分配 ID 的中间件
+pub struct RequestId;
+
+impl RequestId {
+    /// 客户端或者上游代理提供的 ID 只接受字母, 数字, `-` 与 `_`, 避免注入到日志与页面中
+    fn from_header(req: &dev::ServiceRequest) -> Option<String> {
+        let id = req.headers().get(REQUEST_ID)?.to_str().ok()?;
+        let valid = !id.is_empty() && id.len() <= 64 && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if valid { Some(id.to_string()) } else { None }
+    }
+}
+
+impl<S, B> Transform<S> for RequestId
+    where
+        S: Service<Request = dev::ServiceRequest, Response = dev::ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = dev::ServiceRequest;
+    type Response = dev::ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestIdMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware { service })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+    where
+        S: Service<Request = dev::ServiceRequest, Response = dev::ServiceResponse<B>, Error = Error>,
+        S::Future: 'static,
+        B: 'static,
+{
+    type Request = dev::ServiceRequest;
+    type Response = dev::ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: dev::ServiceRequest) -> Self::Future {
+        let id = RequestId::from_header(&req)
+            .unwrap_or_else(|| format!("{:016x}", rand::thread_rng().gen::<u64>()));
+        req.extensions_mut().insert(RequestIdValue(id.clone()));
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            if let Ok(value) = http::HeaderValue::from_str(&id) {
+                res.headers_mut().insert(http::HeaderName::from_static(REQUEST_ID), value);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    async fn echo(req: HttpRequest) -> HttpResponse {
+        HttpResponse::Ok().body(request_id(&req).map(|id| id.as_str().to_string()).unwrap_or_default())
+    }
+
+    #[test]
+    fn uses_valid_client_id_and_replaces_invalid_one() {
+        actix_web::rt::System::new("request_id").block_on(async {
+            let mut app = test::init_service(App::new().wrap(RequestId).route("/", web::get().to(echo))).await;
+
+            let req = test::TestRequest::get().uri("/").header(REQUEST_ID, "abc-123").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.headers().get(REQUEST_ID).unwrap(), "abc-123");
+            assert_eq!(test::read_body(res).await, "abc-123");
+
+            let req = test::TestRequest::get().uri("/").header(REQUEST_ID, "<script>").to_request();
+            let res = test::call_service(&mut app, req).await;
+            let id = res.headers().get(REQUEST_ID).unwrap().to_str().unwrap().to_string();
+            assert_eq!(id.len(), 16);
+            assert_eq!(test::read_body(res).await, id.as_bytes());
+        });
+    }
+}
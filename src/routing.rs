@@ -0,0 +1,473 @@
+//! ## 路由注册表
+//! actix-web 注册之后无法再读取路由的信息. `Routes` 包装 `web::ServiceConfig`, 在注册路由的同时把方法, 完整路径,
+//! 名称与 scope 上的 guard 记录到 `RouteRegistry` 中. guard 的描述来自实际注册到 actix-web 的 guard,
+//! 不会与真正的路由配置不一致.
+//!
+//! 注册表需要显式传给 `Routes::new`, 通常放在 `web::Data` 中由所有 worker 共享. 每个 worker 都会构建一次 App,
+//! 重复的路由只记录一次.
+//!
+//! 同一个路径的所有方法注册为一个 `MethodResource`, 方法不匹配时返回 405 与 `Allow` 头.
+//! `Routes` 被丢弃时才按顺序把资源与 scope 注册到 `ServiceConfig` 中.
+
+use actix_web::{guard, web, dev, middleware::NormalizePath, http, http::Method, HttpResponse, Route};
+use actix_web::dev::{RequestHead, ResourceDef};
+use serde::Serialize;
+
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// 注册表中的一个路由
+#[derive(Clone, Serialize)]
+pub struct RouteInfo {
+    pub method: String,
+    pub pattern: String,
+    pub name: Option<String>,
+    /// 从外到内所有 scope 上的 guard
+    pub guards: Vec<String>,
+    #[serde(skip)]
+    checks: Vec<DescribedGuard>,
+    #[serde(skip)]
+    normalize_path: bool,
+}
+
+impl RouteInfo {
+    /// 路由看到的请求路径: 外层 scope 使用了 `NormalizePath` 时合并连续的 `/`, 并在结尾添加 `/`
+    pub fn request_path<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        if !self.normalize_path {
+            return path.into();
+        }
+        let mut normalized = String::with_capacity(path.len() + 1);
+        for c in path.chars().chain(std::iter::once('/')) {
+            if !(c == '/' && normalized.ends_with('/')) {
+                normalized.push(c);
+            }
+        }
+        normalized.into()
+    }
+
+    /// 路径是否匹配这个路由, 不考虑方法与 guard
+    pub fn is_match(&self, path: &str) -> bool {
+        ResourceDef::new(self.pattern.as_str()).is_match(&self.request_path(path))
+    }
+
+    /// 拒绝了这个请求的 guard 的描述
+    pub fn rejected_by(&self, head: &RequestHead) -> Vec<&str> {
+        self.checks.iter()
+            .filter(|g| !guard::Guard::check(*g, head))
+            .map(|g| g.description.as_str())
+            .collect()
+    }
+
+    fn same_route(&self, other: &RouteInfo) -> bool {
+        self.method == other.method && self.pattern == other.pattern && self.name == other.name && self.guards == other.guards
+    }
+}
+
+/// 路由注册表, 可以在所有 worker 之间共享
+#[derive(Default)]
+pub struct RouteRegistry {
+    routes: Mutex<Vec<RouteInfo>>,
+    printed: AtomicBool,
+}
+
+impl RouteRegistry {
+    pub fn new() -> Self {
+        RouteRegistry::default()
+    }
+
+    /// 每个 worker 都会注册一遍相同的路由, 已经记录过的忽略
+    fn add(&self, route: RouteInfo) {
+        let mut routes = self.routes.lock().unwrap();
+        if !routes.iter().any(|r| r.same_route(&route)) {
+            routes.push(route);
+        }
+    }
+
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    /// 以表格的形式打印所有路由
+    pub fn print_table(&self) {
+        let rows: Vec<[String; 4]> = self.routes().into_iter().map(|r| [
+            r.method,
+            r.pattern,
+            r.name.unwrap_or_else(|| "-".to_string()),
+            if r.guards.is_empty() { "-".to_string() } else { r.guards.join(", ") },
+        ]).collect();
+        let header = ["METHOD", "PATTERN", "NAME", "GUARDS"];
+        let mut widths = header.map(str::len);
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |cells: Vec<&str>| {
+            let cells: Vec<String> = cells.iter().zip(&widths).map(|(c, w)| format!("{:<width$}", c, width = w)).collect();
+            println!("{}", cells.join("  ").trim_end());
+        };
+        line(header.to_vec());
+        for row in &rows {
+            line(row.iter().map(String::as_str).collect());
+        }
+    }
+
+    /// 只在第一次调用时打印, 在 `HttpServer::new` 的 App 工厂中调用, 第一个 worker 构建完 App 之后打印
+    pub fn print_table_once(&self) {
+        if !self.printed.swap(true, Ordering::SeqCst) {
+            self.print_table();
+        }
+    }
+}
+
+/// 带描述的 guard, 描述显示在注册表中
+#[derive(Clone)]
+pub struct DescribedGuard {
+    description: String,
+    guard: Arc<dyn guard::Guard + Send + Sync>,
+}
+
+impl guard::Guard for DescribedGuard {
+    fn check(&self, request: &RequestHead) -> bool {
+        self.guard.check(request)
+    }
+}
+
+/// scope 的前缀, guard 以及是否使用 `NormalizePath`
+pub struct ScopeSpec {
+    prefix: String,
+    guards: Vec<DescribedGuard>,
+    normalize_path: bool,
+}
+
+impl ScopeSpec {
+    pub fn new(prefix: &str) -> Self {
+        ScopeSpec { prefix: prefix.to_string(), guards: Vec::new(), normalize_path: false }
+    }
+
+    /// 添加一个 guard, 以它的类型名作为描述
+    pub fn guard<G: guard::Guard + Send + Sync + 'static>(mut self, guard: G) -> Self {
+        let name = std::any::type_name::<G>();
+        let description = name.rsplit("::").next().unwrap_or(name).to_string();
+        self.guards.push(DescribedGuard { description, guard: Arc::new(guard) });
+        self
+    }
+
+    /// 添加 `guard::Header(name, value)`
+    pub fn header(mut self, name: &'static str, value: &'static str) -> Self {
+        let description = format!("Header({}: {})", name, value);
+        self.guards.push(DescribedGuard { description, guard: Arc::new(guard::Header(name, value)) });
+        self
+    }
+
+    /// 使用 `NormalizePath::default()` 包装 scope
+    pub fn normalize_path(mut self) -> Self {
+        self.normalize_path = true;
+        self
+    }
+}
+
+/// 外层 scope 累积下来的前缀与 guard
+#[derive(Clone, Default)]
+struct ScopeContext {
+    prefix: String,
+    guards: Vec<DescribedGuard>,
+    normalize_path: bool,
+}
+
+/// 还没有注册到 `ServiceConfig` 中的资源与 scope, 保持调用的顺序
+enum Pending {
+    Resource(MethodResource),
+    Service(Box<dyn FnOnce(&mut web::ServiceConfig)>),
+}
+
+/// 包装 `ServiceConfig`, 注册路由的同时把它记录到注册表中
+pub struct Routes<'a> {
+    cfg: &'a mut web::ServiceConfig,
+    registry: &'a RouteRegistry,
+    scope: ScopeContext,
+    pending: Vec<Pending>,
+}
+
+impl<'a> Routes<'a> {
+    pub fn new(cfg: &'a mut web::ServiceConfig, registry: &'a RouteRegistry) -> Self {
+        Routes { cfg, registry, scope: ScopeContext::default(), pending: Vec::new() }
+    }
+
+    fn record(&self, method: &Method, path: &str, name: Option<&str>) {
+        self.registry.add(RouteInfo {
+            method: method.to_string(),
+            pattern: format!("{}{}", self.scope.prefix, path),
+            name: name.map(String::from),
+            guards: self.scope.guards.iter().map(|g| g.description.clone()).collect(),
+            checks: self.scope.guards.clone(),
+            normalize_path: self.scope.normalize_path,
+        });
+    }
+
+    /// 同一个路径的路由合并到一个 `MethodResource` 中
+    fn resource_mut(&mut self, path: &str) -> &mut MethodResource {
+        let index = self.pending.iter().position(|p| matches!(p, Pending::Resource(r) if r.path == path));
+        let index = index.unwrap_or_else(|| {
+            self.pending.push(Pending::Resource(MethodResource::new(path)));
+            self.pending.len() - 1
+        });
+        match &mut self.pending[index] {
+            Pending::Resource(resource) => resource,
+            Pending::Service(_) => unreachable!(),
+        }
+    }
+
+    /// 注册一个路由, `route` 通常是 `web::to(handler)`
+    pub fn route(&mut self, method: Method, path: &str, route: Route) -> &mut Self {
+        self.record(&method, path, None);
+        self.resource_mut(path).routes.push((method, route));
+        self
+    }
+
+    /// 注册一个有名称的路由, 可以用于 `url_for`
+    pub fn named(&mut self, method: Method, path: &str, name: &str, route: Route) -> &mut Self {
+        self.record(&method, path, Some(name));
+        let resource = self.resource_mut(path);
+        resource.name = Some(name.to_string());
+        resource.routes.push((method, route));
+        self
+    }
+
+    /// 注册一个路径的多个方法
+    pub fn resource(&mut self, resource: MethodResource) -> &mut Self {
+        for (method, _) in &resource.routes {
+            self.record(method, &resource.path, resource.name.as_deref());
+        }
+        let existing = self.resource_mut(&resource.path);
+        existing.routes.extend(resource.routes);
+        if resource.name.is_some() {
+            existing.name = resource.name;
+        }
+        self
+    }
+
+    /// 与 `App::configure` 相同, 把一组路由放到单独的函数中
+    pub fn configure<F: FnOnce(&mut Routes)>(&mut self, f: F) -> &mut Self {
+        f(self);
+        self
+    }
+
+    /// 注册一个 scope, 其中的路由通过 `f` 中的 `Routes` 注册
+    pub fn scope<F: FnOnce(&mut Routes)>(&mut self, spec: ScopeSpec, f: F) -> &mut Self {
+        let mut context = self.scope.clone();
+        context.prefix.push_str(&spec.prefix);
+        context.guards.extend(spec.guards.iter().cloned());
+        context.normalize_path |= spec.normalize_path;
+
+        let registry = self.registry;
+        let mut scope = web::scope(&spec.prefix).configure(|cfg| {
+            f(&mut Routes { cfg, registry, scope: context, pending: Vec::new() });
+        });
+        for guard in spec.guards {
+            scope = scope.guard(guard);
+        }
+        let register: Box<dyn FnOnce(&mut web::ServiceConfig)> = if spec.normalize_path {
+            Box::new(move |cfg| { cfg.service(scope.wrap(NormalizePath::default())); })
+        } else {
+            Box::new(move |cfg| { cfg.service(scope); })
+        };
+        self.pending.push(Pending::Service(register));
+        self
+    }
+}
+
+impl Drop for Routes<'_> {
+    fn drop(&mut self) {
+        for pending in self.pending.drain(..) {
+            match pending {
+                Pending::Resource(resource) => {
+                    self.cfg.service(resource);
+                }
+                Pending::Service(register) => register(self.cfg),
+            }
+        }
+    }
+}
+
+/// 记录已注册方法的资源, 自动处理 `OPTIONS`, `HEAD` 与 405
+///
+/// 405 只根据注册的方法判断: 方法匹配但是被路由自己的 guard(例如 `web::to(handler).guard(...)`)拒绝的请求同样得到 405,
+/// 此时 `Allow` 头中仍然包含请求使用的方法.
+pub struct MethodResource {
+    path: String,
+    name: Option<String>,
+    routes: Vec<(Method, Route)>,
+}
+
+impl MethodResource {
+    pub fn new(path: &str) -> Self {
+        MethodResource { path: path.to_string(), name: None, routes: Vec::new() }
+    }
+
+    /// 资源的名称, 可以用于 `url_for`
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// 注册一个方法, `route` 通常是 `web::to(handler)`
+    pub fn route(mut self, method: Method, route: Route) -> Self {
+        self.routes.push((method, route));
+        self
+    }
+
+    pub fn get(self, route: Route) -> Self {
+        self.route(Method::GET, route)
+    }
+
+    pub fn post(self, route: Route) -> Self {
+        self.route(Method::POST, route)
+    }
+
+    /// 注册的方法加上自动处理的 `HEAD` 与 `OPTIONS`, 按字母排序
+    pub fn allowed(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = self.routes.iter().map(|(method, _)| method.clone()).collect();
+        if methods.contains(&Method::GET) {
+            methods.push(Method::HEAD);
+        }
+        methods.push(Method::OPTIONS);
+        methods.sort_by(|a, b| a.as_str().cmp(b.as_str()));
+        methods.dedup();
+        methods
+    }
+}
+
+impl dev::HttpServiceFactory for MethodResource {
+    fn register(self, config: &mut dev::AppService) {
+        let allow = self.allowed().iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+        let has = |method: Method| self.routes.iter().any(|(m, _)| *m == method);
+        let derive_head = !has(Method::HEAD);
+        let answer_options = !has(Method::OPTIONS);
+
+        let mut resource = web::resource(self.path.as_str());
+        if let Some(name) = &self.name {
+            resource = resource.name(name);
+        }
+        for (method, route) in self.routes {
+            resource = if method == Method::GET && derive_head {
+                resource.route(route.guard(guard::Any(guard::Get()).or(guard::Head())))
+            } else {
+                resource.route(route.method(method))
+            };
+        }
+        if answer_options {
+            let allow = allow.clone();
+            resource = resource.route(web::route().method(Method::OPTIONS).to(move || {
+                HttpResponse::NoContent().header(http::header::ALLOW, allow.as_str()).finish()
+            }));
+        }
+        resource
+            .default_service(web::to(move || {
+                HttpResponse::MethodNotAllowed().header(http::header::ALLOW, allow.as_str()).finish()
+            }))
+            .register(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App, HttpResponse};
+
+    struct Always;
+
+    impl guard::Guard for Always {
+        fn check(&self, _: &RequestHead) -> bool {
+            true
+        }
+    }
+
+    fn nested(routes: &mut Routes) {
+        routes.route(Method::POST, "/nested", web::to(HttpResponse::Ok));
+    }
+
+    fn app_routes(cfg: &mut web::ServiceConfig, registry: &RouteRegistry) {
+        Routes::new(cfg, registry)
+            .named(Method::GET, "/", "index", web::to(HttpResponse::Ok))
+            .scope(ScopeSpec::new("/api").header("X-Api", "1").normalize_path(), |routes| {
+                routes.scope(ScopeSpec::new("/v1").guard(Always), |routes| {
+                    routes.route(Method::GET, "/users/{id}/", web::to(HttpResponse::Ok))
+                        .configure(nested);
+                });
+            });
+    }
+
+    #[test]
+    fn records_full_patterns_and_guards_once_per_route() {
+        let registry = RouteRegistry::new();
+        // 每个 worker 都会构建一次 App
+        for _ in 0..2 {
+            let _ = App::new().configure(|cfg| app_routes(cfg, &registry));
+        }
+        let routes: Vec<_> = registry.routes().into_iter()
+            .map(|r| (r.method, r.pattern, r.name, r.guards))
+            .collect();
+        let api_guards = vec!["Header(X-Api: 1)".to_string(), "Always".to_string()];
+        assert_eq!(routes, vec![
+            ("GET".to_string(), "/".to_string(), Some("index".to_string()), vec![]),
+            ("GET".to_string(), "/api/v1/users/{id}/".to_string(), None, api_guards.clone()),
+            ("POST".to_string(), "/api/v1/nested".to_string(), None, api_guards),
+        ]);
+    }
+
+    #[test]
+    fn routes_on_one_path_share_a_resource() {
+        actix_web::rt::System::new("routes").block_on(async {
+            let registry = RouteRegistry::new();
+            let config = |cfg: &mut web::ServiceConfig| {
+                Routes::new(cfg, &registry)
+                    .route(Method::GET, "/items", web::to(HttpResponse::Ok))
+                    .route(Method::POST, "/items", web::to(HttpResponse::Created));
+            };
+            let mut app = test::init_service(App::new().configure(config)).await;
+            let req = test::TestRequest::post().uri("/items").to_request();
+            assert_eq!(test::call_service(&mut app, req).await.status(), http::StatusCode::CREATED);
+
+            let req = test::TestRequest::delete().uri("/items").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers().get(http::header::ALLOW).unwrap(), "GET, HEAD, OPTIONS, POST");
+        });
+    }
+
+    #[test]
+    fn answers_options_and_serves_head_with_the_get_handler() {
+        actix_web::rt::System::new("method_resource").block_on(async {
+            let resource = MethodResource::new("/items")
+                .get(web::to(|| HttpResponse::Ok().header("X-Handler", "get").body("items")))
+                .post(web::to(HttpResponse::Created));
+            let mut app = test::init_service(App::new().service(resource)).await;
+
+            let req = test::TestRequest::with_uri("/items").method(Method::OPTIONS).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), http::StatusCode::NO_CONTENT);
+            assert_eq!(res.headers().get(http::header::ALLOW).unwrap(), "GET, HEAD, OPTIONS, POST");
+
+            let req = test::TestRequest::with_uri("/items").method(Method::HEAD).to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), http::StatusCode::OK);
+            assert_eq!(res.headers().get("X-Handler").unwrap(), "get");
+        });
+    }
+
+    #[test]
+    fn route_guard_rejection_is_a_405_that_allows_the_same_method() {
+        actix_web::rt::System::new("method_resource").block_on(async {
+            let resource = MethodResource::new("/items")
+                .post(web::to(HttpResponse::Created).guard(guard::Header("Content-Type", "application/json")));
+            let mut app = test::init_service(App::new().service(resource)).await;
+
+            let req = test::TestRequest::post().uri("/items").to_request();
+            let res = test::call_service(&mut app, req).await;
+            assert_eq!(res.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(res.headers().get(http::header::ALLOW).unwrap(), "OPTIONS, POST");
+        });
+    }
+}